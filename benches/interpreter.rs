@@ -0,0 +1,54 @@
+//! Interpreter benchmark
+//!
+//! Runs a loop of loads, ALU, CB-prefixed and stack operations
+//! with the screen off, so most of the time goes to the CPU
+//!
+//! Run it with `cargo bench --bench interpreter`
+extern crate safeboy;
+
+use std::time::Instant;
+
+use safeboy::cpu::z80::Z80;
+
+const FRAMES: u32 = 3000;
+
+const PROGRAM: [u8; 30] = [
+    0xAF,               // XOR A
+    0xE0, 0x40,         // LDH (0x40),A, screen off
+    0x21, 0x00, 0xC0,   // LD HL,0xC000
+    0x06, 0x10,         // LD B,0x10
+    0x7E,               // loop: LD A,(HL)
+    0x80,               // ADD A,B
+    0xA9,               // XOR C
+    0x22,               // LD (HL+),A
+    0x4F,               // LD C,A
+    0xCB, 0x01,         // RLC C
+    0xCB, 0x5E,         // BIT 3,(HL)
+    0xCB, 0x32,         // SWAP D
+    0xC5,               // PUSH BC
+    0xD1,               // POP DE
+    0x1C,               // INC E
+    0xFE, 0x20,         // CP 0x20
+    0x05,               // DEC B
+    0x20, 0xED,         // JR NZ,loop
+    0xC3, 0x03, 0x01,   // JP 0x0103
+];
+
+fn main() {
+    let mut cpu = Z80::from_program(&PROGRAM);
+
+    let start = Instant::now();
+
+    for _ in 0 .. FRAMES {
+        cpu.step().unwrap();
+    }
+
+    let elapsed = start.elapsed();
+
+    println!(
+        "interpreter: {} frames in {:.3}s ({:.1}us per frame)",
+        FRAMES,
+        elapsed.as_secs_f64(),
+        elapsed.as_secs_f64() * 1_000_000.0 / FRAMES as f64
+    );
+}
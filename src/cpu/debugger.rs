@@ -0,0 +1,449 @@
+use std::fmt;
+use std::str::FromStr;
+
+use cpu::fault::CpuFault;
+use cpu::registers::RegisterSet;
+
+/// Kind of memory access
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Access {
+    Read,
+    Write,
+
+    /// Both reads and writes, only used by watchpoints
+    Any,
+}
+
+/// Why a run stopped
+#[derive(Clone, Debug)]
+pub enum StopReason {
+    /// The frame ended, nothing else stopped it
+    FrameEnd,
+
+    /// The step (or step over, step out, run to) is complete
+    Step,
+
+    /// About to execute the instruction at a breakpoint
+    Breakpoint(usize),
+
+    /// The last instruction accessed a watched address, with
+    /// the value read or written
+    Watchpoint {
+        id: usize,
+        address: u16,
+        value: u8,
+        access: Access,
+    },
+
+    /// The CPU locked up
+    Fault(CpuFault),
+}
+
+/// Breakpoint on the program counter
+///
+/// With a bank, it's only hit when that ROM bank is mapped. With
+/// a condition, it's only hit if the condition holds
+#[derive(Clone, Debug)]
+pub struct Breakpoint {
+    pub bank: Option<usize>,
+    pub address: u16,
+    pub condition: Option<Condition>,
+}
+
+impl Breakpoint {
+    pub fn new(address: u16) -> Breakpoint {
+        Breakpoint {
+            bank: None,
+            address,
+            condition: None,
+        }
+    }
+}
+
+/// Watchpoint on memory accesses from the CPU
+///
+/// Covers the addresses between `from` and `to`, both included.
+/// Accesses from DMA transfers are not watched
+#[derive(Copy, Clone, Debug)]
+pub struct Watchpoint {
+    pub from: u16,
+    pub to: u16,
+    pub access: Access,
+}
+
+impl Watchpoint {
+    pub fn new(address: u16, access: Access) -> Watchpoint {
+        Watchpoint {
+            from: address,
+            to: address,
+            access,
+        }
+    }
+}
+
+/// Values conditions can look at
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Operand {
+    A, F, B, C, D, E, H, L,
+    AF, BC, DE, HL,
+    SP, PC,
+
+    /// Byte in memory
+    Memory(u16),
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// Condition on a register or memory value, like `A == 0x10`
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Condition {
+    pub operand: Operand,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+impl Condition {
+    /// Evaluates the condition, memory is read with the given function
+    pub fn holds<F>(&self, registers: &RegisterSet, mut read: F) -> bool where F: FnMut(u16) -> u8 {
+        let current = match self.operand {
+            Operand::A => registers.a as u16,
+            Operand::F => registers.flags as u16,
+            Operand::B => registers.b as u16,
+            Operand::C => registers.c as u16,
+            Operand::D => registers.d as u16,
+            Operand::E => registers.e as u16,
+            Operand::H => registers.h as u16,
+            Operand::L => registers.l as u16,
+            Operand::AF => registers.af(),
+            Operand::BC => registers.bc(),
+            Operand::DE => registers.de(),
+            Operand::HL => registers.hl(),
+            Operand::SP => registers.stack_pointer,
+            Operand::PC => registers.program_counter,
+            Operand::Memory(address) => read(address) as u16,
+        };
+
+        match self.comparison {
+            Comparison::Equal => current == self.value,
+            Comparison::NotEqual => current != self.value,
+            Comparison::Less => current < self.value,
+            Comparison::LessOrEqual => current <= self.value,
+            Comparison::Greater => current > self.value,
+            Comparison::GreaterOrEqual => current >= self.value,
+        }
+    }
+}
+
+/// Parses a number, hex with a 0x or $ prefix, in `radix` otherwise
+pub fn parse_number(value: &str, radix: u32) -> Option<u16> {
+    if let Some(hex) = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = value.strip_prefix('$') {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        u16::from_str_radix(value, radix).ok()
+    }
+}
+
+/// Parses an address, always in hex, with an optional 0x or $ prefix
+pub fn parse_address(value: &str) -> Result<u16, String> {
+    parse_number(value, 16).ok_or_else(|| format!("{} is not a valid address", value))
+}
+
+/// Parses an address with an optional ROM bank, as BANK:ADDR, both in hex
+pub fn parse_location(value: &str) -> Result<(Option<usize>, u16), String> {
+    match value.find(':') {
+        Some(position) => {
+            let bank = parse_number(&value[.. position], 16)
+                .ok_or_else(|| format!("{} is not a valid bank", &value[.. position]))?;
+
+            Ok((Some(bank as usize), parse_address(&value[position + 1 ..])?))
+        },
+
+        None => Ok((None, parse_address(value)?)),
+    }
+}
+
+/// Parses a condition, like `A == 0x10`, `HL >= $C000` or `(FF44) == 144`
+///
+/// Memory operands take the address between parentheses, in hex
+/// like every other address in the debugger
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Condition, String> {
+        let invalid = || format!("{} is not a valid condition, like A == 0x10 or (0xC000) != 0", value);
+
+        // longer operators first, so <= is not taken as <
+        let comparisons = [
+            ("==", Comparison::Equal),
+            ("!=", Comparison::NotEqual),
+            ("<=", Comparison::LessOrEqual),
+            (">=", Comparison::GreaterOrEqual),
+            ("<", Comparison::Less),
+            (">", Comparison::Greater),
+        ];
+
+        let (position, symbol, comparison) = comparisons.iter()
+            .filter_map(|&(symbol, comparison)| value.find(symbol).map(|position| (position, symbol, comparison)))
+            .next()
+            .ok_or_else(invalid)?;
+
+        let operand = value[.. position].trim();
+        let number = value[position + symbol.len() ..].trim();
+
+        let operand = match operand.to_uppercase().as_str() {
+            "A" => Operand::A,
+            "F" => Operand::F,
+            "B" => Operand::B,
+            "C" => Operand::C,
+            "D" => Operand::D,
+            "E" => Operand::E,
+            "H" => Operand::H,
+            "L" => Operand::L,
+            "AF" => Operand::AF,
+            "BC" => Operand::BC,
+            "DE" => Operand::DE,
+            "HL" => Operand::HL,
+            "SP" => Operand::SP,
+            "PC" => Operand::PC,
+            memory if memory.starts_with('(') && memory.ends_with(')') => {
+                Operand::Memory(parse_address(&operand[1 .. operand.len() - 1])?)
+            },
+            _ => return Err(invalid()),
+        };
+
+        Ok(Condition {
+            operand,
+            comparison,
+            value: parse_number(number, 10).ok_or_else(invalid)?,
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let comparison = match self.comparison {
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+        };
+
+        match self.operand {
+            Operand::Memory(address) => write!(f, "(${:04X})", address)?,
+            operand => write!(f, "{:?}", operand)?,
+        }
+
+        write!(f, " {} ${:X}", comparison, self.value)
+    }
+}
+
+/// Where a run should stop by itself
+#[derive(Copy, Clone, Debug)]
+pub enum Goal {
+    /// After one instruction
+    Step,
+
+    /// When the program counter is back at an address, with the
+    /// stack at or above the given level (after a call returns)
+    Return(u16, u16),
+
+    /// After a return leaves the stack above the given level
+    StepOut(u16),
+
+    /// When the program counter gets to an address, in any
+    /// bank or only in the given one
+    ProgramCounter(Option<usize>, u16),
+}
+
+/// Debugger
+///
+/// Holds the breakpoints, watchpoints and the goal of the current
+/// run. The CPU checks them while running under `Z80::run_frame`
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: Vec<(usize, Breakpoint)>,
+    watchpoints: Vec<(usize, Watchpoint)>,
+
+    /// Id for the next breakpoint or watchpoint
+    next_id: usize,
+
+    /// Goal of the run in progress, it survives frame ends
+    pub goal: Option<Goal>,
+
+    /// Watchpoint hit by the instruction being executed
+    hit: Option<StopReason>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    /// Adds a breakpoint, returns its id
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        self.next_id += 1;
+        self.breakpoints.push((self.next_id, breakpoint));
+        self.next_id
+    }
+
+    /// Adds a watchpoint, returns its id
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.next_id += 1;
+        self.watchpoints.push((self.next_id, watchpoint));
+        self.next_id
+    }
+
+    /// Removes a breakpoint or watchpoint, returns whether it existed
+    pub fn remove(&mut self, id: usize) -> bool {
+        let count = self.breakpoints.len() + self.watchpoints.len();
+
+        self.breakpoints.retain(|&(i, _)| i != id);
+        self.watchpoints.retain(|&(i, _)| i != id);
+
+        count != self.breakpoints.len() + self.watchpoints.len()
+    }
+
+    /// Removes every breakpoint and watchpoint
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> &[(usize, Breakpoint)] {
+        &self.breakpoints
+    }
+
+    pub fn watchpoints(&self) -> &[(usize, Watchpoint)] {
+        &self.watchpoints
+    }
+
+    /// Whether there are watchpoints to check on memory accesses
+    pub fn watching(&self) -> bool {
+        !self.watchpoints.is_empty()
+    }
+
+    /// Checks a memory access against the watchpoints
+    ///
+    /// The first hit is kept until the instruction ends
+    pub fn check_access(&mut self, address: u16, value: u8, access: Access) {
+        if self.hit.is_some() {
+            return
+        }
+
+        let watchpoint = self.watchpoints.iter().find(|(_, w)| {
+            address >= w.from && address <= w.to && (w.access == Access::Any || w.access == access)
+        });
+
+        if let Some(&(id, _)) = watchpoint {
+            self.hit = Some(StopReason::Watchpoint { id, address, value, access });
+        }
+    }
+
+    /// Takes the watchpoint hit by the last instruction
+    pub fn take_hit(&mut self) -> Option<StopReason> {
+        self.hit.take()
+    }
+
+    /// Breakpoint at an address, if any
+    ///
+    /// Conditions are evaluated with the given registers and memory
+    pub fn breakpoint_at<F>(&self, bank: usize, registers: &RegisterSet, mut read: F) -> Option<usize>
+        where F: FnMut(u16) -> u8 {
+        let program_counter = registers.program_counter;
+
+        self.breakpoints.iter()
+            .find(|(_, b)| {
+                b.address == program_counter &&
+                    b.bank.is_none_or(|b| b == bank) &&
+                    b.condition.is_none_or(|c| c.holds(registers, &mut read))
+            })
+            .map(|&(id, _)| id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_conditions() {
+        let condition: Condition = "hl >= 0xC000".parse().unwrap();
+        assert_eq!(condition.operand, Operand::HL);
+        assert_eq!(condition.comparison, Comparison::GreaterOrEqual);
+        assert_eq!(condition.value, 0xC000);
+
+        let condition: Condition = "($FF44)==144".parse().unwrap();
+        assert_eq!(condition.operand, Operand::Memory(0xFF44));
+        assert_eq!(condition.value, 144);
+        assert_eq!(condition.to_string(), "($FF44) == $90");
+
+        let condition: Condition = "(FF44) == 0x90".parse().unwrap();
+        assert_eq!(condition.operand, Operand::Memory(0xFF44));
+        assert_eq!(condition.value, 0x90);
+
+        let condition: Condition = "(100) == 0".parse().unwrap();
+        assert_eq!(condition.operand, Operand::Memory(0x0100));
+
+        assert!("Q == 1".parse::<Condition>().is_err());
+        assert!("A = 1".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn it_parses_numbers_and_addresses() {
+        assert_eq!(parse_number("16", 10), Some(16));
+        assert_eq!(parse_number("0x10", 10), Some(16));
+        assert_eq!(parse_address("$ff40"), Ok(0xFF40));
+        assert_eq!(parse_address("0XC000"), Ok(0xC000));
+        assert_eq!(parse_address("100"), Ok(0x100));
+        assert!(parse_address("10000").is_err());
+
+        assert_eq!(parse_location("0x1F:$4000"), Ok((Some(0x1F), 0x4000)));
+        assert_eq!(parse_location("C000"), Ok((None, 0xC000)));
+        assert!(parse_location("1:2:3").is_err());
+    }
+
+    #[test]
+    fn it_evaluates_conditions() {
+        let registers = RegisterSet::new();
+        let read = |address| if address == 0xC000 { 0x42 } else { 0 };
+
+        assert!("A == 1".parse::<Condition>().unwrap().holds(&registers, read));
+        assert!(!"A < 1".parse::<Condition>().unwrap().holds(&registers, read));
+        assert!("(0xC000) != 0".parse::<Condition>().unwrap().holds(&registers, read));
+    }
+
+    #[test]
+    fn it_keeps_the_first_watchpoint_hit() {
+        let mut debugger = Debugger::new();
+        let write = debugger.add_watchpoint(Watchpoint::new(0xC000, Access::Write));
+        let any = debugger.add_watchpoint(Watchpoint { from: 0xFF40, to: 0xFF4B, access: Access::Any });
+
+        debugger.check_access(0xC000, 0x12, Access::Read);
+        assert!(debugger.take_hit().is_none());
+
+        debugger.check_access(0xFF44, 0x90, Access::Read);
+        debugger.check_access(0xC000, 0x12, Access::Write);
+
+        match debugger.take_hit() {
+            Some(StopReason::Watchpoint { id, address, value, access }) => {
+                assert_eq!((id, address, value, access), (any, 0xFF44, 0x90, Access::Read));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert!(debugger.remove(write));
+        assert!(!debugger.remove(write));
+    }
+}
@@ -0,0 +1,482 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use cpu::opcodes::{BRANCH_CYCLES, CB_CYCLES, CYCLES, LENGTHS};
+use memory::mmu::MMU;
+
+/// r[n]: registers encoded in 3 bits, (HL) is the memory it points to
+const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+
+/// rp[n]: 16-bit registers
+const PAIRS: [&str; 4] = ["BC", "DE", "HL", "SP"];
+
+/// rp2[n]: 16-bit registers used by PUSH and POP
+const STACK_PAIRS: [&str; 4] = ["BC", "DE", "HL", "AF"];
+
+/// cc[n]: conditions for jumps, calls and returns
+const CONDITIONS: [&str; 4] = ["NZ", "Z", "NC", "C"];
+
+/// Memory pointed to by the indirect loads of A
+const INDIRECT: [&str; 4] = ["(BC)", "(DE)", "(HL+)", "(HL-)"];
+
+const ALU: [&str; 8] = ["ADD", "ADC", "SUB", "SBC", "AND", "XOR", "OR", "CP"];
+
+const ACCUMULATOR: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+
+const ROTATIONS: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+/// Labels for the restart and interrupt vectors, and the entry point
+const VECTORS: [(u16, &str); 14] = [
+    (0x0000, "RST_00"),
+    (0x0008, "RST_08"),
+    (0x0010, "RST_10"),
+    (0x0018, "RST_18"),
+    (0x0020, "RST_20"),
+    (0x0028, "RST_28"),
+    (0x0030, "RST_30"),
+    (0x0038, "RST_38"),
+    (0x0040, "VBlank"),
+    (0x0048, "LCDStat"),
+    (0x0050, "Timer"),
+    (0x0058, "Serial"),
+    (0x0060, "Joypad"),
+    (0x0100, "Entry"),
+];
+
+/// Cartridge header fields: address, size and label
+///
+/// They are data, so they are listed as bytes
+const HEADER: [(u16, u16, &str); 13] = [
+    (0x0104, 0x30, "HeaderLogo"),
+    (0x0134, 0x0F, "HeaderTitle"),
+    (0x0143, 0x01, "HeaderCGBFlag"),
+    (0x0144, 0x02, "HeaderNewLicensee"),
+    (0x0146, 0x01, "HeaderSGBFlag"),
+    (0x0147, 0x01, "HeaderCartridgeType"),
+    (0x0148, 0x01, "HeaderROMSize"),
+    (0x0149, 0x01, "HeaderRAMSize"),
+    (0x014A, 0x01, "HeaderDestination"),
+    (0x014B, 0x01, "HeaderOldLicensee"),
+    (0x014C, 0x01, "HeaderVersion"),
+    (0x014D, 0x01, "HeaderChecksum"),
+    (0x014E, 0x02, "HeaderGlobalChecksum"),
+];
+
+/// A decoded instruction
+pub struct Instruction {
+    /// Address of the opcode
+    pub address: u16,
+
+    /// Opcode and operand bytes
+    pub bytes: Vec<u8>,
+
+    pub mnemonic: &'static str,
+
+    pub operands: Vec<String>,
+
+    /// M-cycles it takes, for conditional instructions
+    /// this is when the condition is not met
+    pub cycles: u8,
+
+    /// M-cycles it takes when the branch is taken,
+    /// only for conditional instructions
+    pub branch_cycles: Option<u8>,
+
+    /// Address it jumps to or calls, if it's known
+    pub target: Option<u16>,
+}
+
+impl Instruction {
+    pub fn length(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    /// Address of the instruction after this one
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.length())
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.operands.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{} {}", self.mnemonic, self.operands.join(", "))
+        }
+    }
+}
+
+/// Decodes the instruction at an address
+///
+/// Bytes are taken from the given function, so this works
+/// on anything that can be read (MMU, ROM banks, etc.)
+pub fn decode<F>(address: u16, mut read: F) -> Instruction where F: FnMut(u16) -> u8 {
+    let opcode = read(address);
+
+    let bytes: Vec<u8> = (0 .. LENGTHS[opcode as usize] as u16)
+        .map(|offset| if offset == 0 { opcode } else { read(address.wrapping_add(offset)) })
+        .collect();
+
+    let (mnemonic, operands, target) = if opcode == 0xCB {
+        decode_cb(bytes[1])
+    } else {
+        decode_base(address, &bytes)
+    };
+
+    let (cycles, branch_cycles) = if opcode == 0xCB {
+        (CB_CYCLES[bytes[1] as usize], None)
+    } else {
+        let cycles = CYCLES[opcode as usize];
+        let branch_cycles = BRANCH_CYCLES[opcode as usize];
+
+        (cycles, if branch_cycles != cycles { Some(branch_cycles) } else { None })
+    };
+
+    Instruction {
+        address,
+        bytes,
+        mnemonic,
+        operands,
+        cycles,
+        branch_cycles,
+        target,
+    }
+}
+
+/// Decodes the instruction at an address, as the CPU sees it
+pub fn decode_memory(mmu: &mut MMU, address: u16) -> Instruction {
+    decode(address, |a| mmu.read_byte(a))
+}
+
+/// Decodes the instruction at an address of a ROM
+///
+/// 0x0000-0x3FFF is always bank 0, and the given bank is the one
+/// at 0x4000-0x7FFF. Anything past the end of the ROM reads 0xFF
+pub fn decode_rom(rom: &[u8], bank: usize, address: u16) -> Instruction {
+    decode(address, |a| read_rom(rom, bank, a))
+}
+
+fn read_rom(rom: &[u8], bank: usize, address: u16) -> u8 {
+    let index = if address < 0x4000 {
+        address as usize
+    } else {
+        bank * 0x4000 + (address as usize & 0x3FFF)
+    };
+
+    rom.get(index).cloned().unwrap_or(0xFF)
+}
+
+fn byte(value: u8) -> String {
+    format!("${:02X}", value)
+}
+
+fn word(value: u16) -> String {
+    format!("${:04X}", value)
+}
+
+fn signed(value: u8) -> String {
+    format!("{:+}", value as i8)
+}
+
+fn operands(list: &[&str]) -> Vec<String> {
+    list.iter().map(|operand| operand.to_string()).collect()
+}
+
+/// Operands of the ALU operations, ADD, ADC and SBC name A
+fn alu_operands(index: u8, operand: String) -> Vec<String> {
+    match index {
+        0 | 1 | 3 => vec!["A".to_string(), operand],
+        _ => vec![operand],
+    }
+}
+
+/// Decodes the base page, following the same x/y/z
+/// layout the CPU uses (see `Z80::execute`)
+fn decode_base(address: u16, bytes: &[u8]) -> (&'static str, Vec<String>, Option<u16>) {
+    let opcode = bytes[0];
+    let n = if bytes.len() > 1 { bytes[1] } else { 0 };
+    let nn = if bytes.len() > 2 { (bytes[2] as u16) << 8 | bytes[1] as u16 } else { 0 };
+
+    let x = opcode >> 6;
+    let y = ((opcode >> 3) & 0x07) as usize;
+    let z = (opcode & 0x07) as usize;
+    let p = y >> 1;
+    let q = y & 0x01;
+
+    let relative = address.wrapping_add(2).wrapping_add(n as i8 as u16);
+
+    match (x, z) {
+        (0, 0) => {
+            match y {
+                0 => ("NOP", vec![], None),
+                1 => ("LD", vec![format!("({})", word(nn)), "SP".to_string()], None),
+                2 => ("STOP", vec![], None),
+                3 => ("JR", vec![word(relative)], Some(relative)),
+                _ => ("JR", vec![CONDITIONS[y - 4].to_string(), word(relative)], Some(relative)),
+            }
+        },
+
+        (0, 1) => {
+            if q == 0 {
+                ("LD", vec![PAIRS[p].to_string(), word(nn)], None)
+            } else {
+                ("ADD", operands(&["HL", PAIRS[p]]), None)
+            }
+        },
+
+        (0, 2) => {
+            if q == 0 {
+                ("LD", operands(&[INDIRECT[p], "A"]), None)
+            } else {
+                ("LD", operands(&["A", INDIRECT[p]]), None)
+            }
+        },
+
+        (0, 3) => (if q == 0 { "INC" } else { "DEC" }, operands(&[PAIRS[p]]), None),
+        (0, 4) => ("INC", operands(&[REGISTERS[y]]), None),
+        (0, 5) => ("DEC", operands(&[REGISTERS[y]]), None),
+        (0, 6) => ("LD", vec![REGISTERS[y].to_string(), byte(n)], None),
+        (0, _) => (ACCUMULATOR[y], vec![], None),
+
+        (1, _) => {
+            if opcode == 0x76 {
+                ("HALT", vec![], None)
+            } else {
+                ("LD", operands(&[REGISTERS[y], REGISTERS[z]]), None)
+            }
+        },
+
+        (2, _) => (ALU[y], alu_operands(y as u8, REGISTERS[z].to_string()), None),
+
+        (3, 0) => {
+            match y {
+                0 ..= 3 => ("RET", operands(&[CONDITIONS[y]]), None),
+                4 => ("LDH", vec![format!("({})", word(0xFF00 | n as u16)), "A".to_string()], None),
+                5 => ("ADD", vec!["SP".to_string(), signed(n)], None),
+                6 => ("LDH", vec!["A".to_string(), format!("({})", word(0xFF00 | n as u16))], None),
+                _ => ("LD", vec!["HL".to_string(), format!("SP{}", signed(n))], None),
+            }
+        },
+
+        (3, 1) => {
+            match (q, p) {
+                (0, _) => ("POP", operands(&[STACK_PAIRS[p]]), None),
+                (_, 0) => ("RET", vec![], None),
+                (_, 1) => ("RETI", vec![], None),
+                (_, 2) => ("JP", operands(&["HL"]), None),
+                _ => ("LD", operands(&["SP", "HL"]), None),
+            }
+        },
+
+        (3, 2) => {
+            match y {
+                0 ..= 3 => ("JP", vec![CONDITIONS[y].to_string(), word(nn)], Some(nn)),
+                4 => ("LD", operands(&["($FF00+C)", "A"]), None),
+                5 => ("LD", vec![format!("({})", word(nn)), "A".to_string()], None),
+                6 => ("LD", operands(&["A", "($FF00+C)"]), None),
+                _ => ("LD", vec!["A".to_string(), format!("({})", word(nn))], None),
+            }
+        },
+
+        (3, 3) => {
+            match y {
+                0 => ("JP", vec![word(nn)], Some(nn)),
+                6 => ("DI", vec![], None),
+                7 => ("EI", vec![], None),
+                _ => ("DB", vec![byte(opcode)], None),
+            }
+        },
+
+        (3, 4) => {
+            if y < 4 {
+                ("CALL", vec![CONDITIONS[y].to_string(), word(nn)], Some(nn))
+            } else {
+                ("DB", vec![byte(opcode)], None)
+            }
+        },
+
+        (3, 5) => {
+            match (q, p) {
+                (0, _) => ("PUSH", operands(&[STACK_PAIRS[p]]), None),
+                (_, 0) => ("CALL", vec![word(nn)], Some(nn)),
+                _ => ("DB", vec![byte(opcode)], None),
+            }
+        },
+
+        (3, 6) => (ALU[y], alu_operands(y as u8, byte(n)), None),
+
+        _ => {
+            let vector = (y as u16) << 3;
+            ("RST", vec![byte(vector as u8)], Some(vector))
+        },
+    }
+}
+
+/// Decodes the CB-prefixed page
+fn decode_cb(opcode: u8) -> (&'static str, Vec<String>, Option<u16>) {
+    let y = ((opcode >> 3) & 0x07) as usize;
+    let register = REGISTERS[(opcode & 0x07) as usize].to_string();
+
+    match opcode >> 6 {
+        0 => (ROTATIONS[y], vec![register], None),
+        1 => ("BIT", vec![y.to_string(), register], None),
+        2 => ("RES", vec![y.to_string(), register], None),
+        _ => ("SET", vec![y.to_string(), register], None),
+    }
+}
+
+/// Label for an address, if it's one of the vectors or
+/// a header field
+pub fn label(address: u16) -> Option<&'static str> {
+    VECTORS.iter()
+        .cloned()
+        .chain(HEADER.iter().map(|&(field, _, label)| (field, label)))
+        .find(|&(a, _)| a == address)
+        .map(|(_, label)| label)
+}
+
+/// Header field an address belongs to, returns where it ends
+fn header_field_end(address: u16) -> Option<u16> {
+    HEADER.iter()
+        .find(|&&(field, size, _)| address >= field && address < field + size)
+        .map(|&(field, size, _)| field + size)
+}
+
+/// Writes the listing of a ROM between two addresses (both included)
+///
+/// Every line starts with the bank and the address, followed by the
+/// instruction bytes and the instruction. The header is listed as data
+pub fn write_listing<W: Write>(out: &mut W, rom: &[u8], bank: usize, from: u16, to: u16) -> io::Result<()> {
+    let mut address = from as u32;
+
+    while address <= to as u32 {
+        let a = address as u16;
+        let prefix = if a < 0x4000 { 0 } else { bank };
+
+        if let Some(label) = label(a) {
+            writeln!(out, "{}:", label)?;
+        }
+
+        if let Some(end) = header_field_end(a) {
+            let bytes: Vec<String> = (a .. end.min(a + 16))
+                .map(|b| byte(read_rom(rom, bank, b)))
+                .collect();
+
+            writeln!(out, "{:02X}:{:04X}  {:<9} DB {}", prefix, a, "", bytes.join(","))?;
+            address += bytes.len() as u32;
+        } else {
+            let instruction = decode_rom(rom, bank, a);
+
+            let bytes: Vec<String> = instruction.bytes.iter()
+                .map(|b| format!("{:02X}", b))
+                .collect();
+
+            writeln!(out, "{:02X}:{:04X}  {:<9} {}", prefix, a, bytes.join(" "), instruction)?;
+            address += instruction.length() as u32;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::z80::Z80;
+
+    fn disassemble(code: &[u8]) -> Vec<String> {
+        let mut cpu = Z80::from_program(code);
+        let mut address = 0x100;
+        let mut listing = vec![];
+
+        while address < 0x100 + code.len() as u16 {
+            let instruction = cpu.disassemble(address);
+            address = instruction.next_address();
+            listing.push(instruction.to_string());
+        }
+
+        listing
+    }
+
+    #[test]
+    fn it_decodes_the_base_page() {
+        let code = [
+            0x00, 0x01, 0x34, 0x12, 0x22, 0x3E, 0x42, 0x47, 0x76, 0x86, 0xD6, 0x01,
+            0xE0, 0x44, 0xF8, 0xFE, 0xF5, 0xE9, 0xC3, 0x50, 0x01, 0xFF, 0xD3,
+        ];
+
+        assert_eq!(disassemble(&code), vec![
+            "NOP", "LD BC, $1234", "LD (HL+), A", "LD A, $42", "LD B, A", "HALT",
+            "ADD A, (HL)", "SUB $01", "LDH ($FF44), A", "LD HL, SP-2", "PUSH AF",
+            "JP HL", "JP $0150", "RST $38", "DB $D3",
+        ]);
+    }
+
+    #[test]
+    fn it_decodes_the_cb_page() {
+        let code = [0xCB, 0x11, 0xCB, 0x37, 0xCB, 0x7E, 0xCB, 0x87, 0xCB, 0xFE];
+
+        assert_eq!(disassemble(&code), vec![
+            "RL C", "SWAP A", "BIT 7, (HL)", "RES 0, A", "SET 7, (HL)",
+        ]);
+    }
+
+    #[test]
+    fn it_reports_length_cycles_and_targets() {
+        // JR NZ, -2 / CALL $4000 / BIT 0, (HL)
+        let mut cpu = Z80::from_program(&[0x20, 0xFE, 0xCD, 0x00, 0x40, 0xCB, 0x46]);
+
+        let jump = cpu.disassemble(0x100);
+        assert_eq!(jump.to_string(), "JR NZ, $0100");
+        assert_eq!((jump.length(), jump.cycles, jump.branch_cycles), (2, 2, Some(3)));
+        assert_eq!(jump.target, Some(0x100));
+
+        let call = cpu.disassemble(0x102);
+        assert_eq!((call.length(), call.cycles, call.branch_cycles), (3, 6, None));
+        assert_eq!(call.target, Some(0x4000));
+
+        let bit = cpu.disassemble(0x105);
+        assert_eq!((bit.length(), bit.cycles), (2, 3));
+    }
+
+    #[test]
+    fn it_reads_the_selected_bank() {
+        let mut data = vec![0xFF; 0x10000];
+        data[0x0000] = 0x00;
+        data[0x8000] = 0xAF;
+        data[0xC000] = 0xC9;
+
+        assert_eq!(decode_rom(&data, 2, 0x0000).to_string(), "NOP");
+        assert_eq!(decode_rom(&data, 2, 0x4000).to_string(), "XOR A");
+        assert_eq!(decode_rom(&data, 3, 0x4000).to_string(), "RET");
+        assert_eq!(decode_rom(&data, 4, 0x4000).to_string(), "RST $38");
+    }
+
+    #[test]
+    fn it_lists_with_labels() {
+        let mut data = vec![0; 0x8000];
+        data[0x100] = 0x00;
+        data[0x101] = 0xC3;
+        data[0x102] = 0x50;
+        data[0x103] = 0x01;
+        data[0x147] = 0x01;
+
+        let mut out = vec![];
+        write_listing(&mut out, &data, 1, 0x100, 0x105).unwrap();
+
+        let listing = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines[0], "Entry:");
+        assert_eq!(lines[1], "00:0100  00        NOP");
+        assert_eq!(lines[2], "00:0101  C3 50 01  JP $0150");
+        assert_eq!(lines[3], "HeaderLogo:");
+        assert!(lines[4].starts_with("00:0104            DB $00,$00"));
+        assert_eq!(lines.len(), 5);
+
+        let mut out = vec![];
+        write_listing(&mut out, &data, 1, 0x4000, 0x4000).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "01:4000  00        NOP\n");
+    }
+}
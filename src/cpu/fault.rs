@@ -0,0 +1,42 @@
+use std::fmt;
+use cpu::registers::RegisterSet;
+
+/// CPU fault
+///
+/// The GameBoy CPU has 11 undefined opcodes (0xD3, 0xDB, 0xDD, 0xE3,
+/// 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC and 0xFD). Executing any of them
+/// locks the CPU up until the console is turned off, which usually
+/// means the game crashed. This is what we know about the crash
+#[derive(Copy, Clone)]
+pub struct CpuFault {
+    /// Address of the opcode
+    pub program_counter: u16,
+
+    /// The opcode that locked up the CPU
+    pub opcode: u8,
+
+    /// ROM bank mapped at the program counter (0 outside ROM)
+    pub bank: usize,
+
+    /// Registers when the opcode was fetched
+    pub registers: RegisterSet,
+}
+
+impl fmt::Display for CpuFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let r = &self.registers;
+
+        write!(
+            f,
+            "illegal opcode {:02X} at {:02X}:{:04X} (A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X})",
+            self.opcode, self.bank, self.program_counter,
+            r.a, r.flags, r.b, r.c, r.d, r.e, r.h, r.l, r.stack_pointer
+        )
+    }
+}
+
+impl fmt::Debug for CpuFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
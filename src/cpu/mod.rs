@@ -2,4 +2,9 @@
 pub mod z80;
 
 pub mod registers;
-pub mod timer;
\ No newline at end of file
+pub mod timer;
+pub mod fault;
+pub mod opcodes;
+pub mod disasm;
+pub mod trace;
+pub mod debugger;
\ No newline at end of file
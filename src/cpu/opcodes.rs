@@ -0,0 +1,197 @@
+/// Opcode cycle tables
+///
+/// These are the M-cycles (4 clock ticks each) every opcode takes,
+/// the CPU returns them after executing an instruction. For the
+/// conditional ones (JR, JP, CALL and RET) this is the time taken
+/// when the condition is not met, see `BRANCH_CYCLES`
+///
+/// Undefined opcodes are 0, they lock up the CPU
+pub const CYCLES: [u8; 256] = [
+//  0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
+    1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1, // 0x
+    1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1, // 1x
+    2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1, // 2x
+    2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1, // 3x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 4x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 5x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 6x
+    2, 2, 2, 2, 2, 2, 1, 2, 1, 1, 1, 1, 1, 1, 2, 1, // 7x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 8x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 9x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // Ax
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // Bx
+    2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 1, 3, 6, 2, 4, // Cx
+    2, 3, 3, 0, 3, 4, 2, 4, 2, 4, 3, 0, 3, 0, 2, 4, // Dx
+    3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4, // Ex
+    3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4, // Fx
+];
+
+/// M-cycles of the conditional opcodes when the branch is taken
+///
+/// The extra cycles are spent reading the return address (RET),
+/// pushing the current one (CALL) or loading the program counter
+pub const BRANCH_CYCLES: [u8; 256] = {
+    let mut cycles = CYCLES;
+
+    cycles[0x20] = 3; cycles[0x28] = 3; cycles[0x30] = 3; cycles[0x38] = 3;
+    cycles[0xC0] = 5; cycles[0xC8] = 5; cycles[0xD0] = 5; cycles[0xD8] = 5;
+    cycles[0xC2] = 4; cycles[0xCA] = 4; cycles[0xD2] = 4; cycles[0xDA] = 4;
+    cycles[0xC4] = 6; cycles[0xCC] = 6; cycles[0xD4] = 6; cycles[0xDC] = 6;
+
+    cycles
+};
+
+/// M-cycles of the CB-prefixed opcodes, including the prefix
+///
+/// Every operation on a register takes 2, on (HL) it takes 4,
+/// as the value is read and written back, except for BIT
+/// which only reads it
+pub const CB_CYCLES: [u8; 256] = {
+    let mut cycles = [2; 256];
+    let mut opcode = 0;
+
+    while opcode < 256 {
+        if opcode & 0x07 == 6 {
+            cycles[opcode] = if opcode & 0xC0 == 0x40 { 3 } else { 4 };
+        }
+
+        opcode += 1;
+    }
+
+    cycles
+};
+
+/// Length in bytes of each opcode, including its operands
+///
+/// CB-prefixed opcodes are always 2 bytes long, STOP is followed
+/// by an ignored byte, and undefined opcodes are just one
+pub const LENGTHS: [u8; 256] = [
+//  0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
+    1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1, // 0x
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 1x
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 2x
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 3x
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 4x
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 5x
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 6x
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 7x
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 8x
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 9x
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // Ax
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // Bx
+    1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 2, 3, 3, 2, 1, // Cx
+    1, 1, 3, 1, 3, 1, 2, 1, 1, 1, 3, 1, 3, 1, 2, 1, // Dx
+    2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // Ex
+    2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // Fx
+];
+
+/// M-cycles an opcode took, depending on whether its branch was taken
+pub fn cycles(opcode: u8, branch: bool) -> u32 {
+    if branch {
+        BRANCH_CYCLES[opcode as usize] as u32
+    } else {
+        CYCLES[opcode as usize] as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // M-cycles from the documented timing of each group of opcodes,
+    // one per byte fetched and per memory access, plus the internal
+    // ones. STOP, HALT, the CB prefix and undefined opcodes are None
+    fn documented_cycles(opcode: u8, branch: bool) -> Option<u8> {
+        let y = (opcode >> 3) & 0x07;
+        let z = opcode & 0x07;
+        let p = y >> 1;
+        let q = y & 0x01;
+
+        // r[6] is (HL), which costs a memory access
+        let hl = |index: u8| if index == 6 { 1 } else { 0 };
+
+        let cycles = match (opcode >> 6, z) {
+            (0, 0) => match y {
+                0 => 1,                             // NOP
+                1 => 5,                             // LD (nn), SP
+                2 => return None,                   // STOP
+                3 => 3,                             // JR d
+                _ => if branch { 3 } else { 2 },    // JR cc, d
+            },
+            (0, 1) => if q == 0 { 3 } else { 2 },   // LD rr, nn / ADD HL, rr
+            (0, 2) | (0, 3) => 2,                   // indirect loads / INC rr, DEC rr
+            (0, 4) | (0, 5) => 1 + 2 * hl(y),       // INC r / DEC r
+            (0, 6) => 2 + hl(y),                    // LD r, n
+            (0, _) => 1,                            // rotations, DAA, CPL, SCF, CCF
+
+            (1, _) if opcode == 0x76 => return None, // HALT
+            (1, _) => 1 + hl(y) + hl(z),            // LD r, r
+            (2, _) => 1 + hl(z),                    // ALU A, r
+
+            (_, 0) => match y {
+                0 ..= 3 => if branch { 5 } else { 2 }, // RET cc
+                4 | 6 => 3,                         // LDH (n), A / LDH A, (n)
+                5 => 4,                             // ADD SP, d
+                _ => 3,                             // LD HL, SP + d
+            },
+            (_, 1) => match (q, p) {
+                (0, _) => 3,                        // POP rr
+                (_, 0) | (_, 1) => 4,               // RET / RETI
+                (_, 2) => 1,                        // JP HL
+                _ => 2,                             // LD SP, HL
+            },
+            (_, 2) => match y {
+                0 ..= 3 => if branch { 4 } else { 3 }, // JP cc, nn
+                4 | 6 => 2,                         // LD (C), A / LD A, (C)
+                _ => 4,                             // LD (nn), A / LD A, (nn)
+            },
+            (_, 3) => match y {
+                0 => 4,                             // JP nn
+                6 | 7 => 1,                         // DI / EI
+                _ => return None,                   // CB prefix and undefined
+            },
+            (_, 4) if y < 4 => if branch { 6 } else { 3 }, // CALL cc, nn
+            (_, 5) if q == 0 => 4,                  // PUSH rr
+            (_, 5) if p == 0 => 6,                  // CALL nn
+            (_, 4) | (_, 5) => return None,         // undefined
+            (_, 6) => 2,                            // ALU A, n
+            _ => 4,                                 // RST
+        };
+
+        Some(cycles)
+    }
+
+    // register operations take the prefix and the opcode, (HL) is
+    // read and written back, except for BIT which only reads it
+    fn documented_cb_cycles(opcode: u8) -> u8 {
+        match (opcode >> 6, opcode & 0x07) {
+            (1, 6) => 3,
+            (_, 6) => 4,
+            _ => 2,
+        }
+    }
+
+    #[test]
+    fn it_matches_the_documented_timing() {
+        for opcode in 0 .. 256 {
+            if let Some(cycles) = documented_cycles(opcode as u8, false) {
+                assert_eq!(CYCLES[opcode], cycles, "opcode {:02X}", opcode);
+            }
+
+            if let Some(cycles) = documented_cycles(opcode as u8, true) {
+                assert_eq!(BRANCH_CYCLES[opcode], cycles, "opcode {:02X}", opcode);
+            }
+
+            assert_eq!(CB_CYCLES[opcode], documented_cb_cycles(opcode as u8), "opcode CB {:02X}", opcode);
+        }
+    }
+
+    #[test]
+    fn it_only_locks_up_on_undefined_opcodes() {
+        let undefined = [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD];
+
+        for (opcode, &cycles) in CYCLES.iter().enumerate() {
+            assert_eq!(cycles == 0, undefined.contains(&opcode), "opcode {:02X}", opcode);
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use model::Model;
+
 /// The GameBoy Z80 CPU Registers
 ///
 /// These registers are used by the CPU to perform calculation. They
@@ -62,19 +64,63 @@ impl RegisterSet {
         // these register initial values are taken
         // from original hardware (as state left by the
         // GameBoy boot ROM)
+        RegisterSet::post_boot(Model::DMG, 0x01)
+    }
+
+    /// Registers left by the boot ROM of each model
+    ///
+    /// The DMG and MGB boot ROMs leave the flags from the header
+    /// checksum verification, so they depend on the cartridge
+    pub fn post_boot(model: Model, header_checksum: u8) -> RegisterSet {
+        let checksum_flags = if header_checksum == 0 { 0x80 } else { 0xB0 };
+
+        let (a, flags, b, c, d, e, h, l) = match model {
+            Model::DMG0 => (0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03),
+            Model::DMG  => (0x01, checksum_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::MGB  => (0xFF, checksum_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::SGB  => (0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            Model::SGB2 => (0xFF, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            Model::CGB  => (0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+
+            // same as the CGB, but B has bit 0 set, this
+            // is how games detect the GameBoy Advance
+            Model::AGB  => (0x11, 0x00, 0x01, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+        };
+
+        RegisterSet {
+            a,
+            b,
+            c,
+            d,
+            e,
+            h,
+            l,
+
+            flags,
+
+            program_counter: 0x0100,
+            stack_pointer: 0xFFFE,
+        }
+    }
+
+    /// Power on registers
+    ///
+    /// State before running the boot ROM, which starts
+    /// at 0x0000 and sets up everything else itself
+    pub fn power_on() -> RegisterSet {
         RegisterSet {
-            a: 0b0000_0001,
-            b: 0b0000_0000,
-            c: 0b0001_0011,
-            d: 0x0000_0000,
-            e: 0b1101_1000,
-            h: 0b0000_0001,
-            l: 0b0100_1101,
-
-            flags: 0b1011_0000,
-
-            program_counter: 0b0000_0001_0000_0000,
-            stack_pointer: 0b1111_1111_1111_1110,
+            a: 0,
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            h: 0,
+            l: 0,
+
+            flags: 0,
+
+            program_counter: 0x0000,
+            stack_pointer: 0x0000,
         }
     }
 
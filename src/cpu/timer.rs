@@ -67,6 +67,15 @@ impl Timer {
         }
     }
 
+    /// Sets the divider and its internal phase
+    ///
+    /// The upper byte is the DIV register, the lower
+    /// byte is how far the next increment is
+    pub fn set_divider_counter(&mut self, counter: u16) {
+        self.divider = (counter >> 8) as u8;
+        self.internal_divider = (counter & 0xFF) as u32;
+    }
+
     pub fn read_byte(&self, address: u16) -> u8 {
         match address {
             0xFF04 => self.divider,
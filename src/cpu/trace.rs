@@ -0,0 +1,286 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::str::FromStr;
+
+use cpu::registers::RegisterSet;
+use cpu::debugger::parse_location;
+use error::SafeboyError;
+
+/// Size of the trace buffer, traces easily go over
+/// millions of lines, so they are written in big chunks
+const BUFFER_SIZE: usize = 1 << 20;
+
+/// When to start or stop tracing
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Trigger {
+    /// After this many instructions
+    Instructions(u64),
+
+    /// When the program counter gets to an address, in
+    /// any bank or only in the given one
+    ProgramCounter(Option<usize>, u16),
+}
+
+impl Trigger {
+    fn fired(&self, instructions: u64, bank: usize, program_counter: u16) -> bool {
+        match *self {
+            Trigger::Instructions(count) => instructions >= count,
+            Trigger::ProgramCounter(None, address) => program_counter == address,
+            Trigger::ProgramCounter(Some(b), address) => program_counter == address && bank == b,
+        }
+    }
+}
+
+/// Parses a trigger
+///
+/// A number is a count of instructions, while pc:ADDR or
+/// pc:BANK:ADDR (both in hex) is a program counter trigger
+impl FromStr for Trigger {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Trigger, String> {
+        let invalid = || format!("{} is not a valid trigger, use a number of instructions, pc:ADDR or pc:BANK:ADDR", value);
+
+        if !value.starts_with("pc:") {
+            return value.parse().map(Trigger::Instructions).map_err(|_| invalid())
+        }
+
+        let (bank, address) = parse_location(&value[3 ..]).map_err(|_| invalid())?;
+
+        Ok(Trigger::ProgramCounter(bank, address))
+    }
+}
+
+/// Trace options
+///
+/// By default, everything is traced from the first instruction
+#[derive(Clone, Debug, Default)]
+pub struct TraceOptions {
+    pub start: Option<Trigger>,
+    pub stop: Option<Trigger>,
+
+    /// Only trace code running from these ROM banks, code
+    /// outside the ROM (like RAM routines) is skipped too
+    pub banks: Vec<usize>,
+}
+
+#[derive(PartialEq, Copy, Clone)]
+enum TraceState {
+    Waiting,
+    Tracing,
+    Done,
+}
+
+/// Execution tracer
+///
+/// Writes a line per instruction with the registers before running
+/// it and the 4 bytes at PC, in the format used by Gameboy Doctor,
+/// so traces can be compared against other emulators:
+///
+/// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+pub struct Tracer {
+    out: BufWriter<Box<dyn Write>>,
+    options: TraceOptions,
+    state: TraceState,
+
+    /// Instructions executed since tracing was set up
+    instructions: u64,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, options: TraceOptions) -> Tracer {
+        let state = if options.start.is_some() {
+            TraceState::Waiting
+        } else {
+            TraceState::Tracing
+        };
+
+        Tracer {
+            out: BufWriter::with_capacity(BUFFER_SIZE, out),
+            options,
+            state,
+            instructions: 0,
+        }
+    }
+
+    /// Creates a tracer writing to a file
+    pub fn create(trace_file: &str, options: TraceOptions) -> Result<Tracer, SafeboyError> {
+        let file = File::create(trace_file)
+            .map_err(|e| SafeboyError::TraceFile(trace_file.to_string(), e))?;
+
+        Ok(Tracer::new(Box::new(file), options))
+    }
+
+    /// Whether the stop trigger was already hit
+    pub fn done(&self) -> bool {
+        self.state == TraceState::Done
+    }
+
+    /// Instructions seen so far
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Logs an instruction about to be executed
+    ///
+    /// The bank is where the program counter is, and the
+    /// memory the 4 bytes starting at the program counter
+    pub fn log(&mut self, registers: &RegisterSet, bank: usize, memory: [u8; 4]) {
+        let program_counter = registers.program_counter;
+
+        if self.state == TraceState::Waiting {
+            if let Some(start) = self.options.start {
+                if start.fired(self.instructions, bank, program_counter) {
+                    self.state = TraceState::Tracing;
+                }
+            }
+        }
+
+        if self.state == TraceState::Tracing {
+            if let Some(stop) = self.options.stop {
+                if stop.fired(self.instructions, bank, program_counter) {
+                    self.finish();
+                }
+            }
+        }
+
+        if self.state == TraceState::Tracing && self.in_banks(bank, program_counter) {
+            if let Err(e) = self.write(registers, memory) {
+                eprintln!("Could not write the trace: {}", e);
+                self.state = TraceState::Done;
+            }
+        }
+
+        self.instructions += 1;
+    }
+
+    /// Stops tracing, writing out whatever is buffered
+    pub fn finish(&mut self) {
+        self.state = TraceState::Done;
+
+        if let Err(e) = self.out.flush() {
+            eprintln!("Could not write the trace: {}", e);
+        }
+    }
+
+    fn in_banks(&self, bank: usize, program_counter: u16) -> bool {
+        self.options.banks.is_empty() ||
+            (program_counter < 0x8000 && self.options.banks.contains(&bank))
+    }
+
+    fn write(&mut self, registers: &RegisterSet, memory: [u8; 4]) -> io::Result<()> {
+        writeln!(
+            self.out,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            registers.a,
+            registers.flags,
+            registers.b,
+            registers.c,
+            registers.d,
+            registers.e,
+            registers.h,
+            registers.l,
+            registers.stack_pointer,
+            registers.program_counter,
+            memory[0],
+            memory[1],
+            memory[2],
+            memory[3]
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    /// Writer the test can read back
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(options: TraceOptions, program: &[(usize, u16)]) -> Vec<String> {
+        let output = Output::default();
+        let mut tracer = Tracer::new(Box::new(output.clone()), options);
+        let mut registers = RegisterSet::new();
+
+        for &(bank, program_counter) in program {
+            registers.program_counter = program_counter;
+            tracer.log(&registers, bank, [0x00, 0xC3, 0x13, 0x02]);
+        }
+
+        tracer.finish();
+
+        let text = String::from_utf8(output.0.borrow().clone()).unwrap();
+        text.lines().map(|line| line[line.find("PC:").unwrap() + 3 ..][.. 4].to_string()).collect()
+    }
+
+    #[test]
+    fn it_writes_gameboy_doctor_lines() {
+        let output = Output::default();
+        let mut tracer = Tracer::new(Box::new(output.clone()), TraceOptions::default());
+
+        tracer.log(&RegisterSet::new(), 0, [0x00, 0xC3, 0x13, 0x02]);
+        tracer.finish();
+
+        assert_eq!(
+            String::from_utf8(output.0.borrow().clone()).unwrap(),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02\n"
+        );
+    }
+
+    #[test]
+    fn it_starts_and_stops_on_triggers() {
+        let program = [(0, 0x100), (0, 0x101), (1, 0x4000), (1, 0x4001), (0, 0x102)];
+
+        let options = TraceOptions {
+            start: Some(Trigger::Instructions(1)),
+            stop: Some(Trigger::ProgramCounter(None, 0x4001)),
+            banks: vec![],
+        };
+
+        assert_eq!(trace(options, &program), vec!["0101", "4000"]);
+
+        let options = TraceOptions {
+            start: Some(Trigger::ProgramCounter(Some(1), 0x4000)),
+            stop: Some(Trigger::Instructions(4)),
+            banks: vec![],
+        };
+
+        assert_eq!(trace(options, &program), vec!["4000", "4001"]);
+    }
+
+    #[test]
+    fn it_filters_banks() {
+        let program = [(0, 0x100), (1, 0x4000), (1, 0xC000), (2, 0x4000)];
+
+        let options = TraceOptions {
+            banks: vec![1],
+            ..TraceOptions::default()
+        };
+
+        assert_eq!(trace(options, &program), vec!["4000"]);
+    }
+
+    #[test]
+    fn it_parses_triggers() {
+        assert_eq!("1000".parse(), Ok(Trigger::Instructions(1000)));
+        assert_eq!("pc:0150".parse(), Ok(Trigger::ProgramCounter(None, 0x150)));
+        assert_eq!("pc:1F:4000".parse(), Ok(Trigger::ProgramCounter(Some(0x1F), 0x4000)));
+        assert_eq!("pc:$1F:0x4000".parse(), Ok(Trigger::ProgramCounter(Some(0x1F), 0x4000)));
+        assert!("pc:zz".parse::<Trigger>().is_err());
+        assert!("soon".parse::<Trigger>().is_err());
+    }
+}
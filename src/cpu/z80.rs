@@ -1,11 +1,59 @@
 use cpu::registers::RegisterSet;
+use cpu::fault::CpuFault;
+use cpu::opcodes;
+use cpu::trace::Tracer;
+use cpu::debugger::{Access, Debugger, Goal, StopReason};
+use cpu::disasm::{self, Instruction};
 use memory::mmu::MMU;
 use cpu::registers::CpuFlag::{C, N, H, Z};
 use frontend::keypad::Key;
+use memory::mbc::{ImageSource, RtcClock};
+use error::SafeboyError;
+use model::Model;
 
 /// CPU Speed, set a 4194304 Hz (taken from the original hardware)
 const CPU_SPEED: u32 = 4_194_304;
 
+/// Clock ticks (T-cycles) in a machine cycle (M-cycle)
+///
+/// Opcodes are timed in M-cycles, while the timer, the GPU and the
+/// cartridge clocks count ticks
+const TICKS_PER_CYCLE: u32 = 4;
+
+/// Calls a handler with the opcode as a constant
+///
+/// Every opcode gets its own copy of the inlined handler, where
+/// the decoding of the opcode bits folds away. Without this, the
+/// decoder is noticeably slower than matching every opcode by hand
+macro_rules! dispatch {
+    ($cpu:ident.$handler:ident($opcode:expr)) => {
+        dispatch!(@arms $cpu, $handler, $opcode,
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F,
+            0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x1B, 0x1C, 0x1D, 0x1E, 0x1F,
+            0x20, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x28, 0x29, 0x2A, 0x2B, 0x2C, 0x2D, 0x2E, 0x2F,
+            0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x3B, 0x3C, 0x3D, 0x3E, 0x3F,
+            0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4A, 0x4B, 0x4C, 0x4D, 0x4E, 0x4F,
+            0x50, 0x51, 0x52, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x5B, 0x5C, 0x5D, 0x5E, 0x5F,
+            0x60, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6A, 0x6B, 0x6C, 0x6D, 0x6E, 0x6F,
+            0x70, 0x71, 0x72, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x7B, 0x7C, 0x7D, 0x7E, 0x7F,
+            0x80, 0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8A, 0x8B, 0x8C, 0x8D, 0x8E, 0x8F,
+            0x90, 0x91, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0x9B, 0x9C, 0x9D, 0x9E, 0x9F,
+            0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xAB, 0xAC, 0xAD, 0xAE, 0xAF,
+            0xB0, 0xB1, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xBB, 0xBC, 0xBD, 0xBE, 0xBF,
+            0xC0, 0xC1, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xCB, 0xCC, 0xCD, 0xCE, 0xCF,
+            0xD0, 0xD1, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xDB, 0xDC, 0xDD, 0xDE, 0xDF,
+            0xE0, 0xE1, 0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xEB, 0xEC, 0xED, 0xEE, 0xEF,
+            0xF0, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8, 0xF9, 0xFA, 0xFB, 0xFC, 0xFD, 0xFE, 0xFF,
+        )
+    };
+
+    (@arms $cpu:ident, $handler:ident, $opcode:expr, $($value:expr,)*) => {
+        match $opcode {
+            $($value => $cpu.$handler($value),)*
+        }
+    };
+}
+
 /// Z80 CPU
 ///
 /// This is the brain of the GameBoy, where operations sent
@@ -38,6 +86,26 @@ pub struct Z80 {
     /// by an opcode, and only remains until an interrupt occurs (use for low battery)
     halted: bool,
 
+    /// HALT bug
+    ///
+    /// HALT with IME disabled and an interrupt already pending
+    /// doesn't halt, instead the CPU fails to increment the program
+    /// counter after fetching the next opcode, so the byte after
+    /// HALT is read twice
+    halt_bug: bool,
+
+    /// Stopped flag
+    ///
+    /// Set by STOP, the CPU and the LCD stop (very low power mode)
+    /// until a key of the selected keypad rows is pressed
+    stopped: bool,
+
+    /// CPU fault
+    ///
+    /// Set when the CPU locks up after an illegal opcode,
+    /// nothing but a reset gets it running again
+    fault: Option<CpuFault>,
+
     /// Interrupt master enable (INTERRUPT_MASTER_ENABLE)
     ///
     /// This is a internal variable we use to enable or disable
@@ -47,17 +115,12 @@ pub struct Z80 {
     /// 1 -> Enable interrupts from the IE in MMU
     interrupt_master_enable: bool,
 
-    /// Set disable interrupt
+    /// Interrupt master enable scheduled
     ///
-    /// Whether we need to disable interrupts. This is set during
-    /// an opcode and updates the CPU interrupt master enable flag
-    /// in the next cycle of the CPU
-    set_disable_interrupts: u32,
-    
-    /// Set enable interrupt
-    /// 
-    /// Inverse of the set disable interrupt. It has a different opcode
-    set_enable_interrupts: u32,
+    /// EI doesn't enable interrupts right away, but after the
+    /// instruction that follows it. DI, on the other hand, has
+    /// an immediate effect (and cancels a scheduled EI)
+    interrupt_master_enable_scheduled: bool,
     
     /// CPU internal clock
     /// 
@@ -71,2971 +134,1178 @@ pub struct Z80 {
     /// from the CPU_SPEED constant (taken from the original hardware)
     ///
     /// This is used to limit the FPS
-    cpu_speed: u32
+    cpu_speed: u32,
+
+    /// M-cycles the rest of the system already ran during
+    /// the current instruction, one per memory access
+    cycle_ticks: u32,
+
+    /// Normal speed ticks that went by during the current instruction
+    cycle_clock: u32,
+
+    /// Execution tracer, logs every instruction when set
+    tracer: Option<Tracer>,
+
+    /// Breakpoints, watchpoints and stepping, see `run_frame`
+    debugger: Debugger,
 }
 
 impl Z80 {
-    pub fn new(rom_file: &str) -> Z80 {
+    pub fn new(rom_file: &str, model: Model) -> Result<Z80, SafeboyError> {
+        Ok(Z80::with_mmu(MMU::new(rom_file, model)?))
+    }
+
+    /// Creates the CPU from ROM data already in memory
+    pub fn from_rom(data: Vec<u8>, model: Model) -> Result<Z80, SafeboyError> {
+        Ok(Z80::with_mmu(MMU::from_rom(data, model)?))
+    }
+
+    /// Creates the CPU running a program from the entry point (0x0100)
+    ///
+    /// The rest of the ROM is zeros, a plain 32k cartridge for the
+    /// original GameBoy. Meant for tests and benchmarks
+    pub fn from_program(code: &[u8]) -> Z80 {
+        let mut data = vec![0; 0x8000];
+        data[0x100 .. 0x100 + code.len()].copy_from_slice(code);
+
+        Z80::from_rom(data, Model::DMG).expect("a blank ROM is always valid")
+    }
+
+    fn with_mmu(mut mmu: MMU) -> Z80 {
+        let registers = RegisterSet::post_boot(mmu.model, mmu.read_byte(0x014D));
+
         Z80 {
-            registers: RegisterSet::new(),
-            mmu: MMU::new(rom_file),
+            registers,
+            mmu,
             halted: false,
+            halt_bug: false,
+            stopped: false,
+            fault: None,
             interrupt_master_enable: true,
-            set_enable_interrupts: 0,
-            set_disable_interrupts: 0,
+            interrupt_master_enable_scheduled: false,
             clock: 0,
-            cpu_speed: ((CPU_SPEED / 1000) * 16) as u32
+            cpu_speed: ((CPU_SPEED / 1000) * 16) as u32,
+            cycle_ticks: 0,
+            cycle_clock: 0,
+            tracer: None,
+            debugger: Debugger::new(),
         }
     }
 
+    /// Runs the boot ROM before the game
+    ///
+    /// The CPU starts from 0x0000 with power on registers, and the
+    /// boot ROM scrolls the logo and checks the header before
+    /// handing over to the cartridge
+    pub fn load_boot_rom(&mut self, boot_rom_file: &str) -> Result<(), SafeboyError> {
+        self.mmu.load_boot_rom(boot_rom_file)?;
+        self.registers = RegisterSet::power_on();
+        self.interrupt_master_enable = false;
+
+        Ok(())
+    }
+
     /// Steps the CPU
     ///
     /// Notice that the clock ticks are taken from observation
     /// from the original CPU, and these are approximate, at exact
     /// cycle time is tied to special hardware constraints that
     /// are not normally reproduced in emulators (hence not-100% accuracy)
-    pub fn step(&mut self) {
+    ///
+    /// If the CPU is locked up, the rest of the hardware keeps running
+    /// but the fault is returned, so the crash can be reported
+    ///
+    /// Breakpoints are ignored here, see `run_frame`
+    pub fn step(&mut self) -> Result<(), CpuFault> {
         while self.clock < self.cpu_speed {
-            // cycle the CPU and obtain how much ticks
-            // the operation took (used to limit the FPS)
-            let op_clock = self.cycle();
-            self.clock += op_clock;
-
-            // steps the MMU, this will turn also steps in
-            // GPU, keypad, timer, etc.
-            self.mmu.step(op_clock);
+            // cycle the CPU, which also steps the MMU (and
+            // with it the GPU, keypad, timer, etc.). In double
+            // speed the CPU needs twice the ticks for the same frame
+            self.clock += self.cycle();
         }
 
         // retract the clock by the same CPU
         // speed value, in order to keep cycling
-        self.clock -= self.cpu_speed
+        self.clock -= self.cpu_speed;
+
+        match self.fault {
+            Some(fault) => Err(fault),
+            None => Ok(()),
+        }
     }
 
-    fn cycle(&mut self) -> u32 {
-        self.update_interrupt_master_enable();
+    /// Runs the rest of the frame under the debugger
+    ///
+    /// Like `step`, but it stops on breakpoints, watchpoints, faults
+    /// and when the goal of the debugger is reached. The first
+    /// instruction doesn't check breakpoints, so running again
+    /// continues from a breakpoint. The frame is resumed where it
+    /// was stopped, and the goal is kept across frame ends
+    pub fn run_frame(&mut self) -> StopReason {
+        let mut first = true;
+
+        loop {
+            if !first {
+                if let Some(id) = self.breakpoint_hit() {
+                    self.debugger.goal = None;
+                    return StopReason::Breakpoint(id)
+                }
 
-        match self.interrupt() {
-            0 => {
-                0
-            },
-            n => {
-                n
-            },
-        };
+                if self.goal_reached() {
+                    self.debugger.goal = None;
+                    return StopReason::Step
+                }
+            }
 
-        if !self.halted {
-            let opcode = self.read_byte();
-            return self.execute(opcode);
-        }
+            first = false;
 
-        1
-    }
+            let faulted = self.fault.is_some();
+            let stack_pointer = self.registers.stack_pointer;
 
-    fn update_interrupt_master_enable(&mut self) {
-        self.set_disable_interrupts = match self.set_disable_interrupts {
-            2 => 1,
-            1 => {
-                self.interrupt_master_enable = false;
-                0
-            },
-            _ => 0,
-        };
+            // the opcode is only needed to step out
+            let opcode = match self.debugger.goal {
+                Some(Goal::StepOut(_)) => self.mmu.read_byte(self.registers.program_counter),
+                _ => 0x00,
+            };
 
-        self.set_enable_interrupts = match self.set_enable_interrupts {
-            2 => 1,
-            1 => {
-                self.interrupt_master_enable = true;
-                0
-            },
-            _ => 0,
-        };
-    }
+            self.debugger.take_hit();
+            self.clock += self.cycle();
 
-    /// Handles interrupts
-    ///
-    /// This function checks for various interrupt sources (including MMU)
-    /// in order to determine whether an interrupt ocurred.
-    fn interrupt(&mut self) -> u32 {
-        if self.interrupt_master_enable == false && self.halted == false {
-            return 0
-        }
+            if let Some(hit) = self.debugger.take_hit() {
+                self.debugger.goal = None;
+                return hit
+            }
 
-        let triggered = self.mmu.interrupt_enable & self.mmu.interrupt_flag;
+            if let (false, Some(fault)) = (faulted, self.fault) {
+                self.debugger.goal = None;
+                return StopReason::Fault(fault)
+            }
 
-        if triggered == 0 {
-            return 0
-        }
+            // step out stops right after the return, as the
+            // instruction it returns to could be a breakpoint. A
+            // conditional return that is not taken doesn't count
+            if let Some(Goal::StepOut(level)) = self.debugger.goal {
+                let returned = [0xC0, 0xC8, 0xC9, 0xD0, 0xD8, 0xD9].contains(&opcode);
 
-        self.halted = false;
-        self.interrupt_master_enable = false;
+                if returned && self.registers.stack_pointer > level && self.registers.stack_pointer > stack_pointer {
+                    self.debugger.goal = None;
+                    return StopReason::Step
+                }
+            }
 
-        // this stands for the interrupt beign triggered:
-        // 0 -> VBlank
-        // 1 -> GPU STAT
-        // 2 -> Timer
-        // 3 -> Serial Port
-        // 4 -> Keypad
-        let interrupt_number = triggered.trailing_zeros();
+            if let Some(Goal::Step) = self.debugger.goal {
+                self.debugger.goal = None;
+                return StopReason::Step
+            }
 
-        if interrupt_number >= 5 {
-            panic!("Invalid interrupt triggered");
+            if self.clock >= self.cpu_speed {
+                self.clock -= self.cpu_speed;
+                return StopReason::FrameEnd
+            }
         }
+    }
 
-        self.mmu.interrupt_flag &= !(1 << interrupt_number);
-
-        // get current program counter
-        let program_counter = self.registers.program_counter;
+    /// Executes a single instruction
+    pub fn step_instruction(&mut self) -> StopReason {
+        self.debugger.goal = Some(Goal::Step);
+        self.run_frame()
+    }
 
-        // push stack of the current program counter
-        self.push_stack(program_counter);
+    /// Executes an instruction, running calls (and restarts) until
+    /// they return, as if they were a single instruction
+    pub fn step_over(&mut self) -> StopReason {
+        let instruction = self.disassemble(self.registers.program_counter);
 
-        // point program counter to where the interrupt is handled
-        // this is then fetch by the CPU and the opcode executed
-        self.registers.program_counter = 0x0040 | ((interrupt_number as u16) << 3);
+        self.debugger.goal = Some(match instruction.mnemonic {
+            "CALL" | "RST" => Goal::Return(instruction.next_address(), self.registers.stack_pointer),
+            _ => Goal::Step,
+        });
 
-        // this operation takes 4 cycles
-        return 4
+        self.run_frame()
     }
 
-    fn push_stack(&mut self, value: u16) {
-        self.registers.stack_pointer -= 2;
+    /// Runs until the current function returns
+    pub fn step_out(&mut self) -> StopReason {
+        self.debugger.goal = Some(Goal::StepOut(self.registers.stack_pointer));
+        self.run_frame()
+    }
 
-        self.mmu.write_word(
-            self.registers.stack_pointer,
-            value
-        );
+    /// Runs until the program counter gets to an address, in any
+    /// bank or only when the given ROM bank is mapped
+    pub fn run_to(&mut self, bank: Option<usize>, address: u16) -> StopReason {
+        self.debugger.goal = Some(Goal::ProgramCounter(bank, address));
+        self.run_frame()
     }
 
-    fn pop_stack(&mut self) -> u16 {
-        let res = self.mmu.read_word(self.registers.stack_pointer);
-        self.registers.stack_pointer += 2;
-        res
+    /// Breakpoints and watchpoints
+    pub fn debugger(&mut self) -> &mut Debugger {
+        &mut self.debugger
     }
 
-    fn read_byte(&mut self) -> u8 {
-        let b = self.mmu.read_byte(self.registers.program_counter);
-        self.registers.program_counter
-        += 1;
-        b
+    pub fn registers(&self) -> &RegisterSet {
+        &self.registers
     }
 
-    fn read_word(&mut self) -> u16 {
-        let w = self.mmu.read_word(self.registers.program_counter);
-        self.registers.program_counter += 2;
-        w
+    pub fn registers_mut(&mut self) -> &mut RegisterSet {
+        &mut self.registers
     }
 
-    /// Executes an opcode
-    ///
-    /// This is where instructions sent by the game are handled.
-    ///
-    /// The Z80 contains 256 operations plus 256 CB-prefixed (see below)
-    fn execute(&mut self, opcode: u8) -> u32 {
-        let oldregs = self.registers;
+    /// Whether interrupts are enabled (IME)
+    pub fn interrupts_enabled(&self) -> bool {
+        self.interrupt_master_enable
+    }
 
-        match opcode {
-            0x00 => {
-                1
-            },
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
 
-            0x01 => {
-                let v = self.read_word();
-                self.registers.set_bc(v);
-                3
-            },
+    /// Reads memory, without taking any time or hitting watchpoints
+    pub fn peek(&mut self, address: u16) -> u8 {
+        match address {
+            0xFF00 => self.mmu.keypad.peek(),
+            _ => self.mmu.read_byte(address),
+        }
+    }
 
-            0x02 => {
-                self.mmu.write_byte(self.registers.bc(), self.registers.a);
-                2
-            },
+    /// Writes memory, without taking any time or hitting watchpoints
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.mmu.write_byte(address, value);
+    }
 
-            0x03 => {
-                let v = self.registers.bc().wrapping_add(1);
-                self.registers.set_bc(v);
-                2
-            },
+    /// ROM bank mapped at an address
+    pub fn rom_bank(&self, address: u16) -> usize {
+        self.mmu.mbc.rom_bank(address)
+    }
 
-            0x04 => {
-                self.registers.b = self.alu_increase(oldregs.b);
-                1
-            },
+    /// Decodes the instruction at an address
+    pub fn disassemble(&mut self, address: u16) -> Instruction {
+        disasm::decode_memory(&mut self.mmu, address)
+    }
 
-            0x05 => {
-                self.registers.b = self.alu_decrease(oldregs.b);
-                1
-            },
-            
-            0x06 => {
-                self.registers.b = self.read_byte();
-                2
-            },
-            
-            0x07 => {
-                self.registers.a = self.alu_rlc(oldregs.a);
-                self.registers.flag(Z, false);
-                1
-            },
+    fn breakpoint_hit(&mut self) -> Option<usize> {
+        if self.debugger.breakpoints().is_empty() {
+            return None
+        }
 
-            0x08 => {
-                let a = self.read_word();
-                self.mmu.write_word(a, self.registers.stack_pointer);
-                5
-            },
+        let bank = self.mmu.mbc.rom_bank(self.registers.program_counter);
+        let mmu = &mut self.mmu;
 
-            0x09 => {
-                let v = self.registers.bc();
-                self.alu_add16(v);
-                2
-            },
+        self.debugger.breakpoint_at(bank, &self.registers, |address| mmu.read_byte(address))
+    }
 
-            0x0A => {
-                self.registers.a = self.mmu.read_byte(self.registers.bc());
-                2
-            },
+    fn goal_reached(&self) -> bool {
+        let program_counter = self.registers.program_counter;
 
-            0x0B => {
-                let v = self.registers.bc().wrapping_sub(1);
-                self.registers.set_bc(v);
-                2
+        match self.debugger.goal {
+            Some(Goal::Return(address, level)) => {
+                program_counter == address && self.registers.stack_pointer >= level
             },
 
-            0x0C => {
-                self.registers.c = self.alu_increase(oldregs.c);
-                1
+            Some(Goal::ProgramCounter(bank, address)) => {
+                program_counter == address &&
+                    bank.is_none_or(|b| b == self.mmu.mbc.rom_bank(program_counter))
             },
 
-            0x0D => {
-                self.registers.c = self.alu_decrease(oldregs.c);
-                1
-            },
+            _ => false,
+        }
+    }
 
-            0x0E => {
-                self.registers.c = self.read_byte();
-                2
-            },
+    /// Traces every instruction executed from now on
+    pub fn trace(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
 
-            0x0F => {
-                self.registers.a = self.alu_rrc(oldregs.a);
-                self.registers.flag(Z, false);
-                1
-            },
+    /// CPU fault, if the CPU locked up
+    pub fn fault(&self) -> Option<&CpuFault> {
+        self.fault.as_ref()
+    }
 
-            0x11 => {
-                let v = self.read_word();
-                self.registers.set_de(v);
-                3
-            },
+    /// Runs one instruction (or interrupt dispatch)
+    ///
+    /// The system is ticked on every memory access, so it sees
+    /// the state changes in the middle of the instruction. The
+    /// cycles without memory accesses are ticked at the end
+    ///
+    /// Returns the normal speed ticks (T-cycles) that went by,
+    /// the same unit as `cpu_speed`
+    fn cycle(&mut self) -> u32 {
+        self.cycle_ticks = 0;
+        self.cycle_clock = 0;
 
-            0x12 => {
-                self.mmu.write_byte(self.registers.de(), self.registers.a);
-                2
-            },
+        let cycles = self.execute_next();
+        let remaining = cycles.saturating_sub(self.cycle_ticks) * TICKS_PER_CYCLE;
 
-            0x13 => {
-                let v = self.registers.de().wrapping_add(1);
-                self.registers.set_de(v);
-                2
-            },
+        // while stopped, nothing but the frame time moves
+        self.cycle_clock += if self.stopped {
+            remaining
+        } else {
+            self.mmu.step(remaining)
+        };
 
-            0x14 => {
-                self.registers.d = self.alu_increase(oldregs.d);
-                1
-            },
+        self.cycle_clock
+    }
 
-            0x15 => {
-                self.registers.d = self.alu_decrease(oldregs.d);
-                1
-            },
+    /// Ticks the rest of the system for one M-cycle
+    fn tick(&mut self) {
+        self.cycle_ticks += 1;
+        self.cycle_clock += self.mmu.step(TICKS_PER_CYCLE);
+    }
 
-            0x16 => {
-                self.registers.d = self.read_byte();
-                2
-            },
+    /// Reads memory, it takes one cycle
+    fn read_memory(&mut self, address: u16) -> u8 {
+        self.tick();
+        let value = self.mmu.read_byte(address);
 
-            0x17 => {
-                self.registers.a = self.alu_rl(oldregs.a);
-                self.registers.flag(Z, false);
-                1
-            },
+        if self.debugger.watching() {
+            self.debugger.check_access(address, value, Access::Read);
+        }
 
-            0x18 => {
-                self.cpu_jr();
-                3
-            },
+        value
+    }
 
-            0x19 => {
-                let v = self.registers.de();
-                self.alu_add16(v);
-                2
-            },
+    /// Writes memory, it takes one cycle
+    fn write_memory(&mut self, address: u16, value: u8) {
+        self.tick();
+        self.mmu.write_byte(address, value);
 
-            0x1A => {
-                self.registers.a = self.mmu.read_byte(self.registers.de());
-                2
-            },
+        if self.debugger.watching() {
+            self.debugger.check_access(address, value, Access::Write);
+        }
+    }
 
-            0x1B => {
-                let v = self.registers.de().wrapping_sub(1);
-                self.registers.set_de(v);
-                2
-            },
+    fn read_memory_word(&mut self, address: u16) -> u16 {
+        (self.read_memory(address) as u16) |
+            ((self.read_memory(address.wrapping_add(1)) as u16) << 8)
+    }
 
-            0x1C => {
-                self.registers.e = self.alu_increase(oldregs.e);
-                1
-            },
+    fn write_memory_word(&mut self, address: u16, value: u16) {
+        self.write_memory(address, (value & 0xFF) as u8);
+        self.write_memory(address.wrapping_add(1), (value >> 8) as u8);
+    }
 
-            0x1D => {
-                self.registers.e = self.alu_decrease(oldregs.e);
-                1
-            },
+    /// Fetches and executes the next instruction, returns
+    /// how many cycles it took
+    fn execute_next(&mut self) -> u32 {
+        // a locked up CPU doesn't even handle interrupts
+        if self.fault.is_some() {
+            return 1
+        }
 
-            0x1E => {
-                self.registers.e = self.read_byte();
-                2
-            },
+        if self.stopped {
+            if self.mmu.keypad.lines() == 0x0F {
+                return 1
+            }
 
-            0x1F => {
-                self.registers.a = self.alu_rr(oldregs.a);
-                self.registers.flag(Z, false);
-                1
-            },
+            self.stopped = false;
+        }
 
-            0x20 => {
-                if !self.registers.is_flag_set(Z) {
-                    self.cpu_jr();
-                    3
-                } else {
-                    self.registers.program_counter += 1;
-                    2
-                }
-            },
+        // an EI before this instruction enables interrupts after it
+        let enable_interrupts = self.interrupt_master_enable_scheduled;
 
-            0x21 => {
-                let v = self.read_word();
-                self.registers.set_hl(v);
-                3
-            },
+        // waking up and dispatching an interrupt
+        // take their own cycles, before the next opcode
+        let interrupt_ticks = self.interrupt();
 
-            0x22 => {
-                self.mmu.write_byte(
-                    self.registers.hl_increase(),
-                    self.registers.a
-                );
+        if interrupt_ticks > 0 {
+            return interrupt_ticks
+        }
 
-                2
-            },
+        if self.halted {
+            return 1
+        }
 
-            0x23 => {
-                let v = self.registers.hl().wrapping_add(1);
-                self.registers.set_hl(v);
-                2
-            },
+        if self.tracer.is_some() {
+            self.log_trace();
+        }
 
-            0x24 => {
-                self.registers.h = self.alu_increase(oldregs.h);
-                1
-            },
+        let opcode = self.read_byte();
 
-            0x25 => {
-                self.registers.h = self.alu_decrease(oldregs.h);
-                1
-            },
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.registers.program_counter = self.registers.program_counter.wrapping_sub(1);
+        }
 
-            0x26 => {
-                self.registers.h = self.read_byte();
-                2
-            },
+        let ticks = self.execute(opcode);
 
-            0x27 => {
-                self.alu_daa();
-                1
-            },
+        if enable_interrupts && self.interrupt_master_enable_scheduled {
+            self.interrupt_master_enable = true;
+            self.interrupt_master_enable_scheduled = false;
+        }
 
-            0x28 => {
-                if self.registers.is_flag_set(Z) {
-                    self.cpu_jr();
-                    3
-                } else {
-                    self.registers.program_counter += 1;
-                    2
-                }
-            },
+        ticks
+    }
 
-            0x29 => {
-                let v = self.registers.hl();
-                self.alu_add16(v);
-                2
-            },
+    /// Logs the instruction about to be executed
+    ///
+    /// The tracer is dropped once it's done, so it
+    /// doesn't slow down the rest of the run
+    fn log_trace(&mut self) {
+        let program_counter = self.registers.program_counter;
+        let bank = self.mmu.mbc.rom_bank(program_counter);
 
-            0x2A => {
-                self.registers.a = self.mmu.read_byte(self.registers.hl_increase());
-                2
-            },
+        let mut memory = [0; 4];
 
-            0x2B => {
-                let v = self.registers.hl().wrapping_sub(1);
-                self.registers.set_hl(v);
-                2
-            },
+        for (offset, byte) in memory.iter_mut().enumerate() {
+            *byte = self.mmu.read_byte(program_counter.wrapping_add(offset as u16));
+        }
 
-            0x2C => {
-                self.registers.l = self.alu_increase(oldregs.l);
-                1
+        let done = match self.tracer {
+            Some(ref mut tracer) => {
+                tracer.log(&self.registers, bank, memory);
+                tracer.done()
             },
+            None => false,
+        };
 
-            0x2D => {
-                self.registers.l = self.alu_decrease(oldregs.l);
-                1
-            },
+        if done {
+            self.tracer = None;
+        }
+    }
 
-            0x2E => {
-                self.registers.l = self.read_byte();
-                2
-            },
+    /// Whether any enabled interrupt is requested
+    fn interrupt_pending(&self) -> bool {
+        self.mmu.interrupt_enable & self.mmu.interrupt_flag & 0x1F != 0
+    }
 
-            0x2F => {
-                self.registers.a = !self.registers.a;
-                self.registers.flag(H, true);
-                self.registers.flag(N, true);
-                1
-            },
+    /// Handles interrupts
+    ///
+    /// This function checks for various interrupt sources (including MMU)
+    /// in order to determine whether an interrupt ocurred.
+    ///
+    /// Any pending interrupt wakes the CPU from HALT, which takes
+    /// one extra cycle. With IME disabled, the CPU just carries on
+    /// after the HALT without handling the interrupt
+    ///
+    /// The dispatch takes 5 cycles: two idle ones, pushing the program
+    /// counter (high byte, then low byte) and jumping to the handler
+    fn interrupt(&mut self) -> u32 {
+        // only the lower 5 bits are connected to interrupt sources
+        let triggered = self.mmu.interrupt_enable & self.mmu.interrupt_flag & 0x1F;
 
-            0x30 => {
-                if !self.registers.is_flag_set(C) {
-                    self.cpu_jr();
-                    3
-                } else {
-                    self.registers.program_counter += 1;
-                    2
-                }
-            },
+        if triggered == 0 {
+            return 0
+        }
 
-            0x31 => {
-                self.registers.stack_pointer = self.read_word();
-                3
-            },
+        let wake_up_ticks = if self.halted {
+            self.halted = false;
+            1
+        } else {
+            0
+        };
 
-            0x32 => {
-                self.mmu.write_byte(
-                    self.registers.hl_decrease(),
-                    self.registers.a
-                );
-                2
-            },
+        if !self.interrupt_master_enable {
+            return wake_up_ticks
+        }
 
-            0x33 => {
-                self.registers.stack_pointer = self.registers.stack_pointer.wrapping_add(1);
-                2
-            },
+        self.interrupt_master_enable = false;
 
-            0x34 => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a);
-                let v2 = self.alu_increase(v);
-                self.mmu.write_byte(a, v2);
-                3
-            },
+        let program_counter = self.registers.program_counter;
 
-            0x35 => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a);
-                let v2 = self.alu_decrease(v);
-                self.mmu.write_byte(a, v2);
-                3
-            },
+        for _ in 0 .. wake_up_ticks + 2 {
+            self.tick();
+        }
 
-            0x36 => {
-                let v = self.read_byte();
-                self.mmu.write_byte(self.registers.hl(), v);
-                3
-            },
+        // the high byte is pushed first, if SP was 0x0000
+        // it lands on IE, changing which interrupt is handled
+        self.registers.stack_pointer = self.registers.stack_pointer.wrapping_sub(1);
+        self.write_memory(self.registers.stack_pointer, (program_counter >> 8) as u8);
 
-            0x37 => {
-                self.registers.flag(C, true);
-                self.registers.flag(H, false);
-                self.registers.flag(N, false);
-                1
-            },
+        let triggered = self.mmu.interrupt_enable & self.mmu.interrupt_flag & 0x1F;
 
-            0x38 => {
-                if self.registers.is_flag_set(C) {
-                    self.cpu_jr();
-                    3
-                } else {
-                    self.registers.program_counter += 1;
-                    2
-                }
-            },
+        self.registers.stack_pointer = self.registers.stack_pointer.wrapping_sub(1);
+        self.write_memory(self.registers.stack_pointer, program_counter as u8);
 
-            0x39 => {
-                let v = self.registers.stack_pointer;
-                self.alu_add16(v);
-                2
-            },
+        // this stands for the interrupt beign triggered:
+        // 0 -> VBlank
+        // 1 -> GPU STAT
+        // 2 -> Timer
+        // 3 -> Serial Port
+        // 4 -> Keypad
+        //
+        // if no interrupt is left after the push,
+        // the dispatch is cancelled and jumps to 0x0000
+        self.registers.program_counter = if triggered == 0 {
+            0x0000
+        } else {
+            let interrupt_number = triggered.trailing_zeros();
+            self.mmu.interrupt_flag &= !(1 << interrupt_number);
 
-            0x3A => {
-                self.registers.a = self.mmu.read_byte(self.registers.hl_decrease());
-                2
-            },
+            // point program counter to where the interrupt is handled
+            // this is then fetch by the CPU and the opcode executed
+            0x0040 | ((interrupt_number as u16) << 3)
+        };
 
-            0x3B => {
-                self.registers.stack_pointer = self.registers.stack_pointer.wrapping_sub(1);
-                2
-            },
+        wake_up_ticks + 5
+    }
 
-            0x3C => {
-                self.registers.a = self.alu_increase(oldregs.a);
-                1
-            },
-
-            0x3D => {
-                self.registers.a = self.alu_decrease(oldregs.a);
-                1
-            },
-
-            0x3E => {
-                self.registers.a = self.read_byte();
-                2
-            },
-
-            0x3F => {
-                let v = !self.registers.is_flag_set(C);
-                self.registers.flag(C, v);
-                self.registers.flag(H, false);
-                self.registers.flag(N, false);
-                1
-            },
-
-            0x40 => {
-                1
-            },
-
-            0x41 => {
-                self.registers.b = self.registers.c;
-                1
-            },
-
-            0x42 => {
-                self.registers.b = self.registers.d;
-                1
-            },
-
-            0x43 => {
-                self.registers.b = self.registers.e;
-                1
-            },
-
-
-            0x44 => {
-                self.registers.b = self.registers.h;
-                1
-            },
-
-
-            0x45 => {
-                self.registers.b = self.registers.l;
-                1
-            },
-
-
-            0x46 => {
-                self.registers.b = self.mmu.read_byte(self.registers.hl());
-                2
-            },
-
-
-            0x47 => {
-                self.registers.b = self.registers.a;
-                1
-            },
-
-
-            0x48 => {
-                self.registers.c = self.registers.b;
-                1
-            },
-
-
-            0x49 => {
-                1
-            },
-
-
-            0x4A => {
-                self.registers.c = self.registers.d;
-                1
-            },
-
-
-            0x4B => {
-                self.registers.c = self.registers.e;
-                1
-            },
-
-
-            0x4C => {
-                self.registers.c = self.registers.h;
-                1
-            },
-
-
-            0x4D => {
-                self.registers.c = self.registers.l;
-                1
-            },
-
-
-            0x4E => {
-                self.registers.c = self.mmu.read_byte(self.registers.hl());
-                2
-            },
-
-
-            0x4F => {
-                self.registers.c = self.registers.a;
-                1
-            },
-
-
-            0x50 => {
-                self.registers.d = self.registers.b;
-                1
-            },
-
-
-            0x51 => {
-                self.registers.d = self.registers.c;
-                1
-            },
-
-
-            0x52 => {
-                1
-            },
-
-
-            0x53 => {
-                self.registers.d = self.registers.e;
-                1
-            },
-
-
-            0x54 => {
-                self.registers.d = self.registers.h;
-                1
-            },
-
-
-            0x55 => {
-                self.registers.d = self.registers.l;
-                1
-            },
-
-
-            0x56 => {
-                self.registers.d = self.mmu.read_byte(self.registers.hl());
-                2
-            },
-
-
-            0x57 => {
-                self.registers.d = self.registers.a;
-                1
-            },
-
-
-            0x58 => {
-                self.registers.e = self.registers.b;
-                1
-            },
-
-
-            0x59 => {
-                self.registers.e = self.registers.c;
-                1
-            },
-
-
-            0x5A => {
-                self.registers.e = self.registers.d;
-                1
-            },
-
-
-            0x5B => {
-                1
-            },
-
-
-            0x5C => {
-                self.registers.e = self.registers.h;
-                1
-            },
-
-
-            0x5D => {
-                self.registers.e = self.registers.l;
-                1
-            },
-
-
-            0x5E => {
-                self.registers.e = self.mmu.read_byte(self.registers.hl());
-                2
-            },
-
-
-            0x5F => {
-                self.registers.e = self.registers.a;
-                1
-            },
-
-
-            0x60 => {
-                self.registers.h = self.registers.b;
-                1
-            },
-
-
-            0x61 => {
-                self.registers.h = self.registers.c;
-                1
-            },
-
-
-            0x62 => {
-                self.registers.h = self.registers.d;
-                1
-            },
-
-
-            0x63 => {
-                self.registers.h = self.registers.e;
-                1
-            },
-
-
-            0x64 => {
-                1
-            },
-
-
-            0x65 => {
-                self.registers.h = self.registers.l;
-                1
-            },
-
-
-            0x66 => {
-                self.registers.h = self.mmu.read_byte(self.registers.hl());
-                2
-            },
-
-
-            0x67 => {
-                self.registers.h = self.registers.a;
-                1
-            },
-
-
-            0x68 => {
-                self.registers.l = self.registers.b;
-                1
-            },
-
-
-            0x69 => {
-                self.registers.l = self.registers.c;
-                1
-            },
-
-
-            0x6A => {
-                self.registers.l = self.registers.d;
-                1
-            },
-
-
-            0x6B => {
-                self.registers.l = self.registers.e;
-                1
-            },
-
-
-            0x6C => {
-                self.registers.l = self.registers.h;
-                1
-            },
-
-
-            0x6D => {
-                1
-            },
-
-
-            0x6E => {
-                self.registers.l = self.mmu.read_byte(self.registers.hl());
-                2
-            },
-
-
-            0x6F => {
-                self.registers.l = self.registers.a;
-                1
-            },
-
-
-            0x70 => {
-                self.mmu.write_byte(self.registers.hl(), self.registers.b);
-                2
-            },
-
-
-            0x71 => {
-                self.mmu.write_byte(self.registers.hl(), self.registers.c);
-                2
-            },
-
-
-            0x72 => {
-                self.mmu.write_byte(self.registers.hl(), self.registers.d);
-                2
-            },
-
-            0x73 => {
-                self.mmu.write_byte(self.registers.hl(), self.registers.e);
-                2
-            },
-
-            0x74 => {
-                self.mmu.write_byte(self.registers.hl(), self.registers.h);
-                2
-            },
-
-            0x75 => {
-                self.mmu.write_byte(self.registers.hl(), self.registers.l);
-                2
-            },
-
-            0x76 => {
-                self.halted = true;
-                1
-            },
-
-            0x77 => {
-                self.mmu.write_byte(self.registers.hl(), self.registers.a);
-                2
-            },
-
-            0x78 => {
-                self.registers.a = self.registers.b;
-                1
-            },
-
-            0x79 => {
-                self.registers.a = self.registers.c;
-                1
-            },
-
-            0x7A => {
-                self.registers.a = self.registers.d;
-                1
-            },
-
-            0x7B => {
-                self.registers.a = self.registers.e;
-                1
-            },
-
-            0x7C => {
-                self.registers.a = self.registers.h;
-                1
-            },
-
-            0x7D => {
-                self.registers.a = self.registers.l;
-                1
-            },
-
-            0x7E => {
-                self.registers.a = self.mmu.read_byte(self.registers.hl());
-                2
-            },
-
-            0x7F => {
-                1
-            },
-
-            0x80 => {
-                self.alu_add(oldregs.b, false);
-                1
-            },
-
-            0x81 => {
-                self.alu_add(oldregs.c, false);
-                1
-            },
-
-            0x82 => {
-                self.alu_add(oldregs.d, false);
-                1
-            },
-
-            0x83 => {
-                self.alu_add(oldregs.e, false);
-                1
-            },
-
-            0x84 => {
-                self.alu_add(oldregs.h, false);
-                1
-            },
-
-            0x85 => {
-                self.alu_add(oldregs.l, false);
-                1
-            },
-
-            0x86 => {
-                let v = self.mmu.read_byte(self.registers.hl());
-                self.alu_add(v, false);
-                2
-            },
-
-            0x87 => {
-                self.alu_add(oldregs.a, false);
-                1
-            },
-
-            0x88 => {
-                self.alu_add(oldregs.b, true);
-                1
-            },
-
-            0x89 => {
-                self.alu_add(oldregs.c, true);
-                1
-            },
-
-            0x8A => {
-                self.alu_add(oldregs.d, true);
-                1
-            },
-
-            0x8B => {
-                self.alu_add(oldregs.e, true);
-                1
-            },
-
-            0x8C => {
-                self.alu_add(oldregs.h, true);
-                1
-            },
-
-            0x8D => {
-                self.alu_add(oldregs.l, true);
-                1
-            },
-
-            0x8E => {
-                let v = self.mmu.read_byte(self.registers.hl());
-                self.alu_add(v, true);
-                2
-            },
-
-            0x8F => {
-                self.alu_add(oldregs.a, true);
-                1
-            },
-
-            0x90 => {
-                self.alu_subtract(oldregs.b, false);
-                1
-            },
-
-            0x91 => {
-                self.alu_subtract(oldregs.c, false);
-                1
-            },
-
-            0x92 => {
-                self.alu_subtract(oldregs.d, false);
-                1
-            },
-
-            0x93 => {
-                self.alu_subtract(oldregs.e, false);
-                1
-            },
-
-            0x94 => {
-                self.alu_subtract(oldregs.h, false);
-                1
-            },
-
-            0x95 => {
-                self.alu_subtract(oldregs.l, false);
-                1
-            },
-
-            0x96 => {
-                let v = self.mmu.read_byte(self.registers.hl());
-                self.alu_subtract(v, false);
-                2
-            },
-
-            0x97 => {
-                self.alu_subtract(oldregs.a, false);
-                1
-            },
-
-            0x98 => {
-                self.alu_subtract(oldregs.b, true);
-                1
-            },
-
-            0x99 => {
-                self.alu_subtract(oldregs.c, true);
-                1
-            },
-
-            0x9A => {
-                self.alu_subtract(oldregs.d, true);
-                1
-            },
-
-            0x9B => {
-                self.alu_subtract(oldregs.e, true);
-                1
-            },
-
-            0x9C => {
-                self.alu_subtract(oldregs.h, true);
-                1
-            },
-
-            0x9D => {
-                self.alu_subtract(oldregs.l, true);
-                1
-            },
-
-            0x9E => {
-                let v = self.mmu.read_byte(self.registers.hl());
-                self.alu_subtract(v, true);
-                2
-            },
-
-            0x9F => {
-                self.alu_subtract(oldregs.a, true);
-                1
-            },
-
-            0xA0 => {
-                self.alu_and(oldregs.b);
-                1
-            },
-
-            0xA1 => {
-                self.alu_and(oldregs.c);
-                1
-            },
-
-            0xA2 => {
-                self.alu_and(oldregs.d);
-                1
-            },
-
-            0xA3 => {
-                self.alu_and(oldregs.e);
-                1
-            },
-
-            0xA4 => {
-                self.alu_and(oldregs.h);
-                1
-            },
-
-            0xA5 => {
-                self.alu_and(oldregs.l);
-                1
-            },
-
-            0xA6 => {
-                let v = self.mmu.read_byte(self.registers.hl());
-                self.alu_and(v);
-                2
-            },
-
-            0xA7 => {
-                self.alu_and(oldregs.a);
-                1
-            },
-
-            0xA8 => {
-                self.alu_xor(oldregs.b);
-                1
-            },
-
-            0xA9 => {
-                self.alu_xor(oldregs.c);
-                1
-            },
-
-            0xAA => {
-                self.alu_xor(oldregs.d);
-                1
-            },
-
-            0xAB => {
-                self.alu_xor(oldregs.e);
-                1
-            },
-
-            0xAC => {
-                self.alu_xor(oldregs.h);
-                1
-            },
-
-            0xAD => {
-                self.alu_xor(oldregs.l);
-                1
-            },
-
-            0xAE => {
-                let v = self.mmu.read_byte(self.registers.hl());
-                self.alu_xor(v);
-                2
-            },
-
-            0xAF => {
-                self.alu_xor(oldregs.a);
-                1
-            },
-
-            0xB0 => {
-                self.alu_or(oldregs.b);
-                1
-            },
-
-            0xB1 => {
-                self.alu_or(oldregs.c);
-                1
-            },
-
-            0xB2 => {
-                self.alu_or(oldregs.d);
-                1
-            },
-
-            0xB3 => {
-                self.alu_or(oldregs.e);
-                1
-            },
-
-            0xB4 => {
-                self.alu_or(oldregs.h);
-                1
-            },
-
-            0xB5 => {
-                self.alu_or(oldregs.l);
-                1
-            },
-
-            0xB6 => {
-                let v = self.mmu.read_byte(self.registers.hl());
-                self.alu_or(v);
-                2
-            },
-
-            0xB7 => {
-                self.alu_or(oldregs.a);
-                1
-            },
-
-            0xB8 => {
-                self.alu_compare(oldregs.b);
-                1
-            },
-
-            0xB9 => {
-                self.alu_compare(oldregs.c);
-                1
-            },
-
-            0xBA => {
-                self.alu_compare(oldregs.d);
-                1
-            },
-
-            0xBB => {
-                self.alu_compare(oldregs.e);
-                1
-            },
-
-            0xBC => {
-                self.alu_compare(oldregs.h);
-                1
-            },
-
-            0xBD => {
-                self.alu_compare(oldregs.l);
-                1
-            },
-
-            0xBE => {
-                let v = self.mmu.read_byte(self.registers.hl());
-                self.alu_compare(v);
-                2
-            },
-
-            0xBF => {
-                self.alu_compare(oldregs.a);
-                1
-            },
-
-            0xC0 => {
-                if !self.registers.is_flag_set(Z) {
-                    self.registers.program_counter = self.pop_stack();
-                    5
-                } else {
-                    2
-                }
-            },
-
-            0xC1 => {
-                let v = self.pop_stack();
-                self.registers.set_bc(v);
-                3
-            },
-
-            0xC2 => {
-                if !self.registers.is_flag_set(Z) {
-                    self.registers.program_counter = self.read_word();
-                    4
-                } else {
-                    self.registers.program_counter += 2;
-                    3
-                }
-            },
-
-            0xC3 => {
-                self.registers.program_counter = self.read_word();
-                4
-            },
-
-            0xC4 => {
-                if !self.registers.is_flag_set(Z) {
-                    self.push_stack(oldregs.program_counter + 2);
-                    self.registers.program_counter = self.read_word();
-                    6
-                } else {
-                    self.registers.program_counter += 2;
-                    3
-                }
-            },
-
-            0xC5 => {
-                let v = self.registers.bc();
-                self.push_stack(v);
-                4
-            },
-
-            0xC6 => {
-                let v = self.read_byte();
-                self.alu_add(v, false);
-                2
-            },
-
-            0xC7 => {
-                self.push_stack(oldregs.program_counter);
-                self.registers.program_counter = 0x00;
-                4
-            },
-
-            0xC8 => {
-                if self.registers.is_flag_set(Z) {
-                    self.registers.program_counter = self.pop_stack();
-                    5
-                } else {
-                    2
-                }
-            },
-
-            0xC9 => {
-                self.registers.program_counter = self.pop_stack();
-                4
-            },
-
-            0xCA => {
-                if self.registers.is_flag_set(Z) {
-                    self.registers.program_counter = self.read_word();
-                    4
-                } else {
-                    self.registers.program_counter += 2;
-                    3
-                }
-            },
-
-            // CB-prefixed operations, call a different set
-            // of operations (see method for more info)
-            0xCB => {
-                self.execute_cb(oldregs)
-            },
-
-            0xCC => {
-                if self.registers.is_flag_set(Z) {
-                    self.push_stack(oldregs.program_counter + 2);
-                    self.registers.program_counter = self.read_word();
-                    6
-                } else {
-                    self.registers.program_counter += 2;
-                    3
-                }
-            },
-
-            // Commented example of stack work, not using
-            // code functions for clarity
-            0xCD => {
-                // decrease current stack pointer to the current function
-                self.registers.stack_pointer -= 2;
-
-                // write address of the current instruction forward
-                self.mmu.write_word(
-                    self.registers.stack_pointer,
-                    oldregs.program_counter + 2
-                );
-
-                // point the program counter to the current function
-                self.registers.program_counter = self.read_word();
-
-                6
-            },
-
-            0xCE => {
-                let v = self.read_byte();
-                self.alu_add(v, true);
-                2
-            },
-
-            0xCF => {
-                self.push_stack(oldregs.program_counter);
-                self.registers.program_counter = 0x08;
-                4
-            },
-
-            0xD0 => {
-                if !self.registers.is_flag_set(C) {
-                    self.registers.program_counter = self.pop_stack();
-                    5
-                } else {
-                    2
-                }
-            },
-
-            0xD1 => {
-                let v = self.pop_stack();
-                self.registers.set_de(v);
-                3
-            },
-
-            0xD2 => {
-                if !self.registers.is_flag_set(C) {
-                    self.registers.program_counter = self.read_word();
-                    4
-                } else {
-                    self.registers.program_counter += 2;
-                    3
-                }
-            },
-
-            0xD4 => {
-                if !self.registers.is_flag_set(C) {
-                    self.push_stack(oldregs.program_counter + 2);
-                    self.registers.program_counter = self.read_word();
-                    6
-                } else {
-                    self.registers.program_counter += 2;
-                    3
-                }
-            },
-
-            0xD5 => {
-                let v = self.registers.de();
-                self.push_stack(v);
-                4
-            },
-
-            0xD6 => {
-                let v = self.read_byte();
-                self.alu_subtract(v, false);
-                2
-            },
-
-            0xD7 => {
-                self.push_stack(oldregs.program_counter);
-                self.registers.program_counter = 0x10;
-                4
-            },
-
-            0xD8 => {
-                if self.registers.is_flag_set(C) {
-                    self.registers.program_counter = self.pop_stack();
-                    5
-                } else {
-                    2
-                }
-            },
-
-            0xD9 => {
-                self.registers.program_counter = self.pop_stack();
-                self.set_enable_interrupts = 1;
-                4
-            },
-
-            0xDA => {
-                if self.registers.is_flag_set(C) {
-                    self.registers.program_counter = self.read_word();
-                    4
-                } else {
-                    self.registers.program_counter += 2;
-                    3
-                }
-            },
-
-            0xDC => {
-                if self.registers.is_flag_set(C) {
-                    self.push_stack(oldregs.program_counter + 2);
-                    self.registers.program_counter = self.read_word();
-                    6
-                } else {
-                    self.registers.program_counter += 2;
-                    3
-                }
-            },
-
-            0xDE => {
-                let v = self.read_byte();
-                self.alu_subtract(v, true);
-                2
-            },
-
-            0xDF => {
-                self.push_stack(oldregs.program_counter);
-                self.registers.program_counter = 0x18;
-                4
-            },
-
-            0xE0 => {
-                let a = 0xFF00 | self.read_byte() as u16;
-                self.mmu.write_byte(a, self.registers.a);
-                3
-            },
-
-            0xE1 => {
-                let v = self.pop_stack();
-                self.registers.set_hl(v);
-                3
-            },
-
-            0xE2 => {
-                self.mmu.write_byte(0xFF00 | self.registers.c as u16, self.registers.a);
-                2
-            },
-
-            0xE5 => {
-                let v = self.registers.hl();
-                self.push_stack(v);
-                4
-            },
-
-            0xE6 => {
-                let v = self.read_byte();
-                self.alu_and(v);
-                2
-            },
-
-            0xE7 => {
-                self.push_stack(oldregs.program_counter);
-                self.registers.program_counter = 0x20;
-                4
-            },
-
-            0xE8 => {
-                self.registers.stack_pointer = self.alu_add16imm(oldregs.stack_pointer);
-                4
-            },
-
-            0xE9 => {
-                self.registers.program_counter = self.registers.hl();
-                1
-            },
-
-            0xEA => {
-                let a = self.read_word();
-                self.mmu.write_byte(a, self.registers.a);
-                4
-            },
-
-            0xEE => {
-                let v = self.read_byte();
-                self.alu_xor(v);
-                2
-            },
-
-            0xEF => {
-                self.push_stack(oldregs.program_counter);
-                self.registers.program_counter = 0x28;
-                4
-            },
-
-            0xF0 => {
-                let a = 0xFF00 | self.read_byte() as u16;
-                self.registers.a = self.mmu.read_byte(a);
-                3
-            },
-
-            0xF1 => {
-                let v = self.pop_stack() & 0xFFF0;
-                self.registers.set_af(v);
-                3
-            },
-
-            0xF2 => {
-                self.registers.a = self.mmu.read_byte(0xFF00 | self.registers.c as u16);
-                2
-            },
-
-            0xF3 => {
-                self.set_disable_interrupts = 2;
-                1
-            },
-
-            0xF5 => {
-                let v = self.registers.af();
-                self.push_stack(v);
-                4
-            },
-
-            0xF6 => {
-                let v = self.read_byte();
-                self.alu_or(v);
-                2
-            },
-
-            0xF7 => {
-                self.push_stack(oldregs.program_counter);
-                self.registers.program_counter = 0x30;
-                4
-            },
-
-
-            0xF8 => {
-                let r = self.alu_add16imm(oldregs.stack_pointer);
-                self.registers.set_hl(r);
-                3
-            },
-
-
-            0xF9 => {
-                self.registers.stack_pointer = self.registers.hl();
-                2
-            },
-
-
-            0xFA => {
-                let a = self.read_word();
-                self.registers.a = self.mmu.read_byte(a);
-                4
-            },
-
-
-            0xFB => {
-                self.set_enable_interrupts = 2;
-                1
-            },
-
-
-            0xFE => {
-                let v = self.read_byte();
-                self.alu_compare(v);
-                2
-            },
-
-
-            0xFF => {
-                self.push_stack(oldregs.program_counter);
-                self.registers.program_counter = 0x38;
-                4
-            },
-
-            other => panic!("CPU instruction not implemented: {:2X}", other),
-        }
-    }
-
-    /// Execute CB-prefixed operations
-    ///
-    /// When an operation is CB-prefixed (CB is hex), these special
-    /// operations are called. This is simply to allow the Z80 to handle
-    /// a bigger number of operations
-    fn execute_cb(&mut self, oldregs: RegisterSet) -> u32 {
-        let opcode = self.read_byte();
-
-        match opcode {
-            // RLC B
-            0x00 => {
-                self.registers.b = self.alu_rlc(oldregs.b);
-                2
-            },
-
-            // RLC C
-            0x01 => {
-                self.registers.c = self.alu_rlc(oldregs.c);
-                2
-            },
-
-            // RLC D
-            0x02 => {
-                self.registers.d = self.alu_rlc(oldregs.d);
-                2
-            },
-
-            0x03 => {
-                self.registers.e = self.alu_rlc(oldregs.e);
-                2
-            },
-
-            0x04 => {
-                self.registers.h = self.alu_rlc(oldregs.h);
-                2
-            },
-
-            0x05 => {
-                self.registers.l = self.alu_rlc(oldregs.l);
-                2
-            },
-
-            0x06 => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a);
-                let v2 = self.alu_rlc(v);
-                self.mmu.write_byte(a, v2);
-                4
-            },
-
-            0x07 => {
-                self.registers.a = self.alu_rlc(oldregs.a);
-                2
-            },
-
-            0x08 => {
-                self.registers.b = self.alu_rrc(oldregs.b);
-                2
-            },
-
-            0x09 => {
-                self.registers.c = self.alu_rrc(oldregs.c);
-                2
-            },
-
-            0x0A => {
-                self.registers.d = self.alu_rrc(oldregs.d);
-                2
-            },
-
-            0x0B => {
-                self.registers.e = self.alu_rrc(oldregs.e);
-                2
-            },
-
-            0x0C => {
-                self.registers.h = self.alu_rrc(oldregs.h);
-                2
-            },
-
-            0x0D => {
-                self.registers.l = self.alu_rrc(oldregs.l);
-                2
-            },
-
-            0x0E => {
-                let a = self.registers.hl(); let v = self.mmu.read_byte(a); let v2 = self.alu_rrc(v); self.mmu.write_byte(a, v2);
-                4
-            },
-
-            0x0F => {
-                self.registers.a = self.alu_rrc(oldregs.a);
-                2
-            },
-
-            0x10 => {
-                self.registers.b = self.alu_rl(oldregs.b);
-                2
-            },
-
-            0x11 => {
-                self.registers.c = self.alu_rl(oldregs.c);
-                2
-            },
-
-            0x12 => {
-                self.registers.d = self.alu_rl(oldregs.d);
-                2
-            },
-
-            0x13 => {
-                self.registers.e = self.alu_rl(oldregs.e);
-                2
-            },
-
-            0x14 => {
-                self.registers.h = self.alu_rl(oldregs.h);
-                2
-            },
-
-            0x15 => {
-                self.registers.l = self.alu_rl(oldregs.l);
-                2
-            },
-
-            0x16 => {
-                let a = self.registers.hl(); let v = self.mmu.read_byte(a); let v2 = self.alu_rl(v); self.mmu.write_byte(a, v2);
-                4
-            },
-
-            0x17 => {
-                self.registers.a = self.alu_rl(oldregs.a);
-                2
-            },
-
-            0x18 => {
-                self.registers.b = self.alu_rr(oldregs.b);
-                2
-            },
-
-            0x19 => {
-                self.registers.c = self.alu_rr(oldregs.c);
-                2
-            },
-
-            0x1A => {
-                self.registers.d = self.alu_rr(oldregs.d);
-                2
-            },
-
-            0x1B => {
-                self.registers.e = self.alu_rr(oldregs.e);
-                2
-            },
-
-            0x1C => {
-                self.registers.h = self.alu_rr(oldregs.h);
-                2
-            },
-
-            0x1D => {
-                self.registers.l = self.alu_rr(oldregs.l);
-                2
-            },
-
-            0x1E => {
-                let a = self.registers.hl(); let v = self.mmu.read_byte(a); let v2 = self.alu_rr(v); self.mmu.write_byte(a, v2);
-                4
-            },
-
-            0x1F => {
-                self.registers.a = self.alu_rr(oldregs.a);
-                2
-            },
-
-            0x20 => {
-                self.registers.b = self.alu_sla(oldregs.b);
-                2
-            },
-
-            0x21 => {
-                self.registers.c = self.alu_sla(oldregs.c);
-                2
-            },
-
-            0x22 => {
-                self.registers.d = self.alu_sla(oldregs.d);
-                2
-            },
-
-            0x23 => {
-                self.registers.e = self.alu_sla(oldregs.e);
-                2
-            },
-
-            0x24 => {
-                self.registers.h = self.alu_sla(oldregs.h);
-                2
-            },
-
-            0x25 => {
-                self.registers.l = self.alu_sla(oldregs.l);
-                2
-            },
-
-            0x26 => {
-                let a = self.registers.hl(); let v = self.mmu.read_byte(a); let v2 = self.alu_sla(v); self.mmu.write_byte(a, v2);
-                4
-            },
-
-            0x27 => {
-                self.registers.a = self.alu_sla(oldregs.a);
-                2
-            },
-
-            0x28 => {
-                self.registers.b = self.alu_sra(oldregs.b);
-                2
-            },
-
-            0x29 => {
-                self.registers.c = self.alu_sra(oldregs.c);
-                2
-            },
-
-            0x2A => {
-                self.registers.d = self.alu_sra(oldregs.d);
-                2
-            },
-
-            0x2B => {
-                self.registers.e = self.alu_sra(oldregs.e);
-                2
-            },
-
-            0x2C => {
-                self.registers.h = self.alu_sra(oldregs.h);
-                2
-            },
-
-            0x2D => {
-                self.registers.l = self.alu_sra(oldregs.l);
-                2
-            },
-
-            0x2E => {
-                let a = self.registers.hl(); let v = self.mmu.read_byte(a); let v2 = self.alu_sra(v); self.mmu.write_byte(a, v2);
-                4
-            },
-
-            0x2F => {
-                self.registers.a = self.alu_sra(oldregs.a);
-                2
-            },
-
-            0x30 => {
-                self.registers.b = self.alu_swap(oldregs.b);
-                2
-            },
-
-            0x31 => {
-                self.registers.c = self.alu_swap(oldregs.c);
-                2
-            },
-
-            0x32 => {
-                self.registers.d = self.alu_swap(oldregs.d);
-                2
-            },
-
-            0x33 => {
-                self.registers.e = self.alu_swap(oldregs.e);
-                2
-            },
-
-            0x34 => {
-                self.registers.h = self.alu_swap(oldregs.h);
-                2
-            },
-
-            0x35 => {
-                self.registers.l = self.alu_swap(oldregs.l);
-                2
-            },
-
-            0x36 => {
-                let a = self.registers.hl(); let v = self.mmu.read_byte(a); let v2 = self.alu_swap(v); self.mmu.write_byte(a, v2);
-                4
-            },
-
-            0x37 => {
-                self.registers.a = self.alu_swap(oldregs.a);
-                2
-            },
-
-            0x38 => {
-                self.registers.b = self.alu_srl(oldregs.b);
-                2
-            },
-
-            0x39 => {
-                self.registers.c = self.alu_srl(oldregs.c);
-                2
-            },
-
-            0x3A => {
-                self.registers.d = self.alu_srl(oldregs.d);
-                2
-            },
-
-            0x3B => {
-                self.registers.e = self.alu_srl(oldregs.e);
-                2
-            },
-
-            0x3C => {
-                self.registers.h = self.alu_srl(oldregs.h);
-                2
-            },
-
-            0x3D => {
-                self.registers.l = self.alu_srl(oldregs.l);
-                2
-            },
-
-            0x3E => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a);
-                let v2 = self.alu_srl(v);
-                self.mmu.write_byte(a, v2);
-
-                4
-            },
-
-            0x3F => {
-                self.registers.a = self.alu_srl(oldregs.a);
-                2
-            },
-
-            0x40 => {
-                self.alu_bit(oldregs.b, 0);
-                2
-            },
-
-            0x41 => {
-                self.alu_bit(oldregs.c, 0);
-                2
-            },
-
-            0x42 => {
-                self.alu_bit(oldregs.d, 0);
-                2
-            },
-
-            0x43 => {
-                self.alu_bit(oldregs.e, 0);
-                2
-            },
-
-            0x44 => {
-                self.alu_bit(oldregs.h, 0);
-                2
-            },
-
-            0x45 => {
-                self.alu_bit(oldregs.l, 0);
-                2
-            },
-
-            0x46 => {
-                let v = self.mmu.read_byte(self.registers.hl());
-                self.alu_bit(v, 0);
-                3
-            },
-
-            0x47 => {
-                self.alu_bit(oldregs.a, 0);
-                2
-            },
-
-            0x48 => {
-                self.alu_bit(oldregs.b, 1);
-                2
-            },
-
-            0x49 => {
-                self.alu_bit(oldregs.c, 1);
-                2
-            },
-
-            0x4A => {
-                self.alu_bit(oldregs.d, 1);
-                2
-            },
-
-            0x4B => {
-                self.alu_bit(oldregs.e, 1);
-                2
-            },
-
-            0x4C => {
-                self.alu_bit(oldregs.h, 1);
-                2
-            },
-
-            0x4D => {
-                self.alu_bit(oldregs.l, 1);
-                2
-            },
-
-            0x4E => {
-                let v = self.mmu.read_byte(self.registers.hl());
-                self.alu_bit(v, 1);
-                3
-            },
-
-            0x4F => {
-                self.alu_bit(oldregs.a, 1);
-                2
-            },
-
-            0x50 => {
-                self.alu_bit(oldregs.b, 2);
-                2
-            },
-
-            0x51 => {
-                self.alu_bit(oldregs.c, 2);
-                2
-            },
-
-            0x52 => {
-                self.alu_bit(oldregs.d, 2);
-                2
-            },
-
-            0x53 => {
-                self.alu_bit(oldregs.e, 2);
-                2
-            },
-
-            0x54 => {
-                self.alu_bit(oldregs.h, 2);
-                2
-            },
-
-            0x55 => {
-                self.alu_bit(oldregs.l, 2);
-                2
-            },
-
-            0x56 => {
-                let v = self.mmu.read_byte(self.registers.hl());
-                self.alu_bit(v, 2);
-
-                3
-            },
-
-            0x57 => {
-                self.alu_bit(oldregs.a, 2);
-                2
-            },
-
-            0x58 => {
-                self.alu_bit(oldregs.b, 3);
-                2
-            },
-
-            0x59 => {
-                self.alu_bit(oldregs.c, 3);
-                2
-            },
-
-            0x5A => {
-                self.alu_bit(oldregs.d, 3);
-                2
-            },
-
-            0x5B => {
-                self.alu_bit(oldregs.e, 3);
-                2
-            },
-
-            0x5C => {
-                self.alu_bit(oldregs.h, 3);
-                2
-            },
-
-            0x5D => {
-                self.alu_bit(oldregs.l, 3);
-                2
-            },
-
-            0x5E => {
-                let v = self.mmu.read_byte(self.registers.hl());
-                self.alu_bit(v, 3);
-
-                3
-            },
-
-            0x5F => {
-                self.alu_bit(oldregs.a, 3);
-                2
-            },
-
-            0x60 => {
-                self.alu_bit(oldregs.b, 4);
-                2
-            },
-
-            0x61 => {
-                self.alu_bit(oldregs.c, 4);
-                2
-            },
-
-            0x62 => {
-                self.alu_bit(oldregs.d, 4);
-                2
-            },
-
-            0x63 => {
-                self.alu_bit(oldregs.e, 4);
-                2
-            },
-
-            0x64 => {
-                self.alu_bit(oldregs.h, 4);
-                2
-            },
-
-            0x65 => {
-                self.alu_bit(oldregs.l, 4);
-                2
-            },
-
-            0x66 => {
-                let v = self.mmu.read_byte(self.registers.hl());
-                self.alu_bit(v, 4);
-
-                3
-            },
-
-            0x67 => {
-                self.alu_bit(oldregs.a, 4);
-                2
-            },
-
-            0x68 => {
-                self.alu_bit(oldregs.b, 5);
-                2
-            },
-
-            0x69 => {
-                self.alu_bit(oldregs.c, 5);
-                2
-            },
-
-            0x6A => {
-                self.alu_bit(oldregs.d, 5);
-                2
-            },
-
-            0x6B => {
-                self.alu_bit(oldregs.e, 5);
-                2
-            },
-
-            0x6C => {
-                self.alu_bit(oldregs.h, 5);
-                2
-            },
-
-            0x6D => {
-                self.alu_bit(oldregs.l, 5);
-                2
-            },
-
-            0x6E => {
-                let v = self.mmu.read_byte(self.registers.hl());
-                self.alu_bit(v, 5);
-
-                3
-            },
-
-            0x6F => {
-                self.alu_bit(oldregs.a, 5);
-                2
-            },
-
-            0x70 => {
-                self.alu_bit(oldregs.b, 6);
-                2
-            },
-
-            0x71 => {
-                self.alu_bit(oldregs.c, 6);
-                2
-            },
-
-            0x72 => {
-                self.alu_bit(oldregs.d, 6);
-                2
-            },
-
-            0x73 => {
-                self.alu_bit(oldregs.e, 6);
-                2
-            },
-
-            0x74 => {
-                self.alu_bit(oldregs.h, 6);
-                2
-            },
-
-            0x75 => {
-                self.alu_bit(oldregs.l, 6);
-                2
-            },
-
-            0x76 => {
-                let v = self.mmu.read_byte(self.registers.hl());
-                self.alu_bit(v, 6);
-
-                3
-            },
-
-            0x77 => {
-                self.alu_bit(oldregs.a, 6);
-                2
-            },
-
-            0x78 => {
-                self.alu_bit(oldregs.b, 7);
-                2
-            },
-
-            0x79 => {
-                self.alu_bit(oldregs.c, 7);
-                2
-            },
-
-            0x7A => {
-                self.alu_bit(oldregs.d, 7);
-                2
-            },
-
-            0x7B => {
-                self.alu_bit(oldregs.e, 7);
-                2
-            },
-
-            0x7C => {
-                self.alu_bit(oldregs.h, 7);
-                2
-            },
-
-            0x7D => {
-                self.alu_bit(oldregs.l, 7);
-                2
-            },
-
-            0x7E => {
-                let v = self.mmu.read_byte(self.registers.hl());
-                self.alu_bit(v, 7);
-
-                3
-            },
-
-            0x7F => {
-                self.alu_bit(oldregs.a, 7);
-                2
-            },
-
-            0x80 => {
-                self.registers.b = self.registers.b & !(1 << 0);
-                2
-            },
-
-            0x81 => {
-                self.registers.c = self.registers.c & !(1 << 0);
-                2
-            },
-
-            0x82 => {
-                self.registers.d = self.registers.d & !(1 << 0);
-                2
-            },
-
-            0x83 => {
-                self.registers.e = self.registers.e & !(1 << 0);
-                2
-            },
-
-            0x84 => {
-                self.registers.h = self.registers.h & !(1 << 0);
-                2
-            },
-
-            0x85 => {
-                self.registers.l = self.registers.l & !(1 << 0);
-                2
-            },
-
-            0x86 => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a) & !(1 << 0);
-                self.mmu.write_byte(a, v);
-
-                4
-            },
-
-            0x87 => {
-                self.registers.a = self.registers.a & !(1 << 0);
-                2
-            },
-
-            0x88 => {
-                self.registers.b = self.registers.b & !(1 << 1);
-                2
-            },
-
-            0x89 => {
-                self.registers.c = self.registers.c & !(1 << 1);
-                2
-            },
-
-            0x8A => {
-                self.registers.d = self.registers.d & !(1 << 1);
-                2
-            },
-
-            0x8B => {
-                self.registers.e = self.registers.e & !(1 << 1);
-                2
-            },
-
-            0x8C => {
-                self.registers.h = self.registers.h & !(1 << 1);
-                2
-            },
-
-            0x8D => {
-                self.registers.l = self.registers.l & !(1 << 1);
-                2
-            },
-
-            0x8E => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a) & !(1 << 1);
-                self.mmu.write_byte(a, v);
-
-                4
-            },
-
-            0x8F => {
-                self.registers.a = self.registers.a & !(1 << 1);
-                2
-            },
-
-            0x90 => {
-                self.registers.b = self.registers.b & !(1 << 2);
-                2
-            },
-
-            0x91 => {
-                self.registers.c = self.registers.c & !(1 << 2);
-                2
-            },
-
-            0x92 => {
-                self.registers.d = self.registers.d & !(1 << 2);
-                2
-            },
-
-            0x93 => {
-                self.registers.e = self.registers.e & !(1 << 2);
-                2
-            },
-
-            0x94 => {
-                self.registers.h = self.registers.h & !(1 << 2);
-                2
-            },
-
-            0x95 => {
-                self.registers.l = self.registers.l & !(1 << 2);
-                2
-            },
-
-            0x96 => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a) & !(1 << 2);
-                self.mmu.write_byte(a, v);
-
-                4
-            },
-
-            0x97 => {
-                self.registers.a = self.registers.a & !(1 << 2);
-                2
-            },
-
-            0x98 => {
-                self.registers.b = self.registers.b & !(1 << 3);
-                2
-            },
-
-            0x99 => {
-                self.registers.c = self.registers.c & !(1 << 3);
-                2
-            },
-
-            0x9A => {
-                self.registers.d = self.registers.d & !(1 << 3);
-                2
-            },
-
-            0x9B => {
-                self.registers.e = self.registers.e & !(1 << 3);
-                2
-            },
-
-            0x9C => {
-                self.registers.h = self.registers.h & !(1 << 3);
-                2
-            },
-
-            0x9D => {
-                self.registers.l = self.registers.l & !(1 << 3);
-                2
-            },
-
-            0x9E => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a) & !(1 << 3);
-                self.mmu.write_byte(a, v);
-
-                4
-            },
-
-            0x9F => {
-                self.registers.a = self.registers.a & !(1 << 3);
-                2
-            },
-
-            0xA0 => {
-                self.registers.b = self.registers.b & !(1 << 4);
-                2
-            },
-
-            0xA1 => {
-                self.registers.c = self.registers.c & !(1 << 4);
-                2
-            },
-
-            0xA2 => {
-                self.registers.d = self.registers.d & !(1 << 4);
-                2
-            },
-
-            0xA3 => {
-                self.registers.e = self.registers.e & !(1 << 4);
-                2
-            },
-
-            0xA4 => {
-                self.registers.h = self.registers.h & !(1 << 4);
-                2
-            },
-
-            0xA5 => {
-                self.registers.l = self.registers.l & !(1 << 4);
-                2
-            },
-
-            0xA6 => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a) & !(1 << 4);
-                self.mmu.write_byte(a, v);
-
-                4
-            },
-
-            0xA7 => {
-                self.registers.a = self.registers.a & !(1 << 4);
-                2
-            },
-
-            0xA8 => {
-                self.registers.b = self.registers.b & !(1 << 5);
-                2
-            },
-
-            0xA9 => {
-                self.registers.c = self.registers.c & !(1 << 5);
-                2
-            },
-
-            0xAA => {
-                self.registers.d = self.registers.d & !(1 << 5);
-                2
-            },
-
-            0xAB => {
-                self.registers.e = self.registers.e & !(1 << 5);
-                2
-            },
-
-            0xAC => {
-                self.registers.h = self.registers.h & !(1 << 5);
-                2
-            },
-
-            0xAD => {
-                self.registers.l = self.registers.l & !(1 << 5);
-                2
-            },
-
-            0xAE => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a) & !(1 << 5);
-                self.mmu.write_byte(a, v);
-
-                4
-            },
-
-            0xAF => {
-                self.registers.a = self.registers.a & !(1 << 5);
-                2
-            },
-
-            0xB0 => {
-                self.registers.b = self.registers.b & !(1 << 6);
-                2
-            },
-
-            0xB1 => {
-                self.registers.c = self.registers.c & !(1 << 6);
-                2
-            },
-
-            0xB2 => {
-                self.registers.d = self.registers.d & !(1 << 6);
-                2
-            },
-
-            0xB3 => {
-                self.registers.e = self.registers.e & !(1 << 6);
-                2
-            },
-
-            0xB4 => {
-                self.registers.h = self.registers.h & !(1 << 6);
-                2
-            },
-
-            0xB5 => {
-                self.registers.l = self.registers.l & !(1 << 6);
-                2
-            },
-
-            0xB6 => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a) & !(1 << 6);
-                self.mmu.write_byte(a, v);
-
-                4
-            },
-
-            0xB7 => {
-                self.registers.a = self.registers.a & !(1 << 6);
-                2
-            },
-
-            0xB8 => {
-                self.registers.b = self.registers.b & !(1 << 7);
-                2
-            },
-
-            0xB9 => {
-                self.registers.c = self.registers.c & !(1 << 7);
-                2
-            },
-
-            0xBA => {
-                self.registers.d = self.registers.d & !(1 << 7);
-                2
-            },
-
-            0xBB => {
-                self.registers.e = self.registers.e & !(1 << 7);
-                2
-            },
-
-            0xBC => {
-                self.registers.h = self.registers.h & !(1 << 7);
-                2
-            },
-
-            0xBD => {
-                self.registers.l = self.registers.l & !(1 << 7);
-                2
-            },
-
-            0xBE => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a) & !(1 << 7);
-                self.mmu.write_byte(a, v);
-                4
-            },
+    /// Handles HALT
+    ///
+    /// The CPU sleeps until an interrupt is requested, except when
+    /// one is already pending with IME disabled:
+    ///
+    /// * Right after EI, the interrupt is handled and it returns
+    ///   to the HALT, which then runs again
+    /// * Otherwise, the HALT bug kicks in
+    fn halt(&mut self) {
+        if self.interrupt_master_enable || !self.interrupt_pending() {
+            self.halted = true;
+        } else if self.interrupt_master_enable_scheduled {
+            self.registers.program_counter = self.registers.program_counter.wrapping_sub(1);
+        } else {
+            self.halt_bug = true;
+        }
+    }
 
-            0xBF => {
-                self.registers.a = self.registers.a & !(1 << 7);
-                2
-            },
+    /// Locks up the CPU
+    ///
+    /// `registers` are the ones from right after fetching the opcode,
+    /// so the program counter is already past it
+    fn lock_up(&mut self, opcode: u8, mut registers: RegisterSet) {
+        let program_counter = registers.program_counter.wrapping_sub(1);
+        registers.program_counter = program_counter;
+
+        let bank = if program_counter < 0x8000 {
+            self.mmu.mbc.rom_bank(program_counter)
+        } else {
+            0
+        };
 
-            0xC0 => {
-                self.registers.b = self.registers.b | (1 << 0);
-                2
-            },
+        self.registers.program_counter = program_counter;
+        self.fault = Some(CpuFault {
+            program_counter,
+            opcode,
+            bank,
+            registers,
+        });
+    }
 
-            0xC1 => {
-                self.registers.c = self.registers.c | (1 << 0);
-                2
-            },
+    /// Handles STOP
+    ///
+    /// STOP is a two bytes instruction (the second one is skipped),
+    /// which stops the CPU and the LCD until a key is pressed. On the
+    /// GameBoy Color it's also how games switch to double speed (see KEY1)
+    ///
+    /// There are some odd cases:
+    ///
+    /// * With a key held, STOP is not entered and it behaves like HALT,
+    ///   or like a one byte NOP if an interrupt is already pending
+    /// * With a pending interrupt, the second byte is not skipped
+    fn stop(&mut self) {
+        let key_held = self.mmu.keypad.lines() != 0x0F;
+        let interrupt_pending = self.interrupt_pending();
+
+        if !interrupt_pending {
+            self.read_byte();
+        }
 
-            0xC2 => {
-                self.registers.d = self.registers.d | (1 << 0);
-                2
-            },
+        if key_held {
+            self.halted = !interrupt_pending;
+            return
+        }
 
-            0xC3 => {
-                self.registers.e = self.registers.e | (1 << 0);
-                2
-            },
+        // DIV is reset when entering STOP, also on speed switches
+        self.mmu.write_byte(0xFF04, 0);
 
-            0xC4 => {
-                self.registers.h = self.registers.h | (1 << 0);
-                2
-            },
+        if !self.mmu.switch_speed() {
+            self.stopped = true;
+        }
+    }
 
-            0xC5 => {
-                self.registers.l = self.registers.l | (1 << 0);
-                2
-            },
+    /// Pushes to the stack
+    ///
+    /// There is an internal cycle before the writes, where
+    /// the stack pointer is decremented
+    fn push_stack(&mut self, value: u16) {
+        self.tick();
 
-            0xC6 => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a) | (1 << 0);
-                self.mmu.write_byte(a, v);
+        self.registers.stack_pointer = self.registers.stack_pointer.wrapping_sub(1);
+        self.write_memory(self.registers.stack_pointer, (value >> 8) as u8);
 
-                4
-            },
+        self.registers.stack_pointer = self.registers.stack_pointer.wrapping_sub(1);
+        self.write_memory(self.registers.stack_pointer, (value & 0xFF) as u8);
+    }
 
-            0xC7 => {
-                self.registers.a = self.registers.a | (1 << 0);
-                2
-            },
+    fn pop_stack(&mut self) -> u16 {
+        let res = self.read_memory_word(self.registers.stack_pointer);
+        self.registers.stack_pointer = self.registers.stack_pointer.wrapping_add(2);
+        res
+    }
 
-            0xC8 => {
-                self.registers.b = self.registers.b | (1 << 1);
-                2
-            },
+    /// Fetches a byte of the instruction, these are not
+    /// data reads, so they don't hit watchpoints
+    fn read_byte(&mut self) -> u8 {
+        self.tick();
+        let b = self.mmu.read_byte(self.registers.program_counter);
+        self.registers.program_counter = self.registers.program_counter.wrapping_add(1);
+        b
+    }
 
-            0xC9 => {
-                self.registers.c = self.registers.c | (1 << 1);
-                2
-            },
+    fn read_word(&mut self) -> u16 {
+        (self.read_byte() as u16) | ((self.read_byte() as u16) << 8)
+    }
 
-            0xCA => {
-                self.registers.d = self.registers.d | (1 << 1);
-                2
-            },
+    /// Executes an opcode
+    ///
+    /// This is where instructions sent by the game are handled.
+    ///
+    /// The Z80 contains 256 operations plus 256 CB-prefixed (see below).
+    /// They follow a regular encoding, so they are decoded from
+    /// the opcode bits instead of handled one by one:
+    ///
+    ///   7 6 | 5 4 3 | 2 1 0
+    ///    x  |   y   |   z
+    ///          p  q
+    ///
+    /// * x = 0 -> loads, increments, relative jumps and rotations
+    /// * x = 1 -> LD r[y], r[z] (except HALT, in place of LD (HL), (HL))
+    /// * x = 2 -> ALU operation y on A and r[z]
+    /// * x = 3 -> jumps, calls, stack, high memory and immediate ALU
+    ///
+    /// r[n] are the registers B, C, D, E, H, L, (HL) and A. Then y also
+    /// works as the condition, or the restart address (RST), while
+    /// p is a 16-bit register (see `read_pair`) and q selects the
+    /// direction of the operation
+    fn execute(&mut self, opcode: u8) -> u32 {
+        dispatch!(self.decode(opcode))
+    }
 
+    /// Decodes and executes an opcode, see `execute`
+    #[inline(always)]
+    fn decode(&mut self, opcode: u8) -> u32 {
+        match opcode {
+            // CB-prefixed operations, call a different set
+            // of operations (see method for more info)
             0xCB => {
-                self.registers.e = self.registers.e | (1 << 1);
-                2
-            },
-
-            0xCC => {
-                self.registers.h = self.registers.h | (1 << 1);
-                2
-            },
-
-            0xCD => {
-                self.registers.l = self.registers.l | (1 << 1);
-                2
-            },
-
-            0xCE => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a) | (1 << 1);
-                self.mmu.write_byte(a, v);
-
-                4
-            },
-
-            0xCF => {
-                self.registers.a = self.registers.a | (1 << 1);
-                2
+                self.execute_cb()
             },
 
-            0xD0 => {
-                self.registers.b = self.registers.b | (1 << 2);
-                2
-            },
-
-            0xD1 => {
-                self.registers.c = self.registers.c | (1 << 2);
-                2
-            },
-
-            0xD2 => {
-                self.registers.d = self.registers.d | (1 << 2);
-                2
-            },
-
-            0xD3 => {
-                self.registers.e = self.registers.e | (1 << 2);
-                2
-            },
-
-            0xD4 => {
-                self.registers.h = self.registers.h | (1 << 2);
-                2
-            },
-
-            0xD5 => {
-                self.registers.l = self.registers.l | (1 << 2);
-                2
-            },
-
-            0xD6 => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a) | (1 << 2);
-                self.mmu.write_byte(a, v);
-
-                4
+            // undefined opcodes, the CPU locks up
+            0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB ..= 0xED | 0xF4 | 0xFC | 0xFD => {
+                let registers = self.registers;
+                self.lock_up(opcode, registers);
+                1
             },
 
-            0xD7 => {
-                self.registers.a = self.registers.a | (1 << 2);
-                2
-            },
+            _ => {
+                let y = (opcode >> 3) & 0x07;
+                let z = opcode & 0x07;
 
-            0xD8 => {
-                self.registers.b = self.registers.b | (1 << 3);
-                2
-            },
+                let branch = match opcode >> 6 {
+                    0 => self.execute_misc(y, z),
 
-            0xD9 => {
-                self.registers.c = self.registers.c | (1 << 3);
-                2
-            },
+                    1 => {
+                        if opcode == 0x76 {
+                            self.halt();
+                        } else {
+                            let v = self.read_operand(z);
+                            self.write_operand(y, v);
+                        }
 
-            0xDA => {
-                self.registers.d = self.registers.d | (1 << 3);
-                2
-            },
+                        false
+                    },
 
-            0xDB => {
-                self.registers.e = self.registers.e | (1 << 3);
-                2
-            },
+                    2 => {
+                        let v = self.read_operand(z);
+                        self.alu(y, v);
+                        false
+                    },
 
-            0xDC => {
-                self.registers.h = self.registers.h | (1 << 3);
-                2
-            },
+                    _ => self.execute_control(opcode, y, z),
+                };
 
-            0xDD => {
-                self.registers.l = self.registers.l | (1 << 3);
-                2
-            },
+                opcodes::cycles(opcode, branch)
+            }
+        }
+    }
 
-            0xDE => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a) | (1 << 3);
-                self.mmu.write_byte(a, v);
+    /// Executes an opcode with x = 0
+    ///
+    /// Returns whether the branch was taken (for JR)
+    #[inline(always)]
+    fn execute_misc(&mut self, y: u8, z: u8) -> bool {
+        let p = y >> 1;
+        let q = y & 0x01;
 
-                4
+        match z {
+            0 => {
+                match y {
+                    // NOP
+                    0 => {},
+
+                    // LD (nn), SP
+                    1 => {
+                        let a = self.read_word();
+                        self.write_memory_word(a, self.registers.stack_pointer);
+                    },
+
+                    2 => {
+                        self.stop();
+                    },
+
+                    // JR d
+                    3 => {
+                        self.jump_relative(true);
+                    },
+
+                    // JR cc, d
+                    _ => {
+                        let condition = self.condition(y - 4);
+                        return self.jump_relative(condition)
+                    }
+                }
             },
 
-            0xDF => {
-                self.registers.a = self.registers.a | (1 << 3);
-                2
+            1 => {
+                if q == 0 {
+                    // LD rr, nn
+                    let v = self.read_word();
+                    self.write_pair(p, v);
+                } else {
+                    // ADD HL, rr
+                    let v = self.read_pair(p);
+                    self.alu_add16(v);
+                }
             },
 
-            0xE0 => {
-                self.registers.b = self.registers.b | (1 << 4);
-                2
-            },
+            // LD (BC), A / LD (DE), A / LD (HL+), A / LD (HL-), A
+            // and the other way around with q = 1
+            2 => {
+                let address = self.indirect_address(p);
 
-            0xE1 => {
-                self.registers.c = self.registers.c | (1 << 4);
-                2
+                if q == 0 {
+                    self.write_memory(address, self.registers.a);
+                } else {
+                    self.registers.a = self.read_memory(address);
+                }
             },
 
-            0xE2 => {
-                self.registers.d = self.registers.d | (1 << 4);
-                2
+            // INC rr / DEC rr
+            3 => {
+                let v = self.read_pair(p);
+                let v = if q == 0 { v.wrapping_add(1) } else { v.wrapping_sub(1) };
+                self.write_pair(p, v);
             },
 
-            0xE3 => {
-                self.registers.e = self.registers.e | (1 << 4);
-                2
+            4 => {
+                let v = self.read_operand(y);
+                let r = self.alu_increase(v);
+                self.write_operand(y, r);
             },
 
-            0xE4 => {
-                self.registers.h = self.registers.h | (1 << 4);
-                2
+            5 => {
+                let v = self.read_operand(y);
+                let r = self.alu_decrease(v);
+                self.write_operand(y, r);
             },
 
-            0xE5 => {
-                self.registers.l = self.registers.l | (1 << 4);
-                2
+            // LD r, n
+            6 => {
+                let v = self.read_byte();
+                self.write_operand(y, v);
+            },
+
+            _ => {
+                match y {
+                    // RLCA, RRCA, RLA and RRA work like their CB-prefixed
+                    // counterparts, but always reset the zero flag
+                    0 ..= 3 => {
+                        let a = self.registers.a;
+
+                        self.registers.a = match y {
+                            0 => self.alu_rlc(a),
+                            1 => self.alu_rrc(a),
+                            2 => self.alu_rl(a),
+                            _ => self.alu_rr(a),
+                        };
+
+                        self.registers.flag(Z, false);
+                    },
+
+                    4 => {
+                        self.alu_daa();
+                    },
+
+                    // CPL
+                    5 => {
+                        self.registers.a = !self.registers.a;
+                        self.registers.flag(H, true);
+                        self.registers.flag(N, true);
+                    },
+
+                    // SCF / CCF
+                    _ => {
+                        let v = y == 6 || !self.registers.is_flag_set(C);
+                        self.registers.flag(C, v);
+                        self.registers.flag(H, false);
+                        self.registers.flag(N, false);
+                    },
+                }
             },
+        }
 
-            0xE6 => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a) | (1 << 4);
-                self.mmu.write_byte(a, v);
+        false
+    }
 
-                4
-            },
+    /// Executes an opcode with x = 3
+    ///
+    /// Returns whether the branch was taken (for JP, CALL and RET)
+    #[inline(always)]
+    fn execute_control(&mut self, opcode: u8, y: u8, z: u8) -> bool {
+        let p = y >> 1;
+        let q = y & 0x01;
 
-            0xE7 => {
-                self.registers.a = self.registers.a | (1 << 4);
-                2
+        match z {
+            0 => {
+                match y {
+                    // RET cc
+                    0 ..= 3 => {
+                        if self.condition(y) {
+                            self.registers.program_counter = self.pop_stack();
+                            return true
+                        }
+                    },
+
+                    // LDH (n), A
+                    4 => {
+                        let a = 0xFF00 | self.read_byte() as u16;
+                        self.write_memory(a, self.registers.a);
+                    },
+
+                    // ADD SP, d
+                    5 => {
+                        let sp = self.registers.stack_pointer;
+                        self.registers.stack_pointer = self.alu_add16imm(sp);
+                    },
+
+                    // LDH A, (n)
+                    6 => {
+                        let a = 0xFF00 | self.read_byte() as u16;
+                        self.registers.a = self.read_memory(a);
+                    },
+
+                    // LD HL, SP + d
+                    _ => {
+                        let sp = self.registers.stack_pointer;
+                        let r = self.alu_add16imm(sp);
+                        self.registers.set_hl(r);
+                    },
+                }
             },
 
-            0xE8 => {
-                self.registers.b = self.registers.b | (1 << 5);
-                2
+            1 => {
+                match (q, p) {
+                    // POP rr, the lower bits of F are always zero
+                    (0, 3) => {
+                        let v = self.pop_stack() & 0xFFF0;
+                        self.registers.set_af(v);
+                    },
+
+                    (0, _) => {
+                        let v = self.pop_stack();
+                        self.write_pair(p, v);
+                    },
+
+                    // RET
+                    (_, 0) => {
+                        self.registers.program_counter = self.pop_stack();
+                    },
+
+                    // RETI
+                    (_, 1) => {
+                        self.registers.program_counter = self.pop_stack();
+                        self.interrupt_master_enable = true;
+                    },
+
+                    // JP HL
+                    (_, 2) => {
+                        self.registers.program_counter = self.registers.hl();
+                    },
+
+                    // LD SP, HL
+                    _ => {
+                        self.registers.stack_pointer = self.registers.hl();
+                    },
+                }
             },
 
-            0xE9 => {
-                self.registers.c = self.registers.c | (1 << 5);
-                2
+            2 => {
+                match y {
+                    // JP cc, nn
+                    0 ..= 3 => {
+                        let a = self.read_word();
+
+                        if self.condition(y) {
+                            self.registers.program_counter = a;
+                            return true
+                        }
+                    },
+
+                    // LD (0xFF00 + C), A
+                    4 => {
+                        self.write_memory(0xFF00 | self.registers.c as u16, self.registers.a);
+                    },
+
+                    // LD (nn), A
+                    5 => {
+                        let a = self.read_word();
+                        self.write_memory(a, self.registers.a);
+                    },
+
+                    // LD A, (0xFF00 + C)
+                    6 => {
+                        self.registers.a = self.read_memory(0xFF00 | self.registers.c as u16);
+                    },
+
+                    // LD A, (nn)
+                    _ => {
+                        let a = self.read_word();
+                        self.registers.a = self.read_memory(a);
+                    },
+                }
             },
 
-            0xEA => {
-                self.registers.d = self.registers.d | (1 << 5);
-                2
-            },
+            3 => {
+                match y {
+                    // JP nn
+                    0 => {
+                        self.registers.program_counter = self.read_word();
+                    },
 
-            0xEB => {
-                self.registers.e = self.registers.e | (1 << 5);
-                2
-            },
+                    // DI
+                    6 => {
+                        self.interrupt_master_enable = false;
+                        self.interrupt_master_enable_scheduled = false;
+                    },
 
-            0xEC => {
-                self.registers.h = self.registers.h | (1 << 5);
-                2
-            },
+                    // EI
+                    7 => {
+                        self.interrupt_master_enable_scheduled = true;
+                    },
 
-            0xED => {
-                self.registers.l = self.registers.l | (1 << 5);
-                2
+                    _ => unreachable!("Opcode {:02X} is not decoded here", opcode),
+                }
             },
 
-            0xEE => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a) | (1 << 5);
-                self.mmu.write_byte(a, v);
+            // CALL cc, nn
+            4 => {
+                let a = self.read_word();
 
-                4
+                if self.condition(y) {
+                    self.call(a);
+                    return true
+                }
             },
 
-            0xEF => {
-                self.registers.a = self.registers.a | (1 << 5);
-                2
+            5 => {
+                match (q, p) {
+                    // PUSH rr
+                    (0, 3) => {
+                        let v = self.registers.af();
+                        self.push_stack(v);
+                    },
+
+                    (0, _) => {
+                        let v = self.read_pair(p);
+                        self.push_stack(v);
+                    },
+
+                    // CALL nn
+                    _ => {
+                        let a = self.read_word();
+                        self.call(a);
+                    },
+                }
             },
 
-            0xF0 => {
-                self.registers.b = self.registers.b | (1 << 6);
-                2
+            // ALU operation with n
+            6 => {
+                let v = self.read_byte();
+                self.alu(y, v);
             },
 
-            0xF1 => {
-                self.registers.c = self.registers.c | (1 << 6);
-                2
+            // RST, calls to one of the first 8 addresses
+            // multiple of 8 (0x00, 0x08, 0x10 ... 0x38)
+            _ => {
+                self.call((y as u16) << 3);
             },
+        }
 
-            0xF2 => {
-                self.registers.d = self.registers.d | (1 << 6);
-                2
-            },
+        false
+    }
 
-            0xF3 => {
-                self.registers.e = self.registers.e | (1 << 6);
-                2
-            },
+    /// Execute CB-prefixed operations
+    ///
+    /// When an operation is CB-prefixed (CB is hex), these special
+    /// operations are called. This is simply to allow the Z80 to handle
+    /// a bigger number of operations
+    ///
+    /// They use the same layout as the rest, and always operate on r[z]:
+    ///
+    /// * x = 0 -> rotation or shift y (RLC, RRC, RL, RR, SLA, SRA, SWAP, SRL)
+    /// * x = 1 -> BIT y
+    /// * x = 2 -> RES y
+    /// * x = 3 -> SET y
+    fn execute_cb(&mut self) -> u32 {
+        let opcode = self.read_byte();
+        dispatch!(self.decode_cb(opcode))
+    }
 
-            0xF4 => {
-                self.registers.h = self.registers.h | (1 << 6);
-                2
-            },
+    /// Decodes and executes a CB-prefixed opcode, see `execute_cb`
+    #[inline(always)]
+    fn decode_cb(&mut self, opcode: u8) -> u32 {
+        let y = (opcode >> 3) & 0x07;
+        let z = opcode & 0x07;
 
-            0xF5 => {
-                self.registers.l = self.registers.l | (1 << 6);
-                2
-            },
+        let v = self.read_operand(z);
 
-            0xF6 => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a) | (1 << 6);
-                self.mmu.write_byte(a, v);
+        match opcode >> 6 {
+            0 => {
+                let r = match y {
+                    0 => self.alu_rlc(v),
+                    1 => self.alu_rrc(v),
+                    2 => self.alu_rl(v),
+                    3 => self.alu_rr(v),
+                    4 => self.alu_sla(v),
+                    5 => self.alu_sra(v),
+                    6 => self.alu_swap(v),
+                    _ => self.alu_srl(v),
+                };
 
-                4
+                self.write_operand(z, r);
             },
 
-            0xF7 => {
-                self.registers.a = self.registers.a | (1 << 6);
-                2
+            1 => {
+                self.alu_bit(v, y);
             },
 
-            0xF8 => {
-                self.registers.b = self.registers.b | (1 << 7);
-                2
+            2 => {
+                self.write_operand(z, v & !(1 << y));
             },
 
-            0xF9 => {
-                self.registers.c = self.registers.c | (1 << 7);
-                2
+            _ => {
+                self.write_operand(z, v | (1 << y));
             },
+        }
 
-            0xFA => {
-                self.registers.d = self.registers.d | (1 << 7);
-                2
-            },
+        opcodes::CB_CYCLES[opcode as usize] as u32
+    }
 
-            0xFB => {
-                self.registers.e = self.registers.e | (1 << 7);
-                2
+    /// Reads the register r[index]
+    ///
+    /// These are B, C, D, E, H, L, (HL) and A, reading
+    /// (HL) takes a cycle, as it goes to memory
+    #[inline(always)]
+    fn read_operand(&mut self, index: u8) -> u8 {
+        match index {
+            0 => self.registers.b,
+            1 => self.registers.c,
+            2 => self.registers.d,
+            3 => self.registers.e,
+            4 => self.registers.h,
+            5 => self.registers.l,
+            6 => {
+                let a = self.registers.hl();
+                self.read_memory(a)
             },
+            _ => self.registers.a,
+        }
+    }
 
-            0xFC => {
-                self.registers.h = self.registers.h | (1 << 7);
-                2
+    /// Writes the register r[index]
+    #[inline(always)]
+    fn write_operand(&mut self, index: u8, value: u8) {
+        match index {
+            0 => self.registers.b = value,
+            1 => self.registers.c = value,
+            2 => self.registers.d = value,
+            3 => self.registers.e = value,
+            4 => self.registers.h = value,
+            5 => self.registers.l = value,
+            6 => {
+                let a = self.registers.hl();
+                self.write_memory(a, value);
             },
+            _ => self.registers.a = value,
+        }
+    }
 
-            0xFD => {
-                self.registers.l = self.registers.l | (1 << 7);
-                2
-            },
+    /// Reads the 16-bit register rp[index]
+    ///
+    /// These are BC, DE, HL and SP, the stack operations
+    /// use AF in place of SP
+    #[inline(always)]
+    fn read_pair(&self, index: u8) -> u16 {
+        match index {
+            0 => self.registers.bc(),
+            1 => self.registers.de(),
+            2 => self.registers.hl(),
+            _ => self.registers.stack_pointer,
+        }
+    }
 
-            0xFE => {
-                let a = self.registers.hl();
-                let v = self.mmu.read_byte(a) | (1 << 7);
-                self.mmu.write_byte(a, v);
+    /// Writes the 16-bit register rp[index]
+    #[inline(always)]
+    fn write_pair(&mut self, index: u8, value: u16) {
+        match index {
+            0 => self.registers.set_bc(value),
+            1 => self.registers.set_de(value),
+            2 => self.registers.set_hl(value),
+            _ => self.registers.stack_pointer = value,
+        }
+    }
 
-                4
-            },
+    /// Address for the indirect loads of A
+    ///
+    /// These are (BC), (DE), (HL+) and (HL-), the
+    /// last two increase or decrease HL afterwards
+    #[inline(always)]
+    fn indirect_address(&mut self, index: u8) -> u16 {
+        match index {
+            0 => self.registers.bc(),
+            1 => self.registers.de(),
+            2 => self.registers.hl_increase(),
+            _ => self.registers.hl_decrease(),
+        }
+    }
 
-            0xFF => {
-                self.registers.a = self.registers.a | (1 << 7);
-                2
-            }
+    /// Evaluates the condition cc[index]: NZ, Z, NC and C
+    #[inline(always)]
+    fn condition(&self, index: u8) -> bool {
+        match index {
+            0 => !self.registers.is_flag_set(Z),
+            1 => self.registers.is_flag_set(Z),
+            2 => !self.registers.is_flag_set(C),
+            _ => self.registers.is_flag_set(C),
         }
     }
 
+    /// Performs the ALU operation alu[index] on A
+    ///
+    /// These are ADD, ADC, SUB, SBC, AND, XOR, OR and CP
+    #[inline(always)]
+    fn alu(&mut self, index: u8, value: u8) {
+        match index {
+            0 => self.alu_add(value, false),
+            1 => self.alu_add(value, true),
+            2 => self.alu_subtract(value, false),
+            3 => self.alu_subtract(value, true),
+            4 => self.alu_and(value),
+            5 => self.alu_xor(value),
+            6 => self.alu_or(value),
+            _ => self.alu_compare(value),
+        }
+    }
+
+    /// Calls a function
+    ///
+    /// The address of the next instruction is pushed
+    /// to the stack, so RET can come back to it
+    fn call(&mut self, address: u16) {
+        let program_counter = self.registers.program_counter;
+        self.push_stack(program_counter);
+        self.registers.program_counter = address;
+    }
+
     /// Performs an addition
     fn alu_add(&mut self, b: u8, usec: bool) {
         let c = if usec && self.registers.is_flag_set(C) { 1 } else { 0 };
@@ -3265,9 +1535,18 @@ impl Z80 {
     }
 
     /// Jump Relative (JR) CPU functionality
-    fn cpu_jr(&mut self) {
+    ///
+    /// The offset is always read, but only added to
+    /// the program counter if the condition is met
+    #[inline(always)]
+    fn jump_relative(&mut self, condition: bool) -> bool {
         let n = self.read_byte() as i8;
-        self.registers.program_counter = ((self.registers.program_counter as u32 as i32) + (n as i32)) as u16;
+
+        if condition {
+            self.registers.program_counter = self.registers.program_counter.wrapping_add(n as u16);
+        }
+
+        condition
     }
 
     pub fn get_gpu_pixels(&self) -> &[u8] {
@@ -3281,6 +1560,34 @@ impl Z80 {
     pub fn key_up(&mut self, key: Key) {
         self.mmu.keypad.key_up(key);
     }
+
+    /// Tilts the cartridge
+    ///
+    /// Values are in g, only used by cartridges with an accelerometer
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.mmu.mbc.set_tilt(x, y);
+    }
+
+    /// Sets the image seen by the camera sensor (Pocket Camera)
+    pub fn set_image_source(&mut self, source: Box<dyn ImageSource>) {
+        self.mmu.mbc.set_image_source(source);
+    }
+
+    /// Sets what drives the cartridge clock, if there is one
+    pub fn set_rtc_clock(&mut self, rtc_clock: RtcClock) {
+        self.mmu.mbc.set_rtc_clock(rtc_clock);
+    }
+
+    /// Share of time the cartridge rumble motor was on
+    /// since the last call, from 0.0 to 1.0
+    pub fn rumble(&mut self) -> f32 {
+        self.mmu.mbc.rumble()
+    }
+
+    /// Saves the battery backed cartridge RAM to disk
+    pub fn save_ram(&mut self) {
+        self.mmu.save_ram();
+    }
 }
 
 #[cfg(test)]
@@ -3289,9 +1596,508 @@ mod tests {
     use memory::mmu::MMU;
     use std::cell::RefCell;
     use std::rc::Rc;
+    use std::fs;
 
     #[test]
     fn it_instantiates() {
-        let mut cpu = Z80::new("./data/tetris.gb");
+        let mut cpu = Z80::new("./data/tetris.gb", Model::DMG).unwrap();
+    }
+
+    fn boot(data: Vec<u8>) -> Z80 {
+        let mut cpu = Z80::from_rom(data, Model::DMG).unwrap();
+        cpu.load_boot_rom("./data/dmg.rom").unwrap();
+
+        // the logo scroll takes a bit more than 2 seconds
+        for _ in 0 .. 5_000_000 {
+            if cpu.registers.program_counter == 0x0100 {
+                break
+            }
+
+            cpu.cycle();
+        }
+
+        cpu
+    }
+
+    #[test]
+    fn it_powers_on_before_the_boot_rom() {
+        let mut cpu = Z80::new("./data/tetris.gb", Model::DMG).unwrap();
+        cpu.mmu.write_byte(0xFF43, 0x12);
+        cpu.load_boot_rom("./data/dmg.rom").unwrap();
+
+        assert!(!cpu.interrupt_master_enable);
+        assert_eq!(cpu.registers.program_counter, 0x0000);
+
+        for &address in &[0xFF07, 0xFF0F, 0xFF26, 0xFF40, 0xFF43, 0xFF47, 0xFF48, 0xFF49] {
+            assert_eq!(cpu.mmu.read_byte(address), 0, "register {:04X}", address);
+        }
+    }
+
+    #[test]
+    fn it_runs_the_boot_rom() {
+        let data = fs::read("./data/tetris.gb").unwrap();
+        let mut cpu = boot(data.clone());
+
+        assert_eq!(cpu.registers.program_counter, 0x0100);
+        assert!(!cpu.mmu.boot_rom_mapped());
+        assert_eq!(cpu.mmu.read_byte(0x0000), data[0x0000]);
+        assert_eq!(cpu.registers.a, 0x01);
+    }
+
+    #[test]
+    fn it_locks_up_with_a_bad_logo() {
+        let mut data = fs::read("./data/tetris.gb").unwrap();
+        data[0x104] = 0;
+
+        let cpu = boot(data);
+
+        assert_ne!(cpu.registers.program_counter, 0x0100);
+        assert!(cpu.mmu.boot_rom_mapped());
+    }
+
+    #[test]
+    fn it_starts_in_the_model_post_boot_state() {
+        let cpu = Z80::new("./data/tetris.gb", Model::DMG).unwrap();
+        assert_eq!(cpu.registers.a, 0x01);
+        assert_eq!(cpu.registers.flags, 0xB0);
+
+        let mut cpu = Z80::new("./data/tetris.gb", Model::AGB).unwrap();
+        assert_eq!(cpu.registers.a, 0x11);
+        assert_eq!(cpu.registers.b & 0x01, 0x01);
+        assert_eq!(cpu.mmu.read_byte(0xFF04), 0x26);
+        assert_eq!(cpu.mmu.read_byte(0xFF44), 0x90);
+    }
+
+    #[test]
+    fn it_starts_with_the_model_io_registers() {
+        let mut cpu = Z80::new("./data/tetris.gb", Model::DMG).unwrap();
+        assert_eq!(cpu.mmu.read_byte(0xFF02), 0x7E);
+        assert_eq!(cpu.mmu.read_byte(0xFF26), 0xF1);
+        assert_eq!(cpu.mmu.read_byte(0xFF0F), 0xE1);
+
+        let mut cpu = Z80::new("./data/tetris.gb", Model::SGB).unwrap();
+        assert_eq!(cpu.mmu.read_byte(0xFF26), 0xF0);
+
+        for &model in &[Model::CGB, Model::AGB] {
+            let mut cpu = Z80::new("./data/tetris.gb", model).unwrap();
+            assert_eq!(cpu.mmu.read_byte(0xFF02), 0x7F);
+        }
+    }
+
+    #[test]
+    fn it_banks_working_ram_in_color_mode() {
+        let mut data = vec![0; 0x8000];
+        data[0x143] = 0x80;
+
+        let mut cpu = Z80::from_rom(data.clone(), Model::CGB).unwrap();
+        assert!(cpu.mmu.cgb_mode);
+
+        cpu.mmu.write_byte(0xFF70, 0x02);
+        cpu.mmu.write_byte(0xD000, 0x22);
+        cpu.mmu.write_byte(0xFF70, 0x00);
+        cpu.mmu.write_byte(0xD000, 0x11);
+
+        assert_eq!(cpu.mmu.read_byte(0xFF70), 0xF9);
+        cpu.mmu.write_byte(0xFF70, 0x02);
+        assert_eq!(cpu.mmu.read_byte(0xD000), 0x22);
+
+        let cpu = Z80::from_rom(data, Model::DMG).unwrap();
+        assert!(!cpu.mmu.cgb_mode);
+    }
+
+    #[test]
+    fn it_switches_to_double_speed_on_stop() {
+        let mut data = vec![0; 0x8000];
+        data[0x143] = 0xC0;
+        data[0x100] = 0x10;
+
+        let mut cpu = Z80::from_rom(data, Model::CGB).unwrap();
+        cpu.mmu.write_byte(0xFF4D, 0x01);
+        assert_eq!(cpu.mmu.read_byte(0xFF4D), 0x7F);
+
+        cpu.cycle();
+        assert_eq!(cpu.registers.program_counter, 0x102);
+        assert_eq!(cpu.mmu.read_byte(0xFF4D), 0xFE);
+
+        // the GPU sees half of the CPU ticks
+        assert_eq!(cpu.mmu.step(3) + cpu.mmu.step(1), 2);
+    }
+
+    #[test]
+    fn it_stops_until_a_key_is_pressed() {
+        let mut cpu = Z80::from_program(&[0x10]);
+        cpu.mmu.write_byte(0xFF00, 0x20);
+        cpu.step().unwrap();
+
+        assert!(cpu.stopped);
+        assert_eq!(cpu.registers.program_counter, 0x102);
+        assert_eq!(cpu.mmu.read_byte(0xFF04), 0);
+
+        // keys outside the selected row don't wake the CPU up
+        cpu.key_down(Key::A);
+        cpu.step().unwrap();
+        assert!(cpu.stopped);
+
+        cpu.key_down(Key::Left);
+        cpu.step().unwrap();
+        assert!(!cpu.stopped);
+    }
+
+    #[test]
+    fn it_halts_on_stop_with_a_key_held() {
+        let mut cpu = Z80::from_program(&[0x10]);
+        cpu.key_down(Key::Start);
+        cpu.cycle();
+
+        assert!(!cpu.stopped);
+        assert!(cpu.halted);
+        assert_eq!(cpu.registers.program_counter, 0x102);
+    }
+
+    #[test]
+    fn it_locks_up_on_illegal_opcodes() {
+        let mut cpu = Z80::from_program(&[0x00, 0xFD]);
+        cpu.mmu.interrupt_enable = 0x1F;
+        cpu.mmu.interrupt_flag = 0x01;
+        cpu.interrupt_master_enable = false;
+
+        let fault = cpu.step().unwrap_err();
+        assert_eq!(fault.opcode, 0xFD);
+        assert_eq!(fault.program_counter, 0x101);
+        assert_eq!(fault.bank, 0);
+
+        // not even interrupts get it running again
+        cpu.interrupt_master_enable = true;
+        assert!(cpu.step().is_err());
+        assert_eq!(cpu.registers.program_counter, 0x101);
+    }
+
+    fn program(code: &[u8]) -> Z80 {
+        let mut cpu = Z80::from_program(code);
+        cpu.interrupt_master_enable = false;
+        cpu.registers.a = 0;
+        cpu
+    }
+
+    #[test]
+    fn it_ticks_the_system_before_each_memory_access() {
+        // LDH A,(0x44), LDH A,(0x44)
+        let mut cpu = program(&[0xF0, 0x44, 0xF0, 0x44]);
+        cpu.mmu.gpu.set_phase(10, 446);
+
+        // the line ends on the operand fetch, before LY is read
+        assert_eq!(cpu.cycle(), 12);
+        assert_eq!(cpu.registers.a, 11);
+
+        // here it ends right after the read
+        cpu.mmu.gpu.set_phase(10, 443);
+
+        cpu.cycle();
+        assert_eq!(cpu.registers.a, 10);
+    }
+
+    #[test]
+    fn it_reads_the_timer_in_the_middle_of_an_instruction() {
+        // LDH A,(0x04), LDH A,(0x04)
+        let mut cpu = program(&[0xF0, 0x04, 0xF0, 0x04]);
+
+        // DIV goes up 8 ticks into the instruction, before the read
+        cpu.mmu.timer.set_divider_counter(0x01F8);
+        cpu.cycle();
+        assert_eq!(cpu.registers.a, 0x02);
+
+        // and here 2 ticks after the read
+        cpu.mmu.timer.set_divider_counter(0x01F2);
+        cpu.cycle();
+        assert_eq!(cpu.registers.a, 0x01);
+    }
+
+    #[test]
+    fn it_counts_the_divider_in_clock_ticks() {
+        // JR -2, 12 ticks each
+        let mut cpu = program(&[0x18, 0xFE]);
+        cpu.mmu.timer.set_divider_counter(0);
+
+        for _ in 0 .. 64 {
+            cpu.cycle();
+        }
+
+        assert_eq!(cpu.mmu.read_byte(0xFF04), 3);
+    }
+
+    #[test]
+    fn it_takes_the_official_cycles_for_every_opcode() {
+        let conditional = [
+            0x20, 0x28, 0x30, 0x38, 0xC0, 0xC8, 0xD0, 0xD8,
+            0xC2, 0xCA, 0xD2, 0xDA, 0xC4, 0xCC, 0xD4, 0xDC,
+        ];
+
+        for opcode in 0 .. 256 {
+            let opcode = opcode as u8;
+
+            if opcodes::CYCLES[opcode as usize] == 0 || [0x10, 0x76, 0xCB].contains(&opcode) {
+                continue
+            }
+
+            // NZ and NC are met with the flags clear, Z and C with them set
+            for &flags in &[0x00, 0xF0] {
+                let mut cpu = program(&[opcode, 0x00, 0xC0]);
+                cpu.registers.flags = flags;
+                cpu.registers.c = 0x80;
+                cpu.registers.set_hl(0xC000);
+                cpu.registers.stack_pointer = 0xD000;
+
+                let taken = conditional.contains(&opcode) &&
+                    (((opcode >> 3) & 0x01 == 0) == (flags == 0x00));
+
+                assert_eq!(cpu.cycle(), opcodes::cycles(opcode, taken) * 4, "opcode {:02X}", opcode);
+            }
+        }
+
+        for opcode in 0 .. 256 {
+            let mut cpu = program(&[0xCB, opcode as u8]);
+            cpu.registers.set_hl(0xC000);
+
+            assert_eq!(cpu.cycle(), opcodes::CB_CYCLES[opcode] as u32 * 4, "opcode CB {:02X}", opcode);
+        }
+    }
+
+    #[test]
+    fn it_reads_the_byte_after_halt_twice() {
+        // HALT, INC A, INC A
+        let mut cpu = program(&[0x76, 0x3C, 0x3C]);
+        cpu.mmu.interrupt_enable = 0x04;
+        cpu.mmu.interrupt_flag = 0x04;
+
+        for _ in 0 .. 3 {
+            cpu.cycle();
+        }
+
+        assert!(!cpu.halted);
+        assert_eq!(cpu.registers.a, 2);
+        assert_eq!(cpu.registers.program_counter, 0x102);
+    }
+
+    #[test]
+    fn it_wakes_up_from_halt_without_ime() {
+        // HALT, INC A
+        let mut cpu = program(&[0x76, 0x3C]);
+        cpu.cycle();
+        assert!(cpu.halted);
+
+        cpu.mmu.interrupt_enable = 0x04;
+        cpu.mmu.interrupt_flag = 0x04;
+        assert_eq!(cpu.cycle(), 4);
+        cpu.cycle();
+
+        // the interrupt is not handled
+        assert_eq!(cpu.registers.a, 1);
+        assert_eq!(cpu.mmu.interrupt_flag, 0x04);
+    }
+
+    #[test]
+    fn it_returns_to_halt_after_ei() {
+        // EI, HALT
+        let mut cpu = program(&[0xFB, 0x76]);
+        cpu.mmu.interrupt_enable = 0x04;
+        cpu.mmu.interrupt_flag = 0x04;
+
+        for _ in 0 .. 3 {
+            cpu.cycle();
+        }
+
+        assert_eq!(cpu.registers.program_counter, 0x50);
+        assert_eq!(cpu.mmu.read_word(cpu.registers.stack_pointer), 0x101);
+    }
+
+    #[test]
+    fn it_enables_interrupts_one_instruction_after_ei() {
+        // EI, INC A, INC A
+        let mut cpu = program(&[0xFB, 0x3C, 0x3C]);
+        cpu.mmu.interrupt_enable = 0x01;
+        cpu.mmu.interrupt_flag = 0x01;
+
+        cpu.cycle();
+        cpu.cycle();
+        assert_eq!(cpu.cycle(), 20);
+
+        assert_eq!(cpu.registers.a, 1);
+        assert_eq!(cpu.registers.program_counter, 0x40);
+        assert_eq!(cpu.mmu.interrupt_flag, 0x00);
+    }
+
+    #[test]
+    fn it_disables_interrupts_right_away() {
+        // EI, DI, INC A
+        let mut cpu = program(&[0xFB, 0xF3, 0x3C]);
+        cpu.mmu.interrupt_enable = 0x01;
+        cpu.mmu.interrupt_flag = 0x01;
+
+        for _ in 0 .. 3 {
+            cpu.cycle();
+        }
+
+        assert_eq!(cpu.registers.a, 1);
+        assert!(!cpu.interrupt_master_enable);
+    }
+
+    #[test]
+    fn it_cancels_the_dispatch_when_pushing_over_ie() {
+        let mut cpu = program(&[]);
+        cpu.interrupt_master_enable = true;
+        cpu.registers.stack_pointer = 0x0000;
+        cpu.mmu.interrupt_enable = 0x04;
+        cpu.mmu.interrupt_flag = 0x04;
+
+        // pushing 0x01 (PC high byte) to IE leaves only VBlank enabled
+        cpu.cycle();
+
+        assert_eq!(cpu.mmu.interrupt_enable, 0x01);
+        assert_eq!(cpu.mmu.interrupt_flag, 0x04);
+        assert_eq!(cpu.registers.program_counter, 0x0000);
+        assert_eq!(cpu.registers.stack_pointer, 0xFFFE);
+    }
+
+    #[test]
+    fn it_traces_instructions_to_a_file() {
+        use std::env;
+        use std::fs;
+        use cpu::trace::{TraceOptions, Trigger};
+
+        // NOP, JP 0x0100
+        let mut cpu = program(&[0x00, 0xC3, 0x00, 0x01]);
+        cpu.registers.a = 0x01;
+
+        let path = env::temp_dir().join("safeboy-trace-test.log");
+        let options = TraceOptions {
+            stop: Some(Trigger::Instructions(3)),
+            ..TraceOptions::default()
+        };
+
+        cpu.trace(Tracer::create(path.to_str().unwrap(), options).unwrap());
+
+        for _ in 0 .. 4 {
+            cpu.cycle();
+        }
+
+        assert!(cpu.tracer.is_none());
+
+        let trace = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(trace, "\
+A:01 F:80 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,00,01
+A:01 F:80 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,00,01,00
+A:01 F:80 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,00,01
+");
+    }
+
+    #[test]
+    fn it_stops_on_breakpoints_and_watchpoints() {
+        use cpu::debugger::{Breakpoint, Watchpoint};
+
+        // INC A, LD (0xC000), A, JP 0x0100
+        let mut cpu = program(&[0x3C, 0xEA, 0x00, 0xC0, 0xC3, 0x00, 0x01]);
+
+        let breakpoint = cpu.debugger().add_breakpoint(Breakpoint {
+            condition: Some("A == 3".parse().unwrap()),
+            ..Breakpoint::new(0x0101)
+        });
+
+        match cpu.run_frame() {
+            StopReason::Breakpoint(id) => assert_eq!(id, breakpoint),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert_eq!((cpu.registers.program_counter, cpu.registers.a), (0x0101, 3));
+
+        cpu.debugger().clear();
+        let watchpoint = cpu.debugger().add_watchpoint(Watchpoint::new(0xC000, Access::Write));
+
+        match cpu.run_frame() {
+            StopReason::Watchpoint { id, address, value, access } => {
+                assert_eq!((id, address, value, access), (watchpoint, 0xC000, 3, Access::Write));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert_eq!(cpu.registers.program_counter, 0x0104);
+
+        cpu.debugger().clear();
+
+        match cpu.run_frame() {
+            StopReason::FrameEnd => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_steps_over_into_and_out_of_calls() {
+        let mut code = vec![0; 0x20];
+
+        // CALL 0x0110, NOP ... 0x0110: INC B, INC B, RET
+        code[.. 4].copy_from_slice(&[0xCD, 0x10, 0x01, 0x00]);
+        code[0x10 .. 0x13].copy_from_slice(&[0x04, 0x04, 0xC9]);
+
+        let mut cpu = program(&code);
+        cpu.registers.b = 0;
+
+        let steps = |cpu: &mut Z80, reason: StopReason| match reason {
+            StopReason::Step => (cpu.registers.program_counter, cpu.registers.b),
+            other => panic!("unexpected {:?}", other),
+        };
+
+        let reason = cpu.step_over();
+        assert_eq!(steps(&mut cpu, reason), (0x0103, 2));
+
+        cpu.registers.program_counter = 0x0100;
+
+        let reason = cpu.step_instruction();
+        assert_eq!(steps(&mut cpu, reason), (0x0110, 2));
+
+        let reason = cpu.step_instruction();
+        assert_eq!(steps(&mut cpu, reason), (0x0111, 3));
+
+        let reason = cpu.step_out();
+        assert_eq!(steps(&mut cpu, reason), (0x0103, 4));
+
+        cpu.registers.program_counter = 0x0100;
+
+        let reason = cpu.run_to(None, 0x0112);
+        assert_eq!(steps(&mut cpu, reason), (0x0112, 6));
+    }
+
+    #[test]
+    fn it_runs_the_cartridge_clock_from_cycles() {
+        // MBC3+TIMER+BATTERY
+        let mut data = vec![0; 0x8000];
+        data[0x147] = 0x0F;
+
+        let mut cpu = Z80::from_rom(data, Model::DMG).unwrap();
+        cpu.set_rtc_clock(RtcClock::Cycles);
+
+        // with the LCD off, so a minute of drawing doesn't slow down the test
+        cpu.mmu.write_byte(0xFF40, 0x00);
+        cpu.mmu.step(4_194_304 * 61);
+
+        // enable the clock, latch it and read the seconds and minutes
+        cpu.mmu.write_byte(0x0000, 0x0A);
+        cpu.mmu.write_byte(0x6000, 0x00);
+        cpu.mmu.write_byte(0x6000, 0x01);
+
+        cpu.mmu.write_byte(0x4000, 0x08);
+        assert_eq!(cpu.mmu.read_byte(0xA000), 1);
+        cpu.mmu.write_byte(0x4000, 0x09);
+        assert_eq!(cpu.mmu.read_byte(0xA000), 1);
+    }
+
+    #[test]
+    fn it_reports_rom_errors() {
+        assert!(matches!(Z80::new("./data/missing.gb", Model::DMG), Err(SafeboyError::RomFile(..))));
+        assert!(matches!(Z80::from_rom(vec![0; 0x100], Model::DMG), Err(SafeboyError::InvalidRom(..))));
+
+        let mut data = vec![0; 0x8000];
+        data[0x147] = 0x20;
+        assert!(matches!(Z80::from_rom(data, Model::DMG), Err(SafeboyError::UnsupportedMapper(0x20))));
     }
 }
\ No newline at end of file
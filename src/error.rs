@@ -0,0 +1,57 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Safeboy errors
+///
+/// Anything that can go wrong while building the machine,
+/// mostly problems with the ROM we were given
+#[derive(Debug)]
+pub enum SafeboyError {
+    /// The ROM file could not be opened or read
+    RomFile(String, io::Error),
+
+    /// The data is not a GameBoy ROM, or it is cut short
+    InvalidRom(String),
+
+    /// The cartridge uses a controller we don't emulate (yet)
+    UnsupportedMapper(u8),
+
+    /// The boot ROM file could not be opened or read
+    BootRomFile(String, io::Error),
+
+    /// The boot ROM is not 256 bytes long
+    InvalidBootRom(usize),
+
+    /// The trace file could not be created
+    TraceFile(String, io::Error),
+
+    /// The GDB server could not listen on the port
+    GdbServer(u16, io::Error),
+}
+
+impl fmt::Display for SafeboyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SafeboyError::RomFile(ref path, ref e) => write!(f, "Could not read ROM file {}: {}", path, e),
+            SafeboyError::InvalidRom(ref reason) => write!(f, "Invalid ROM: {}", reason),
+            SafeboyError::UnsupportedMapper(code) => write!(f, "Unsupported MBC: {:02X}", code),
+            SafeboyError::BootRomFile(ref path, ref e) => write!(f, "Could not read boot ROM file {}: {}", path, e),
+            SafeboyError::InvalidBootRom(size) => write!(f, "Invalid boot ROM: expected 256 bytes, got {}", size),
+            SafeboyError::TraceFile(ref path, ref e) => write!(f, "Could not create trace file {}: {}", path, e),
+            SafeboyError::GdbServer(port, ref e) => write!(f, "Could not listen for GDB on port {}: {}", port, e),
+        }
+    }
+}
+
+impl Error for SafeboyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            SafeboyError::RomFile(_, ref e) => Some(e),
+            SafeboyError::BootRomFile(_, ref e) => Some(e),
+            SafeboyError::TraceFile(_, ref e) => Some(e),
+            SafeboyError::GdbServer(_, ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,763 @@
+use std::io::{self, BufRead, Write};
+
+use cpu::z80::Z80;
+use cpu::disasm;
+use cpu::debugger::{self, parse_address, parse_location, Access, Breakpoint, StopReason, Watchpoint};
+
+/// I/O registers, by name
+const IO_REGISTERS: [(&str, u16); 55] = [
+    ("P1", 0xFF00), ("SB", 0xFF01), ("SC", 0xFF02),
+    ("DIV", 0xFF04), ("TIMA", 0xFF05), ("TMA", 0xFF06), ("TAC", 0xFF07),
+    ("IF", 0xFF0F),
+    ("NR10", 0xFF10), ("NR11", 0xFF11), ("NR12", 0xFF12), ("NR13", 0xFF13), ("NR14", 0xFF14),
+    ("NR21", 0xFF16), ("NR22", 0xFF17), ("NR23", 0xFF18), ("NR24", 0xFF19),
+    ("NR30", 0xFF1A), ("NR31", 0xFF1B), ("NR32", 0xFF1C), ("NR33", 0xFF1D), ("NR34", 0xFF1E),
+    ("NR41", 0xFF20), ("NR42", 0xFF21), ("NR43", 0xFF22), ("NR44", 0xFF23),
+    ("NR50", 0xFF24), ("NR51", 0xFF25), ("NR52", 0xFF26),
+    ("LCDC", 0xFF40), ("STAT", 0xFF41), ("SCY", 0xFF42), ("SCX", 0xFF43),
+    ("LY", 0xFF44), ("LYC", 0xFF45), ("DMA", 0xFF46),
+    ("BGP", 0xFF47), ("OBP0", 0xFF48), ("OBP1", 0xFF49), ("WY", 0xFF4A), ("WX", 0xFF4B),
+    ("KEY1", 0xFF4D), ("VBK", 0xFF4F),
+    ("HDMA1", 0xFF51), ("HDMA2", 0xFF52), ("HDMA3", 0xFF53), ("HDMA4", 0xFF54), ("HDMA5", 0xFF55),
+    ("RP", 0xFF56),
+    ("BCPS", 0xFF68), ("BCPD", 0xFF69), ("OCPS", 0xFF6A), ("OCPD", 0xFF6B),
+    ("SVBK", 0xFF70),
+    ("IE", 0xFFFF),
+];
+
+/// Instructions shown before the program counter when disassembling
+const CONTEXT_BEFORE: u16 = 4;
+
+/// Instructions disassembled by default
+const DISASSEMBLY_LENGTH: u16 = 10;
+
+/// Frames next, finish and until run before giving up,
+/// about ten seconds of emulated time
+const RESUME_FRAMES: usize = 600;
+
+const HELP: &str = "\
+Commands (an empty line repeats the last one):
+  s, step [N]                 executes N instructions (1 by default)
+  n, next                     executes an instruction, running calls until they return
+  finish                      runs until the current function returns
+  c, continue [FRAMES]        runs until stopped, or for a number of frames
+  u, until [BANK:]ADDR        runs until the program counter gets to an address
+  b, break [BANK:]ADDR [if C] adds a breakpoint, with a condition like A == 0x10
+  w, watch ADDR[-END] [r|w|rw] adds a watchpoint on writes (by default) or reads
+  d, delete [ID]              deletes a breakpoint or watchpoint, or all of them
+  l, list                     lists breakpoints and watchpoints
+  r, regs                     shows the registers
+  x ADDR [LENGTH]             dumps memory
+  dis [ADDR] [COUNT]          disassembles around the program counter or at an address
+  stack [COUNT]               shows the words on the stack
+  io [NAME...]                shows the I/O registers, or the named ones
+  q, quit                     exits
+Addresses are in hex, counts in decimal (or hex with a 0x prefix)";
+
+/// Whether the console keeps reading commands
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Flow {
+    Continue,
+    Quit,
+}
+
+/// Error running a command
+enum CommandError {
+    /// The command was wrong, the console reports it and goes on
+    Invalid(String),
+
+    /// The output can't be written
+    Output(io::Error),
+}
+
+impl From<String> for CommandError {
+    fn from(message: String) -> CommandError {
+        CommandError::Invalid(message)
+    }
+}
+
+impl From<io::Error> for CommandError {
+    fn from(error: io::Error) -> CommandError {
+        CommandError::Output(error)
+    }
+}
+
+type CommandResult = Result<(), CommandError>;
+
+/// Terminal debugger
+///
+/// Runs the CPU under the debugger, reading commands line by
+/// line. Nothing is drawn, so it works without a display (over
+/// SSH or in CI), type help for the list of commands
+pub struct Console {
+    cpu: Z80,
+
+    /// Last command, an empty line runs it again
+    last: String,
+}
+
+impl Console {
+    pub fn new(cpu: Z80) -> Console {
+        Console {
+            cpu,
+            last: String::new(),
+        }
+    }
+
+    /// Reads commands until quit or the end of the input
+    ///
+    /// The battery backed RAM is saved when leaving, just
+    /// like when closing the emulator window
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, out: &mut W) -> io::Result<()> {
+        let result = self.read_commands(input, out);
+        self.cpu.save_ram();
+
+        result
+    }
+
+    fn read_commands<R: BufRead, W: Write>(&mut self, input: R, out: &mut W) -> io::Result<()> {
+        self.show_location(out)?;
+
+        let mut lines = input.lines();
+
+        loop {
+            write!(out, "(safeboy) ")?;
+            out.flush()?;
+
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return writeln!(out),
+            };
+
+            if !line.trim().is_empty() {
+                self.last = line.trim().to_string();
+            }
+
+            let command = self.last.clone();
+
+            if self.execute(&command, out)? == Flow::Quit {
+                return Ok(())
+            }
+        }
+    }
+
+    /// Runs a command
+    ///
+    /// Mistakes in the command are reported to the output, only
+    /// errors writing the output are returned
+    pub fn execute<W: Write>(&mut self, line: &str, out: &mut W) -> io::Result<Flow> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Ok(Flow::Continue),
+        };
+
+        let result = match command {
+            "s" | "step" => self.step(args, out),
+            "n" | "next" => self.next(out),
+            "finish" => self.finish(out),
+            "c" | "continue" => self.continue_running(args, out),
+            "u" | "until" => self.until(args, out),
+            "b" | "break" => self.add_breakpoint(args, out),
+            "w" | "watch" => self.add_watchpoint(args, out),
+            "d" | "delete" => self.delete(args, out),
+            "l" | "list" => self.list(out),
+            "r" | "regs" => self.show_registers(out),
+            "x" => self.dump(args, out),
+            "dis" => self.disassemble(args, out),
+            "stack" => self.show_stack(args, out),
+            "io" => self.show_io(args, out),
+            "h" | "help" => writeln!(out, "{}", HELP).map_err(CommandError::from),
+            "q" | "quit" => return Ok(Flow::Quit),
+            _ => Err(CommandError::from(format!("Unknown command {}, type help for the list", command))),
+        };
+
+        match result {
+            Ok(()) => Ok(Flow::Continue),
+            Err(CommandError::Invalid(message)) => writeln!(out, "{}", message).map(|_| Flow::Continue),
+            Err(CommandError::Output(e)) => Err(e),
+        }
+    }
+
+    fn step<W: Write>(&mut self, args: &[&str], out: &mut W) -> CommandResult {
+        let count = match args {
+            [] => 1,
+            [count] => parse_count(count)?,
+            _ => return Err(usage("step [N]")),
+        };
+
+        for _ in 0 .. count {
+            let reason = self.cpu.step_instruction();
+
+            if let StopReason::Step = reason {
+                continue
+            }
+
+            self.report(&reason, out)?;
+            break
+        }
+
+        Ok(self.show_location(out)?)
+    }
+
+    fn next<W: Write>(&mut self, out: &mut W) -> CommandResult {
+        self.check_fault()?;
+
+        let reason = self.cpu.step_over();
+        self.resume(reason, out)
+    }
+
+    fn finish<W: Write>(&mut self, out: &mut W) -> CommandResult {
+        self.check_fault()?;
+
+        let reason = self.cpu.step_out();
+        self.resume(reason, out)
+    }
+
+    fn until<W: Write>(&mut self, args: &[&str], out: &mut W) -> CommandResult {
+        let (bank, address) = match args {
+            [location] => parse_location(location)?,
+            _ => return Err(usage("until [BANK:]ADDR")),
+        };
+
+        self.check_fault()?;
+
+        let reason = self.cpu.run_to(bank, address);
+        self.resume(reason, out)
+    }
+
+    fn continue_running<W: Write>(&mut self, args: &[&str], out: &mut W) -> CommandResult {
+        let frames = match args {
+            [] => None,
+            [frames] => Some(parse_count(frames)?),
+            _ => return Err(usage("continue [FRAMES]")),
+        };
+
+        if frames.is_none() {
+            self.check_fault()?;
+        }
+
+        let mut ran = 0;
+
+        loop {
+            match self.cpu.run_frame() {
+                StopReason::FrameEnd => {
+                    ran += 1;
+
+                    if frames == Some(ran) {
+                        writeln!(out, "Ran {} frames", ran)?;
+                        break
+                    }
+                },
+
+                reason => {
+                    self.report(&reason, out)?;
+                    break
+                },
+            }
+        }
+
+        Ok(self.show_location(out)?)
+    }
+
+    /// Keeps running frames until the step (or a breakpoint) stops
+    ///
+    /// It gives up after `RESUME_FRAMES`, in case the step never
+    /// completes, like an address that is never reached
+    fn resume<W: Write>(&mut self, mut reason: StopReason, out: &mut W) -> CommandResult {
+        let mut ran = 0;
+
+        while let StopReason::FrameEnd = reason {
+            ran += 1;
+
+            if ran == RESUME_FRAMES {
+                self.cpu.debugger().goal = None;
+                writeln!(out, "Stopped after {} frames", ran)?;
+
+                return Ok(self.show_location(out)?)
+            }
+
+            reason = self.cpu.run_frame();
+        }
+
+        self.report(&reason, out)?;
+        Ok(self.show_location(out)?)
+    }
+
+    /// A locked up CPU never stops by itself, so it can't
+    /// run until something happens
+    fn check_fault(&self) -> CommandResult {
+        match self.cpu.fault() {
+            Some(_) => Err(CommandError::from("The CPU locked up, it can only run for a number of frames".to_string())),
+            None => Ok(()),
+        }
+    }
+
+    fn add_breakpoint<W: Write>(&mut self, args: &[&str], out: &mut W) -> CommandResult {
+        let (location, condition) = match args {
+            [location] => (location, None),
+            [location, "if", condition @ ..] if !condition.is_empty() => {
+                (location, Some(condition.join(" ").parse()?))
+            },
+            _ => return Err(usage("break [BANK:]ADDR [if CONDITION]")),
+        };
+
+        let (bank, address) = parse_location(location)?;
+        let breakpoint = Breakpoint { bank, address, condition };
+        let description = describe_breakpoint(&breakpoint);
+
+        let id = self.cpu.debugger().add_breakpoint(breakpoint);
+        writeln!(out, "Breakpoint {} at {}", id, description)?;
+
+        Ok(())
+    }
+
+    fn add_watchpoint<W: Write>(&mut self, args: &[&str], out: &mut W) -> CommandResult {
+        let (range, access) = match args {
+            [range] => (range, Access::Write),
+            [range, "r"] => (range, Access::Read),
+            [range, "w"] => (range, Access::Write),
+            [range, "rw"] => (range, Access::Any),
+            _ => return Err(usage("watch ADDR[-END] [r|w|rw]")),
+        };
+
+        let (from, to) = match range.find('-') {
+            Some(position) => (parse_address(&range[.. position])?, parse_address(&range[position + 1 ..])?),
+            None => (parse_address(range)?, parse_address(range)?),
+        };
+
+        if from > to {
+            return Err(CommandError::from(format!("Invalid range {}", range)))
+        }
+
+        let watchpoint = Watchpoint { from, to, access };
+        let id = self.cpu.debugger().add_watchpoint(watchpoint);
+
+        writeln!(out, "Watchpoint {} on {}", id, describe_watchpoint(&watchpoint))?;
+
+        Ok(())
+    }
+
+    fn delete<W: Write>(&mut self, args: &[&str], out: &mut W) -> CommandResult {
+        match args {
+            [] => {
+                self.cpu.debugger().clear();
+                writeln!(out, "Deleted every breakpoint and watchpoint")?;
+            },
+
+            [id] => {
+                let id = parse_count(id)?;
+
+                if !self.cpu.debugger().remove(id) {
+                    return Err(CommandError::from(format!("There is no breakpoint or watchpoint {}", id)))
+                }
+            },
+
+            _ => return Err(usage("delete [ID]")),
+        }
+
+        Ok(())
+    }
+
+    fn list<W: Write>(&mut self, out: &mut W) -> CommandResult {
+        let debugger = self.cpu.debugger();
+
+        if debugger.breakpoints().is_empty() && debugger.watchpoints().is_empty() {
+            writeln!(out, "No breakpoints or watchpoints")?;
+        }
+
+        for (id, breakpoint) in debugger.breakpoints() {
+            writeln!(out, "{:>3}  break  {}", id, describe_breakpoint(breakpoint))?;
+        }
+
+        for (id, watchpoint) in debugger.watchpoints() {
+            writeln!(out, "{:>3}  watch  {}", id, describe_watchpoint(watchpoint))?;
+        }
+
+        Ok(())
+    }
+
+    fn show_registers<W: Write>(&mut self, out: &mut W) -> CommandResult {
+        let r = *self.cpu.registers();
+
+        let flags: String = ["Z", "N", "H", "C"].iter()
+            .enumerate()
+            .map(|(bit, name)| if r.flags & (0x80 >> bit) != 0 { *name } else { "-" })
+            .collect();
+
+        writeln!(
+            out,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X}",
+            r.a, r.flags, r.b, r.c, r.d, r.e, r.h, r.l
+        )?;
+
+        writeln!(
+            out,
+            "AF:{:04X} BC:{:04X} DE:{:04X} HL:{:04X} SP:{:04X} PC:{:04X}",
+            r.af(), r.bc(), r.de(), r.hl(), r.stack_pointer, r.program_counter
+        )?;
+
+        writeln!(
+            out,
+            "Flags:{} IME:{} Halted:{} Bank:{:02X}",
+            flags,
+            self.cpu.interrupts_enabled() as u8,
+            self.cpu.halted() as u8,
+            self.cpu.rom_bank(0x4000)
+        )?;
+
+        Ok(())
+    }
+
+    fn dump<W: Write>(&mut self, args: &[&str], out: &mut W) -> CommandResult {
+        let (from, length) = match args {
+            [address] => (parse_address(address)?, 64),
+            [address, length] => (parse_address(address)?, parse_count(length)?),
+            _ => return Err(usage("x ADDR [LENGTH]")),
+        };
+
+        let to = (from as usize + length).min(0x10000);
+
+        for line in (from as usize .. to).step_by(16) {
+            let bytes: Vec<u8> = (line .. (line + 16).min(to))
+                .map(|address| self.cpu.peek(address as u16))
+                .collect();
+
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+
+            let text: String = bytes.iter()
+                .map(|&b| if (0x20 .. 0x7F).contains(&b) { b as char } else { '.' })
+                .collect();
+
+            writeln!(out, "{:04X}  {:<47}  |{}|", line, hex.join(" "), text)?;
+        }
+
+        Ok(())
+    }
+
+    fn disassemble<W: Write>(&mut self, args: &[&str], out: &mut W) -> CommandResult {
+        let program_counter = self.cpu.registers().program_counter;
+
+        let (from, count) = match args {
+            [] => (self.context_start(program_counter), DISASSEMBLY_LENGTH as usize),
+            [address] => (parse_address(address)?, DISASSEMBLY_LENGTH as usize),
+            [address, count] => (parse_address(address)?, parse_count(count)?),
+            _ => return Err(usage("dis [ADDR] [COUNT]")),
+        };
+
+        let mut address = from;
+
+        for _ in 0 .. count {
+            address = self.write_instruction(address, out)?;
+        }
+
+        Ok(())
+    }
+
+    fn show_stack<W: Write>(&mut self, args: &[&str], out: &mut W) -> CommandResult {
+        let count = match args {
+            [] => 8,
+            [count] => parse_count(count)?,
+            _ => return Err(usage("stack [COUNT]")),
+        };
+
+        // the whole address space, in words
+        let count = count.min(0x8000);
+
+        let stack_pointer = self.cpu.registers().stack_pointer;
+
+        for index in 0 .. count as u16 {
+            let address = stack_pointer.wrapping_add(index * 2);
+            let value = self.cpu.peek(address) as u16 | (self.cpu.peek(address.wrapping_add(1)) as u16) << 8;
+
+            writeln!(out, "SP+{:02X}  {:04X}  {:04X}", index * 2, address, value)?;
+        }
+
+        Ok(())
+    }
+
+    fn show_io<W: Write>(&mut self, args: &[&str], out: &mut W) -> CommandResult {
+        if args.is_empty() {
+            for row in IO_REGISTERS.chunks(4) {
+                let columns: Vec<String> = row.iter()
+                    .map(|&(name, address)| format!("{:<5} {:04X} {:02X}", name, address, self.cpu.peek(address)))
+                    .collect();
+
+                writeln!(out, "{}", columns.join("   "))?;
+            }
+
+            return Ok(())
+        }
+
+        for name in args {
+            let address = IO_REGISTERS.iter()
+                .find(|(register, _)| register.eq_ignore_ascii_case(name))
+                .map(|&(_, address)| address)
+                .ok_or_else(|| format!("Unknown I/O register {}", name))?;
+
+            let value = self.cpu.peek(address);
+
+            writeln!(out, "{:<5} {:04X} {:02X} {:08b}", name.to_uppercase(), address, value, value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes why the CPU stopped, nothing for a completed step
+    fn report<W: Write>(&mut self, reason: &StopReason, out: &mut W) -> io::Result<()> {
+        match *reason {
+            StopReason::FrameEnd | StopReason::Step => {},
+
+            StopReason::Breakpoint(id) => writeln!(out, "Breakpoint {}", id)?,
+
+            StopReason::Watchpoint { id, address, value, access: Access::Read } => {
+                writeln!(out, "Watchpoint {}: read {:02X} from {:04X}", id, value, address)?
+            },
+
+            StopReason::Watchpoint { id, address, value, .. } => {
+                writeln!(out, "Watchpoint {}: wrote {:02X} to {:04X}", id, value, address)?
+            },
+
+            StopReason::Fault(ref fault) => writeln!(out, "The CPU locked up: {}", fault)?,
+        }
+
+        Ok(())
+    }
+
+    /// Shows the instruction about to be executed
+    fn show_location<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        let program_counter = self.cpu.registers().program_counter;
+        self.write_instruction(program_counter, out)?;
+
+        Ok(())
+    }
+
+    /// Writes an instruction in the listing format, marking the one at
+    /// the program counter, and returns the address of the next one
+    fn write_instruction<W: Write>(&mut self, address: u16, out: &mut W) -> io::Result<u16> {
+        let instruction = self.cpu.disassemble(address);
+        let marker = if address == self.cpu.registers().program_counter { "=>" } else { "  " };
+
+        if let Some(label) = disasm::label(address) {
+            writeln!(out, "   {}:", label)?;
+        }
+
+        let bytes: Vec<String> = instruction.bytes.iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+
+        let location = if address < 0x8000 {
+            format!("{:02X}:{:04X}", self.cpu.rom_bank(address), address)
+        } else {
+            format!("   {:04X}", address)
+        };
+
+        writeln!(out, "{} {}  {:<9} {}", marker, location, bytes.join(" "), instruction)?;
+
+        Ok(instruction.next_address())
+    }
+
+    /// Address to start disassembling a few instructions before
+    /// the program counter
+    ///
+    /// Instructions have different lengths, so this looks for the
+    /// furthest address whose instructions line up with the program
+    /// counter. Data before it can still make it look wrong
+    fn context_start(&mut self, program_counter: u16) -> u16 {
+        for back in (1 ..= CONTEXT_BEFORE * 3).rev() {
+            if back > program_counter {
+                continue
+            }
+
+            let start = program_counter - back;
+            let mut offset = 0;
+            let mut count = 0;
+
+            while offset < back && count < CONTEXT_BEFORE {
+                offset += self.cpu.disassemble(start + offset).length();
+                count += 1;
+            }
+
+            if offset == back {
+                return start
+            }
+        }
+
+        program_counter
+    }
+}
+
+fn usage(syntax: &str) -> CommandError {
+    CommandError::Invalid(format!("Usage: {}", syntax))
+}
+
+fn parse_count(value: &str) -> Result<usize, String> {
+    debugger::parse_number(value, 10)
+        .map(|count| count as usize)
+        .ok_or_else(|| format!("{} is not a valid count", value))
+}
+
+fn describe_breakpoint(breakpoint: &Breakpoint) -> String {
+    let mut description = match breakpoint.bank {
+        Some(bank) => format!("{:02X}:{:04X}", bank, breakpoint.address),
+        None => format!("{:04X}", breakpoint.address),
+    };
+
+    if let Some(condition) = breakpoint.condition {
+        description.push_str(&format!(" if {}", condition));
+    }
+
+    description
+}
+
+fn describe_watchpoint(watchpoint: &Watchpoint) -> String {
+    let access = match watchpoint.access {
+        Access::Read => "reads",
+        Access::Write => "writes",
+        Access::Any => "reads and writes",
+    };
+
+    if watchpoint.from == watchpoint.to {
+        format!("{:04X} ({})", watchpoint.from, access)
+    } else {
+        format!("{:04X}-{:04X} ({})", watchpoint.from, watchpoint.to, access)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+    use frontend::keypad::Key;
+    use model::Model;
+
+    fn console(code: &[u8]) -> Console {
+        Console::new(Z80::from_program(code))
+    }
+
+    fn run(console: &mut Console, commands: &str) -> String {
+        let mut out = Vec::new();
+        console.run(commands.as_bytes(), &mut out).unwrap();
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn it_saves_the_battery_ram_when_quitting() {
+        // LD A, 0x0A; LD (0x0000), A; LD A, 0x42; LD (0xA000), A
+        let code = [0x3E, 0x0A, 0xEA, 0x00, 0x00, 0x3E, 0x42, 0xEA, 0x00, 0xA0];
+
+        let mut data = vec![0; 0x8000];
+        data[0x100 .. 0x100 + code.len()].copy_from_slice(&code);
+        data[0x147] = 0x03;
+        data[0x149] = 0x02;
+
+        let rom_file = env::temp_dir().join("safeboy-console-save-test.gb");
+        let save_file = rom_file.with_extension("sav");
+        fs::write(&rom_file, data).unwrap();
+        let _ = fs::remove_file(&save_file);
+
+        let mut console = Console::new(Z80::new(rom_file.to_str().unwrap(), Model::DMG).unwrap());
+        run(&mut console, "step 4\nquit\n");
+
+        let save = fs::read(&save_file).unwrap();
+        fs::remove_file(&rom_file).unwrap();
+        fs::remove_file(&save_file).unwrap();
+
+        assert_eq!(save[0], 0x42);
+    }
+
+    #[test]
+    fn it_stops_on_breakpoints_and_watchpoints() {
+        // INC A, LD (0xC000), A, JP 0x0100
+        let mut console = console(&[0x3C, 0xEA, 0x00, 0xC0, 0xC3, 0x00, 0x01]);
+
+        let output = run(&mut console, "break 0101 if A == 4\ncontinue\nregs\ndelete 1\nwatch C000\ncontinue\nquit\n");
+
+        assert!(output.contains("Breakpoint 1 at 0101 if A == $4"), "{}", output);
+        assert!(output.contains("Breakpoint 1\n=> 00:0101  EA 00 C0  LD ($C000), A"), "{}", output);
+        assert!(output.contains("A:04 F:"), "{}", output);
+        assert!(output.contains("Watchpoint 2: wrote 04 to C000\n"), "{}", output);
+        assert!(output.contains("=> 00:0104  C3 00 01  JP $0100"), "{}", output);
+        assert_eq!(console.cpu.peek(0xC000), 4);
+    }
+
+    #[test]
+    fn it_gives_up_on_steps_that_never_complete() {
+        // JR -2
+        let mut console = console(&[0x18, 0xFE]);
+
+        let output = run(&mut console, "until 0200
+");
+        assert!(output.contains("Stopped after 600 frames"), "{}", output);
+        assert!(console.cpu.debugger().goal.is_none());
+
+        // an illegal opcode locks up the CPU
+        console.cpu.poke(0xC000, 0xFD);
+        console.cpu.registers_mut().program_counter = 0xC000;
+
+        let output = run(&mut console, "step
+finish
+until 0100
+next
+");
+        assert!(output.contains("The CPU locked up: "), "{}", output);
+        assert_eq!(output.matches("it can only run for a number of frames").count(), 3, "{}", output);
+    }
+
+    #[test]
+    fn it_steps_and_repeats_the_last_command() {
+        // NOP, NOP, NOP
+        let mut console = console(&[0x00, 0x00, 0x00]);
+
+        let output = run(&mut console, "step\n\n");
+
+        assert!(output.contains("=> 00:0102"), "{}", output);
+        assert_eq!(console.cpu.registers().program_counter, 0x0102);
+    }
+
+    #[test]
+    fn it_shows_memory_and_io_registers() {
+        let mut console = console(&[]);
+        console.cpu.poke(0xC000, 0x41);
+
+        let output = run(&mut console, "x C000 2\nio lcdc\nio NOPE\nstack 1\nfly\n");
+
+        assert!(output.contains("C000  41 00"), "{}", output);
+        assert!(output.contains("|A.|"), "{}", output);
+        assert!(output.contains("LCDC  FF40 91 10010001"), "{}", output);
+        assert!(output.contains("Unknown I/O register NOPE"), "{}", output);
+        assert!(output.contains("SP+00  FFFE"), "{}", output);
+        assert!(output.contains("Unknown command fly"), "{}", output);
+    }
+
+    #[test]
+    fn it_shows_p1_with_no_row_selected() {
+        let mut console = console(&[]);
+        console.cpu.poke(0xFF00, 0x30);
+        console.cpu.key_down(Key::A);
+
+        let output = run(&mut console, "io p1
+x FF00 1
+");
+
+        assert!(output.contains("P1    FF00 FF 11111111"), "{}", output);
+        assert!(output.contains("FF00  FF"), "{}", output);
+
+        console.cpu.poke(0xFF00, 0x10);
+        assert_eq!(console.cpu.peek(0xFF00), 0xDE);
+    }
+
+    #[test]
+    fn it_disassembles_around_the_program_counter() {
+        // LD A, 0x01, LD B, 0x02, NOP
+        let mut console = console(&[0x3E, 0x01, 0x06, 0x02, 0x00]);
+
+        run(&mut console, "step 2\n");
+        let output = run(&mut console, "dis\n");
+
+        assert!(output.contains("   00:0100  3E 01     LD A, $01\n"), "{}", output);
+        assert!(output.contains("=> 00:0104  00        NOP\n"), "{}", output);
+    }
+}
@@ -1,6 +1,16 @@
 use cpu::z80::Z80;
+use cpu::fault::CpuFault;
+use cpu::trace::Tracer;
+use cpu::debugger::{Debugger, StopReason};
 use display::display::{Display, Event, EventType};
 use frontend::keypad::Key;
+use frontend::gdb::GdbServer;
+use memory::mbc::{ImageSource, RtcClock};
+use error::SafeboyError;
+use model::Model;
+
+/// How many frames between battery RAM saves, around 5 seconds
+const SAVE_INTERVAL_FRAMES: u32 = 300;
 
 /// GameBoy
 ///
@@ -8,7 +18,24 @@ use frontend::keypad::Key;
 /// It contains the CPU and the OpenGL display
 pub struct Gameboy {
     cpu: Z80,
-    display: Display
+    display: Display,
+
+    /// Last reported strength of the cartridge rumble motor
+    rumble: f32,
+
+    /// Called with the rumble strength, from 0.0 to 1.0, when
+    /// it changes between frames. By default it does nothing
+    rumble_handler: Box<dyn FnMut(f32)>,
+
+    /// Whether the CPU fault was already reported
+    fault_reported: bool,
+
+    /// Called once when the CPU locks up (usually, the
+    /// game crashed), by default it just prints it
+    fault_handler: Box<dyn FnMut(&CpuFault)>,
+
+    /// GDB server controlling the CPU, if any
+    gdb: Option<GdbServer>,
 }
 
 /// Basic signals
@@ -24,14 +51,116 @@ enum EventSignal {
 impl Gameboy {
     /// Creates a new GameBoy instance
     ///
-    /// We need the GameBoy (.gb) file that will be run
-    pub fn new(rom_file: &str) -> Gameboy {
+    /// We need the GameBoy (.gb) file that will be run,
+    /// and the model it runs on
+    pub fn new(rom_file: &str, model: Model) -> Result<Gameboy, SafeboyError> {
+        Ok(Gameboy::with_cpu(Z80::new(rom_file, model)?))
+    }
+
+    /// Creates a new GameBoy instance from ROM data
+    ///
+    /// Useful when the game doesn't come from a file, battery
+    /// backed games will not be saved in this case
+    pub fn from_rom(data: Vec<u8>, model: Model) -> Result<Gameboy, SafeboyError> {
+        Ok(Gameboy::with_cpu(Z80::from_rom(data, model)?))
+    }
+
+    fn with_cpu(cpu: Z80) -> Gameboy {
         Gameboy {
-            cpu: Z80::new(rom_file),
+            cpu,
             display: Display::new(),
+            rumble: 0.0,
+            rumble_handler: Box::new(|_| ()),
+            fault_reported: false,
+            fault_handler: Box::new(|fault| {
+                eprintln!("The CPU locked up: {}", fault);
+            }),
+            gdb: None,
         }
     }
 
+    /// Runs the boot ROM before the game
+    ///
+    /// Without it, the machine starts at 0x0100 in the
+    /// state the boot ROM would have left it
+    pub fn load_boot_rom(&mut self, boot_rom_file: &str) -> Result<(), SafeboyError> {
+        self.cpu.load_boot_rom(boot_rom_file)
+    }
+
+    /// Sets the rumble handler
+    ///
+    /// Hosts can use this to forward the cartridge rumble
+    /// motor to a gamepad, for example. Games pulse the motor
+    /// to set its strength, so the handler gets the share of
+    /// the last frame it was on
+    pub fn on_rumble<F: FnMut(f32) + 'static>(&mut self, handler: F) {
+        self.rumble_handler = Box::new(handler);
+    }
+
+    /// Sets the CPU fault handler
+    ///
+    /// The game keeps running (with a locked up CPU) after
+    /// the handler is called, like on real hardware
+    pub fn on_fault<F: FnMut(&CpuFault) + 'static>(&mut self, handler: F) {
+        self.fault_handler = Box::new(handler);
+    }
+
+    /// Traces every instruction the CPU executes
+    ///
+    /// See `Tracer` for the format and the options
+    pub fn trace(&mut self, tracer: Tracer) {
+        self.cpu.trace(tracer);
+    }
+
+    /// CPU fault, if the CPU locked up
+    pub fn fault(&self) -> Option<&CpuFault> {
+        self.cpu.fault()
+    }
+
+    /// Lets a GDB client control the CPU
+    ///
+    /// The game waits for the client to connect and continue,
+    /// see `GdbServer`
+    pub fn serve_gdb(&mut self, server: GdbServer) {
+        self.gdb = Some(server);
+    }
+
+    /// Breakpoints and watchpoints checked by the run and step calls
+    pub fn debugger(&mut self) -> &mut Debugger {
+        self.cpu.debugger()
+    }
+
+    /// Runs until the end of the frame or until something stops it
+    ///
+    /// The frame is drawn to the display either way, so a stopped
+    /// game shows what it has drawn so far
+    pub fn run_frame(&mut self) -> StopReason {
+        let reason = self.cpu.run_frame();
+        self.display.draw(self.cpu.get_gpu_pixels());
+
+        reason
+    }
+
+    /// Executes a single instruction
+    pub fn step_instruction(&mut self) -> StopReason {
+        self.cpu.step_instruction()
+    }
+
+    /// Executes an instruction, running calls until they return
+    pub fn step_over(&mut self) -> StopReason {
+        self.cpu.step_over()
+    }
+
+    /// Runs until the current function returns
+    pub fn step_out(&mut self) -> StopReason {
+        self.cpu.step_out()
+    }
+
+    /// Runs until the program counter gets to an address
+    pub fn run_to(&mut self, bank: Option<usize>, address: u16) -> StopReason {
+        self.cpu.run_to(bank, address)
+    }
+
     /// Runs the game
     ///
     /// This will enter the main loop and process
@@ -40,16 +169,80 @@ impl Gameboy {
     ///
     /// It will also poll for events (keyboard) and translate
     /// them into GameBoy-valid keypad events
+    ///
+    /// The cartridge RAM is saved periodically and when closing,
+    /// so battery backed games keep their progress
     pub fn run(&mut self) -> () {
         self.display.initialize();
 
+        let mut frames = 0;
+
         loop {
             if self.poll_events() == EventSignal::Close {
                 break;
             }
 
-            self.cpu.step();
+            let result = match self.gdb {
+                Some(ref mut server) => server.step(&mut self.cpu),
+                None => self.cpu.step(),
+            };
+
+            if let Err(fault) = result {
+                self.report_fault(&fault);
+            }
+
             self.display.draw(self.cpu.get_gpu_pixels());
+            self.check_rumble();
+
+            frames += 1;
+
+            if frames >= SAVE_INTERVAL_FRAMES {
+                self.cpu.save_ram();
+                frames = 0;
+            }
+        }
+
+        self.cpu.save_ram();
+    }
+
+    /// Tilts the cartridge
+    ///
+    /// This is the input for accelerometer cartridges (MBC7),
+    /// values are in g: x is positive when tilting right and
+    /// y when tilting down
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.cpu.set_tilt(x, y);
+    }
+
+    /// Sets the image seen by the camera sensor
+    ///
+    /// Only used by the Pocket Camera, see `FileImage` and
+    /// `CallbackImage` in the MBC module
+    pub fn set_image_source(&mut self, source: Box<dyn ImageSource>) {
+        self.cpu.set_image_source(source);
+    }
+
+    /// Sets what drives the cartridge clock
+    ///
+    /// Clocks (MBC3 and HuC3) follow the host wall-clock by
+    /// default, counting emulated cycles makes them deterministic
+    pub fn set_rtc_clock(&mut self, rtc_clock: RtcClock) {
+        self.cpu.set_rtc_clock(rtc_clock);
+    }
+
+    fn report_fault(&mut self, fault: &CpuFault) {
+        if !self.fault_reported {
+            self.fault_reported = true;
+            (self.fault_handler)(fault);
+        }
+    }
+
+    fn check_rumble(&mut self) {
+        let rumble = self.cpu.rumble();
+
+        if rumble != self.rumble {
+            self.rumble = rumble;
+            (self.rumble_handler)(rumble);
         }
     }
 
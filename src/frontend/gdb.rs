@@ -0,0 +1,738 @@
+use std::io::{self, Read, Write};
+use std::str;
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use cpu::z80::Z80;
+use cpu::fault::CpuFault;
+use cpu::debugger::{Access, Breakpoint, StopReason, Watchpoint};
+use error::SafeboyError;
+
+/// Signal reported when the CPU stops on a breakpoint or a step
+const SIGTRAP: u8 = 5;
+
+/// Signal reported when the client interrupts the CPU (Ctrl-C)
+const SIGINT: u8 = 2;
+
+/// Signal reported when the CPU locks up
+const SIGILL: u8 = 4;
+
+/// Biggest packet we accept, in bytes
+const PACKET_SIZE: usize = 0x4000;
+
+/// Registers as the client sees them, all 16 bits little endian:
+/// AF, BC, DE, HL, SP and PC
+const REGISTER_COUNT: usize = 6;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.z80.cpu">
+    <reg name="af" bitsize="16" type="int"/>
+    <reg name="bc" bitsize="16" type="int"/>
+    <reg name="de" bitsize="16" type="int"/>
+    <reg name="hl" bitsize="16" type="int"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// GDB remote serial protocol server
+///
+/// Listens on localhost and lets a GDB client (or any other
+/// client speaking the protocol) control the CPU: registers,
+/// memory, breakpoints, watchpoints, stepping and continuing.
+/// Only one client is served at a time. The CPU doesn't run
+/// until the first client connects and continues, and it runs
+/// freely again once the client detaches
+///
+/// Both software and hardware breakpoints are kept by the
+/// debugger, the ROM is never patched
+pub struct GdbServer {
+    listener: TcpListener,
+    client: Option<Client>,
+
+    /// Whether a client detached, leaving the CPU running
+    detached: bool,
+}
+
+impl GdbServer {
+    /// Listens on a port of localhost, 0 picks any free port
+    pub fn bind(port: u16) -> Result<GdbServer, SafeboyError> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+            .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+            .map_err(|e| SafeboyError::GdbServer(port, e))?;
+
+        Ok(GdbServer {
+            listener,
+            client: None,
+            detached: false,
+        })
+    }
+
+    /// Port the server is listening on
+    pub fn port(&self) -> u16 {
+        self.listener.local_addr().map(|address| address.port()).unwrap_or(0)
+    }
+
+    /// Whether a client is connected
+    pub fn connected(&self) -> bool {
+        self.client.is_some()
+    }
+
+    /// Serves the client, and runs a frame if the client let the CPU run
+    ///
+    /// This never blocks waiting for the client, so it can be called
+    /// from the main loop in place of `Z80::step`. Faults are reported
+    /// to the client, and returned like `Z80::step` does
+    pub fn step(&mut self, cpu: &mut Z80) -> Result<(), CpuFault> {
+        if self.client.is_none() {
+            self.accept();
+        }
+
+        let result = match self.client {
+            Some(ref mut client) => client.serve(cpu),
+            None if self.detached => return cpu.step(),
+            None => return Ok(()),
+        };
+
+        if let Err(e) = result {
+            if e.kind() != io::ErrorKind::UnexpectedEof {
+                eprintln!("GDB connection lost: {}", e);
+            }
+
+            self.disconnect(cpu);
+        }
+
+        match cpu.fault() {
+            Some(fault) => Err(*fault),
+            None => Ok(()),
+        }
+    }
+
+    fn accept(&mut self) {
+        let stream = match self.listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(e) => {
+                eprintln!("Could not accept the GDB connection: {}", e);
+                return
+            },
+        };
+
+        match stream.set_nonblocking(true).and_then(|_| stream.set_nodelay(true)) {
+            Ok(()) => {
+                eprintln!("GDB connected from {}", stream.peer_addr().map(|a| a.to_string()).unwrap_or_default());
+                self.client = Some(Client::new(stream));
+            },
+            Err(e) => eprintln!("Could not accept the GDB connection: {}", e),
+        }
+    }
+
+    /// Drops the client and its breakpoints, the game keeps running
+    fn disconnect(&mut self, cpu: &mut Z80) {
+        if let Some(client) = self.client.take() {
+            for point in client.points {
+                cpu.debugger().remove(point.id);
+            }
+        }
+
+        self.detached = true;
+        eprintln!("GDB disconnected");
+    }
+}
+
+/// Breakpoint or watchpoint set by the client
+struct Point {
+    /// Type in the Z packet: 0 and 1 are breakpoints, 2 to 4 watchpoints
+    kind: u8,
+    address: u16,
+    length: u16,
+
+    /// Id in the debugger
+    id: usize,
+}
+
+/// Connected client
+struct Client {
+    stream: TcpStream,
+
+    /// Received bytes not handled yet
+    input: Vec<u8>,
+
+    /// Whether packets are acknowledged, until the client
+    /// asks for no-ack mode
+    acks: bool,
+
+    /// Whether the CPU is running, until something stops it
+    running: bool,
+
+    /// Why the CPU stopped last, the reply to `?`
+    stop: String,
+
+    points: Vec<Point>,
+}
+
+impl Client {
+    fn new(stream: TcpStream) -> Client {
+        Client {
+            stream,
+            input: Vec::new(),
+            acks: true,
+            running: false,
+            stop: format!("S{:02X}", SIGTRAP),
+            points: Vec::new(),
+        }
+    }
+
+    /// Handles everything the client sent, then runs a frame if needed
+    fn serve(&mut self, cpu: &mut Z80) -> io::Result<()> {
+        self.receive()?;
+
+        while let Some(packet) = self.next_packet()? {
+            self.handle(&packet, cpu)?;
+        }
+
+        if self.running {
+            match cpu.run_frame() {
+                StopReason::FrameEnd => {},
+                reason => self.stopped(&reason)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads whatever the client sent, without blocking
+    fn receive(&mut self) -> io::Result<()> {
+        let mut buffer = [0; 1024];
+
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed")),
+                Ok(size) => self.input.extend_from_slice(&buffer[.. size]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+
+            if self.input.len() > PACKET_SIZE * 2 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "packet too big"))
+            }
+        }
+    }
+
+    /// Takes the next complete packet from the input
+    ///
+    /// Acknowledgements are skipped, and an interrupt (Ctrl-C
+    /// sends a single 0x03 byte) stops the CPU right away
+    fn next_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.input.first() {
+                None => return Ok(None),
+
+                Some(&b'$') => {},
+
+                Some(&0x03) => {
+                    self.input.remove(0);
+
+                    if self.running {
+                        self.running = false;
+                        self.stop = format!("T{:02X}", SIGINT);
+                        let stop = self.stop.clone();
+                        self.send(&stop)?;
+                    }
+
+                    continue
+                },
+
+                // acks, and noise between packets
+                Some(_) => {
+                    self.input.remove(0);
+                    continue
+                },
+            }
+
+            // $data#checksum
+            let end = match self.input.iter().position(|&b| b == b'#') {
+                Some(end) if self.input.len() >= end + 3 => end,
+                _ => return Ok(None),
+            };
+
+            let packet: Vec<u8> = self.input.drain(.. end + 3).collect();
+            let data = &packet[1 .. end];
+
+            let checksum = str::from_utf8(&packet[end + 1 ..]).ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+
+            if checksum != Some(checksum_of(data)) {
+                if self.acks {
+                    self.stream_write(b"-")?;
+                }
+
+                continue
+            }
+
+            if self.acks {
+                self.stream_write(b"+")?;
+            }
+
+            return Ok(Some(String::from_utf8_lossy(data).into_owned()))
+        }
+    }
+
+    fn handle(&mut self, packet: &str, cpu: &mut Z80) -> io::Result<()> {
+        // a new command while running (other than an interrupt)
+        // is not expected, the protocol has the client wait
+        if self.running {
+            return Ok(())
+        }
+
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+
+        let reply = match command {
+            "?" => self.stop.clone(),
+            "g" => read_registers(cpu),
+            "G" => write_registers(cpu, args),
+            "p" => read_register(cpu, args),
+            "P" => write_register(cpu, args),
+            "m" => read_memory(cpu, args),
+            "M" => write_memory(cpu, args),
+            "Z" => self.insert_point(cpu, args),
+            "z" => self.remove_point(cpu, args),
+            "H" => "OK".to_string(),
+            "T" => "OK".to_string(),
+
+            "c" => {
+                if let Some(address) = parse_hex(args) {
+                    cpu.registers_mut().program_counter = address;
+                }
+
+                self.running = true;
+                return Ok(())
+            },
+
+            "s" => {
+                if let Some(address) = parse_hex(args) {
+                    cpu.registers_mut().program_counter = address;
+                }
+
+                let reason = cpu.step_instruction();
+                return self.stopped(&reason)
+            },
+
+            "D" => {
+                self.send("OK")?;
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "detached"))
+            },
+
+            "k" => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "killed")),
+
+            "q" | "Q" | "v" => self.query(packet),
+
+            _ => String::new(),
+        };
+
+        self.send(&reply)
+    }
+
+    /// General queries and settings, packets starting with q, Q or v
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!("PacketSize={:X};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE)
+        }
+
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return read_target_xml(args)
+        }
+
+        match packet {
+            "QStartNoAckMode" => {
+                self.acks = false;
+                "OK".to_string()
+            },
+
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            "qSymbol::" => "OK".to_string(),
+
+            // vCont and everything else is not supported, the client
+            // falls back to the basic packets
+            _ => String::new(),
+        }
+    }
+
+    /// Reports why the CPU stopped
+    fn stopped(&mut self, reason: &StopReason) -> io::Result<()> {
+        self.running = false;
+
+        self.stop = match *reason {
+            StopReason::FrameEnd | StopReason::Step | StopReason::Breakpoint(_) => {
+                format!("T{:02X}", SIGTRAP)
+            },
+
+            StopReason::Watchpoint { id, address, .. } => {
+                let kind = match self.points.iter().find(|p| p.id == id).map(|p| p.kind) {
+                    Some(3) => "rwatch",
+                    Some(4) => "awatch",
+                    _ => "watch",
+                };
+
+                format!("T{:02X}{}:{:04X};", SIGTRAP, kind, address)
+            },
+
+            StopReason::Fault(_) => format!("T{:02X}", SIGILL),
+        };
+
+        let stop = self.stop.clone();
+        self.send(&stop)
+    }
+
+    /// Z packet: type,address,kind (the kind is the length for watchpoints)
+    fn insert_point(&mut self, cpu: &mut Z80, args: &str) -> String {
+        let (kind, address, length) = match parse_point(args) {
+            Some(point) => point,
+            None => return "E01".to_string(),
+        };
+
+        if self.points.iter().any(|p| p.kind == kind && p.address == address && p.length == length) {
+            return "OK".to_string()
+        }
+
+        let last = address.saturating_add(length.max(1) - 1);
+
+        let id = match kind {
+            0 | 1 => cpu.debugger().add_breakpoint(Breakpoint::new(address)),
+            2 => cpu.debugger().add_watchpoint(Watchpoint { from: address, to: last, access: Access::Write }),
+            3 => cpu.debugger().add_watchpoint(Watchpoint { from: address, to: last, access: Access::Read }),
+            4 => cpu.debugger().add_watchpoint(Watchpoint { from: address, to: last, access: Access::Any }),
+            _ => return String::new(),
+        };
+
+        self.points.push(Point { kind, address, length, id });
+
+        "OK".to_string()
+    }
+
+    fn remove_point(&mut self, cpu: &mut Z80, args: &str) -> String {
+        let (kind, address, length) = match parse_point(args) {
+            Some(point) => point,
+            None => return "E01".to_string(),
+        };
+
+        if let Some(index) = self.points.iter().position(|p| p.kind == kind && p.address == address && p.length == length) {
+            let point = self.points.remove(index);
+            cpu.debugger().remove(point.id);
+        }
+
+        "OK".to_string()
+    }
+
+    /// Sends a packet, as $data#checksum
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.stream_write(packet.as_bytes())
+    }
+
+    /// Writes everything, the stream doesn't block so this waits
+    /// when the client is slow reading
+    fn stream_write(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            match self.stream.write(data) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "closed")),
+                Ok(size) => data = &data[size ..],
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn parse_hex(value: &str) -> Option<u16> {
+    u16::from_str_radix(value, 16).ok()
+}
+
+/// Parses type,address,kind from a Z or z packet
+fn parse_point(args: &str) -> Option<(u8, u16, u16)> {
+    let mut parts = args.split(',');
+
+    let kind = parts.next()?.parse().ok()?;
+    let address = parse_hex(parts.next()?)?;
+    let length = parse_hex(parts.next()?.split(';').next()?)?;
+
+    Some((kind, address, length))
+}
+
+/// Parses address,length from a memory packet
+fn parse_range(args: &str) -> Option<(u16, usize)> {
+    let mut parts = args.split(',');
+
+    let address = parse_hex(parts.next()?)?;
+    let length = usize::from_str_radix(parts.next()?, 16).ok()?;
+
+    Some((address, length))
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_bytes(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() & 1 != 0 {
+        return None
+    }
+
+    (0 .. hex.len()).step_by(2)
+        .map(|i| hex.get(i .. i + 2).and_then(|digits| u8::from_str_radix(digits, 16).ok()))
+        .collect()
+}
+
+fn register_values(cpu: &Z80) -> [u16; REGISTER_COUNT] {
+    let r = cpu.registers();
+    [r.af(), r.bc(), r.de(), r.hl(), r.stack_pointer, r.program_counter]
+}
+
+fn set_register(cpu: &mut Z80, index: usize, value: u16) {
+    let r = cpu.registers_mut();
+
+    match index {
+        0 => r.set_af(value),
+        1 => r.set_bc(value),
+        2 => r.set_de(value),
+        3 => r.set_hl(value),
+        4 => r.stack_pointer = value,
+        _ => r.program_counter = value,
+    }
+}
+
+fn read_registers(cpu: &Z80) -> String {
+    let bytes: Vec<u8> = register_values(cpu).iter()
+        .flat_map(|value| vec![*value as u8, (*value >> 8) as u8])
+        .collect();
+
+    hex_bytes(&bytes)
+}
+
+fn write_registers(cpu: &mut Z80, args: &str) -> String {
+    let bytes = match parse_bytes(args) {
+        Some(ref bytes) if bytes.len() >= REGISTER_COUNT * 2 => bytes.clone(),
+        _ => return "E01".to_string(),
+    };
+
+    for index in 0 .. REGISTER_COUNT {
+        set_register(cpu, index, bytes[index * 2] as u16 | (bytes[index * 2 + 1] as u16) << 8);
+    }
+
+    "OK".to_string()
+}
+
+fn read_register(cpu: &Z80, args: &str) -> String {
+    match usize::from_str_radix(args, 16) {
+        Ok(index) if index < REGISTER_COUNT => {
+            let value = register_values(cpu)[index];
+            hex_bytes(&[value as u8, (value >> 8) as u8])
+        },
+
+        _ => "E01".to_string(),
+    }
+}
+
+/// P packet: index=value, the value in target byte order
+fn write_register(cpu: &mut Z80, args: &str) -> String {
+    let mut parts = args.split('=');
+
+    let index = parts.next().and_then(|index| usize::from_str_radix(index, 16).ok());
+    let bytes = parts.next().and_then(parse_bytes);
+
+    match (index, bytes) {
+        (Some(index), Some(ref bytes)) if index < REGISTER_COUNT && bytes.len() == 2 => {
+            set_register(cpu, index, bytes[0] as u16 | (bytes[1] as u16) << 8);
+            "OK".to_string()
+        },
+
+        _ => "E01".to_string(),
+    }
+}
+
+fn read_memory(cpu: &mut Z80, args: &str) -> String {
+    let (address, length) = match parse_range(args) {
+        Some((address, length)) if length <= PACKET_SIZE / 2 => (address, length),
+        _ => return "E01".to_string(),
+    };
+
+    let bytes: Vec<u8> = (0 .. length)
+        .map(|offset| cpu.peek(address.wrapping_add(offset as u16)))
+        .collect();
+
+    hex_bytes(&bytes)
+}
+
+/// M packet: address,length:bytes
+fn write_memory(cpu: &mut Z80, args: &str) -> String {
+    let mut parts = args.split(':');
+
+    let range = parts.next().and_then(parse_range);
+    let bytes = parts.next().and_then(parse_bytes);
+
+    match (range, bytes) {
+        (Some((address, length)), Some(bytes)) if bytes.len() == length => {
+            for (offset, value) in bytes.into_iter().enumerate() {
+                cpu.poke(address.wrapping_add(offset as u16), value);
+            }
+
+            "OK".to_string()
+        },
+
+        _ => "E01".to_string(),
+    }
+}
+
+/// Reads a chunk of the target description, offset,length
+fn read_target_xml(args: &str) -> String {
+    let mut parts = args.split(',');
+
+    let offset = parts.next().and_then(|offset| usize::from_str_radix(offset, 16).ok());
+    let length = parts.next().and_then(|length| usize::from_str_radix(length, 16).ok());
+
+    match (offset, length) {
+        (Some(offset), Some(length)) if offset <= TARGET_XML.len() => {
+            let end = (offset + length).min(TARGET_XML.len());
+            let marker = if end == TARGET_XML.len() { "l" } else { "m" };
+
+            format!("{}{}", marker, &TARGET_XML[offset .. end])
+        },
+
+        _ => "E01".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scripted client, talking to the server from the same thread
+    struct TestClient {
+        stream: TcpStream,
+        server: GdbServer,
+        cpu: Z80,
+    }
+
+    impl TestClient {
+        fn new(code: &[u8]) -> TestClient {
+            let server = GdbServer::bind(0).unwrap();
+            let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, server.port())).unwrap();
+            stream.set_nonblocking(true).unwrap();
+
+            TestClient {
+                stream,
+                server,
+                cpu: Z80::from_program(code),
+            }
+        }
+
+        fn write(&mut self, data: &[u8]) {
+            self.stream.write_all(data).unwrap();
+        }
+
+        /// Runs the server until it replies with a packet
+        fn reply(&mut self) -> String {
+            let mut received = Vec::new();
+            let mut buffer = [0; 1024];
+
+            for _ in 0 .. 1000 {
+                self.server.step(&mut self.cpu).unwrap();
+
+                if let Ok(size) = self.stream.read(&mut buffer) {
+                    received.extend_from_slice(&buffer[.. size]);
+                }
+
+                let text = String::from_utf8_lossy(&received).into_owned();
+                let start = text.find('$');
+
+                if let Some(end) = text.find('#') {
+                    if text.len() >= end + 3 {
+                        let data = &text[start.unwrap() + 1 .. end];
+                        assert_eq!(&text[end + 1 .. end + 3], format!("{:02x}", checksum_of(data.as_bytes())));
+
+                        return data.to_string()
+                    }
+                }
+
+                thread::sleep(Duration::from_millis(1));
+            }
+
+            panic!("no reply, got {:?}", String::from_utf8_lossy(&received))
+        }
+
+        fn request(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+            self.write(packet.as_bytes());
+            self.reply()
+        }
+    }
+
+    #[test]
+    fn it_reads_and_writes_registers_and_memory() {
+        let mut client = TestClient::new(&[]);
+
+        assert_eq!(client.request("qSupported:swbreak+"), "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+");
+        assert_eq!(client.request("?"), "S05");
+
+        // AF=0180 BC=0013 DE=00D8 HL=014D SP=FFFE PC=0100, the
+        // header checksum is 0 so H and C are not set
+        assert_eq!(client.request("g"), "80011300d8004d01feff0001");
+        assert_eq!(client.request("P5=5001"), "OK");
+        assert_eq!(client.request("p5"), "5001");
+        assert_eq!(client.cpu.registers().program_counter, 0x0150);
+
+        assert_eq!(client.request("Mc000,3:123456"), "OK");
+        assert_eq!(client.request("mc000,4"), "12345600");
+        assert_eq!(client.request("mc000"), "E01");
+
+        assert!(client.request("qXfer:features:read:target.xml:0,1000").starts_with("l<?xml"));
+    }
+
+    #[test]
+    fn it_steps_continues_and_stops_on_breakpoints() {
+        // INC A, LD (0xC000), A, JP 0x0100
+        let mut client = TestClient::new(&[0x3C, 0xEA, 0x00, 0xC0, 0xC3, 0x00, 0x01]);
+
+        assert_eq!(client.request("s"), "T05");
+        assert_eq!(client.cpu.registers().program_counter, 0x0101);
+
+        assert_eq!(client.request("Z0,104,1"), "OK");
+        assert_eq!(client.request("c"), "T05");
+        assert_eq!(client.cpu.registers().program_counter, 0x0104);
+
+        assert_eq!(client.request("z0,104,1"), "OK");
+        assert_eq!(client.request("Z2,c000,1"), "OK");
+        assert_eq!(client.request("c"), "T05watch:C000;");
+        assert_eq!(client.request("z2,c000,1"), "OK");
+
+        // runs forever until interrupted
+        client.write(b"$c#63");
+        client.write(&[0x03]);
+        assert_eq!(client.reply(), "T02");
+    }
+
+    #[test]
+    fn it_rejects_bad_checksums() {
+        let mut client = TestClient::new(&[]);
+
+        client.write(b"$g#00");
+        client.write(b"$?#3f");
+
+        assert_eq!(client.reply(), "S05");
+    }
+}
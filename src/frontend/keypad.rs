@@ -72,6 +72,27 @@ impl Keypad {
         }
     }
 
+    /// Joypad lines (P10-P13)
+    ///
+    /// A line goes low when a key of any of the selected
+    /// rows is pressed, this is what wakes the CPU from STOP
+    pub fn lines(&self) -> u8 {
+        match self.column {
+            0x00 => self.keys[0] & self.keys[1],
+            0x10 => self.keys[0],
+            0x20 => self.keys[1],
+            _ => 0x0F,
+        }
+    }
+
+    /// P1 register, for any column
+    ///
+    /// Unlike `read_byte`, it doesn't panic with no row selected,
+    /// debuggers use it to show the register as the hardware has it
+    pub fn peek(&self) -> u8 {
+        0xC0 | self.column | self.lines()
+    }
+
     /// Write the column
     ///
     /// This is the only keypad write operation, to change
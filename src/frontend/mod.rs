@@ -1,2 +1,4 @@
 pub mod gameboy;
-pub mod keypad;
\ No newline at end of file
+pub mod keypad;
+pub mod console;
+pub mod gdb;
@@ -2,6 +2,12 @@
 /// Video Ram size, 16 kb
 const VIDEO_RAM_SIZE: usize = 0x8000;
 
+/// Video Ram bank size, 8kb (the CGB has two banks)
+const VIDEO_RAM_BANK_SIZE: usize = 0x2000;
+
+/// Color palette RAM size, 8 palettes of 4 colors (2 bytes each)
+const PALETTE_RAM_SIZE: usize = 0x40;
+
 /// Object Attribute Memory size, 160 bytes (4 bits per sprite at 40 sprites)
 const VIDEO_OBJECT_ATTRIBUTE_MEMORY_SIZE: usize = 0xA0;
 
@@ -12,6 +18,9 @@ const HEIGHT: usize = 144;
 enum PrioType {
     Color0,
     Normal,
+
+    /// Background tile drawn over sprites (CGB attribute bit 7)
+    Priority,
 }
 
 #[derive(PartialEq, Copy, Clone)]
@@ -185,6 +194,32 @@ pub struct GPU {
     /// * 9800-9FFF -> Background and Window raw_pixels (used indistinctly)
     video_ram: [u8; VIDEO_RAM_SIZE],
 
+    /// Video RAM bank (VBK)
+    ///
+    /// The CGB has a second VRAM bank, with more tile data
+    /// and the attributes for each tile in the BG maps
+    video_ram_bank: usize,
+
+    /// Color mode
+    ///
+    /// Whether the GPU runs as a GameBoy Color one, using
+    /// both VRAM banks and the color palettes
+    cgb_mode: bool,
+
+    /// Background / OBJ color palette RAM
+    ///
+    /// 8 palettes of 4 colors each, with colors stored
+    /// as 15 bit RGB values (little endian, 5 bits per component)
+    bg_color_palettes: [u8; PALETTE_RAM_SIZE],
+    obj_color_palettes: [u8; PALETTE_RAM_SIZE],
+
+    /// Background / OBJ color palette index (BCPS / OCPS)
+    ///
+    /// Bits 0-5 select the byte in the palette RAM, and
+    /// bit 7 increments the index after each write
+    bg_color_palette_index: u8,
+    obj_color_palette_index: u8,
+
     /// Object Attribute Memory (OAM)
     ///
     /// This is where raw_pixels about the sprites is stored. It contains
@@ -194,6 +229,10 @@ pub struct GPU {
 
     bg_priority: [PrioType; WIDTH],
 
+    /// Whether a horizontal blank started in the last step,
+    /// HBlank DMA copies a block every time it happens
+    pub horizontal_blank_started: bool,
+
     /// GPU Interrupt
     ///
     /// The GPU has 2 interrupts:
@@ -239,6 +278,13 @@ impl GPU {
             obj_0_palette_colors: [0; 4],
             obj_1_palette_colors: [0; 4],
             video_ram: [0; VIDEO_RAM_SIZE],
+            video_ram_bank: 0,
+            cgb_mode: false,
+            bg_color_palettes: [0xFF; PALETTE_RAM_SIZE],
+            obj_color_palettes: [0; PALETTE_RAM_SIZE],
+            bg_color_palette_index: 0,
+            obj_color_palette_index: 0,
+            horizontal_blank_started: false,
             video_object_attribute_memory: [0; VIDEO_OBJECT_ATTRIBUTE_MEMORY_SIZE],
             raw_pixels: vec![0; WIDTH * HEIGHT * 3], // each pixel is a RGB value, so 24 bits are needed per pixel
             bg_priority: [PrioType::Normal; WIDTH],
@@ -322,7 +368,7 @@ impl GPU {
     /// each of the address ranges commented in the code.
     pub fn read_byte(&self, address: u16) -> u8 {
         match address {
-            0x8000 ..= 0x9FFF => self.video_ram                     [address as usize & 0x1FFF],
+            0x8000 ..= 0x9FFF => self.video_ram                     [self.video_ram_index(address)],
 
             0xFE00 ..= 0xFE9F => self.video_object_attribute_memory [address as usize - 0xFE00],
 
@@ -383,6 +429,19 @@ impl GPU {
 
             0xFF4B => self.window_position_x,
 
+            // the rest are GameBoy Color registers
+            0xFF4F if self.cgb_mode => 0xFE | self.video_ram_bank as u8,
+
+            0xFF68 if self.cgb_mode => 0x40 | self.bg_color_palette_index,
+
+            0xFF69 if self.cgb_mode => self.bg_color_palettes[(self.bg_color_palette_index & 0x3F) as usize],
+
+            0xFF6A if self.cgb_mode => 0x40 | self.obj_color_palette_index,
+
+            0xFF6B if self.cgb_mode => self.obj_color_palettes[(self.obj_color_palette_index & 0x3F) as usize],
+
+            0xFF4F | 0xFF68 ..= 0xFF6B => 0xFF,
+
             _ => {
                 println!("Invalid GPU Read {:04X}", address);
                 0
@@ -399,7 +458,7 @@ impl GPU {
         match address {
             // manipulates the video ram raw_pixels. We apply the AND & operator
             // in order to map the hex address requested to our 0-indexed vector
-            0x8000 ..= 0x9FFF => self.video_ram[address as usize & 0x1FFF] = value,
+            0x8000 ..= 0x9FFF => self.video_ram[self.video_ram_index(address)] = value,
 
             0xFE00 ..= 0xFE9F => self.video_object_attribute_memory[address as usize - 0xFE00] = value,
 
@@ -449,6 +508,25 @@ impl GPU {
 
             0xFF4B => self.window_position_x = value,
 
+            0xFF4F if self.cgb_mode => self.video_ram_bank = (value & 0x01) as usize,
+
+            0xFF68 if self.cgb_mode => self.bg_color_palette_index = value & 0xBF,
+
+            0xFF69 if self.cgb_mode => {
+                self.bg_color_palettes[(self.bg_color_palette_index & 0x3F) as usize] = value;
+                self.bg_color_palette_index = GPU::next_palette_index(self.bg_color_palette_index);
+            },
+
+            0xFF6A if self.cgb_mode => self.obj_color_palette_index = value & 0xBF,
+
+            0xFF6B if self.cgb_mode => {
+                self.obj_color_palettes[(self.obj_color_palette_index & 0x3F) as usize] = value;
+                self.obj_color_palette_index = GPU::next_palette_index(self.obj_color_palette_index);
+            },
+
+            // ignored by the monochrome GameBoy
+            0xFF4F | 0xFF68 ..= 0xFF6B => {},
+
             _ => {
                 println!("Invalid GPU write {:04X}", address)
             },
@@ -476,6 +554,7 @@ impl GPU {
         let interrupt = match self.mode {
             Mode::HorizontalBlank => {
                 self.render_line();
+                self.horizontal_blank_started = true;
                 self.horizontal_blank_interrupt
             },
             Mode::VerticalBlank => {
@@ -491,8 +570,39 @@ impl GPU {
         }
     }
 
-    fn read_byte_from_video_ram(&self, address: u16) -> u8 {
-        self.video_ram[address as usize & 0x1FFF]
+    /// Reads from a VRAM bank, no matter which one
+    /// the CPU has selected
+    fn read_byte_from_video_ram_bank(&self, bank: usize, address: u16) -> u8 {
+        self.video_ram[bank * VIDEO_RAM_BANK_SIZE + (address as usize & 0x1FFF)]
+    }
+
+    fn video_ram_index(&self, address: u16) -> usize {
+        self.video_ram_bank * VIDEO_RAM_BANK_SIZE + (address as usize & 0x1FFF)
+    }
+
+    /// Palette index after a data write, bit 7
+    /// enables the auto-increment (wrapping at 0x3F)
+    fn next_palette_index(index: u8) -> u8 {
+        if index & 0x80 == 0x80 {
+            0x80 | (index.wrapping_add(1) & 0x3F)
+        } else {
+            index
+        }
+    }
+
+    /// Converts a color from a palette RAM to RGB
+    ///
+    /// Colors are 15 bits, we scale each 5 bit component to 8 bits
+    fn color_palette_rgb(palettes: &[u8; PALETTE_RAM_SIZE], palette: usize, color_number: usize) -> [u8; 3] {
+        let index = palette * 8 + color_number * 2;
+        let color = palettes[index] as u16 | (palettes[index + 1] as u16) << 8;
+
+        let scale = |component: u16| {
+            let component = (component & 0x1F) as u8;
+            (component << 3) | (component >> 2)
+        };
+
+        [scale(color), scale(color >> 5), scale(color >> 10)]
     }
 
     /// Handle the GPU STAT / Control instruction
@@ -550,6 +660,29 @@ impl GPU {
         }
     }
 
+    /// Turns on the GameBoy Color features
+    pub fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+    }
+
+    /// Moves the GPU to a line and clock
+    ///
+    /// Used to start where the boot ROM left the LCD,
+    /// it has no effect if the LCD is off
+    pub fn set_phase(&mut self, line: u8, clock: u32) {
+        if !self.lcd_display_enable {
+            return
+        }
+
+        self.line = line;
+        self.clock = clock;
+        self.mode = if line >= HEIGHT as u8 {
+            Mode::VerticalBlank
+        } else {
+            Mode::OAMRead
+        };
+    }
+
     /// Reset the GPU
     ///
     /// We first need to reset the internal clock and lines,
@@ -604,11 +737,16 @@ impl GPU {
     /// Each pixel has 3 color components, which are RGB as
     /// per OpenGL pixel format (U8U8U8)
     fn calculate_pixel(&mut self, position_x: usize, color: u8) {
+        self.calculate_color_pixel(position_x, [color, color, color]);
+    }
+
+    /// Calculates a pixel from its RGB components
+    fn calculate_color_pixel(&mut self, position_x: usize, color: [u8; 3]) {
         let position_y = self.line as usize;
 
-        self.raw_pixels[position_y * WIDTH * 3 + position_x * 3 + 0] = color;
-        self.raw_pixels[position_y * WIDTH * 3 + position_x * 3 + 1] = color;
-        self.raw_pixels[position_y * WIDTH * 3 + position_x * 3 + 2] = color;
+        self.raw_pixels[position_y * WIDTH * 3 + position_x * 3 + 0] = color[0];
+        self.raw_pixels[position_y * WIDTH * 3 + position_x * 3 + 1] = color[1];
+        self.raw_pixels[position_y * WIDTH * 3 + position_x * 3 + 2] = color[2];
     }
 
     /// Draws the background and window
@@ -617,11 +755,13 @@ impl GPU {
     /// draw both the background and window layer on the screen. More
     /// details in the implementation
     fn draw_background(&mut self) {
-        let draw_background = self.background_display_enable;
+        // in color mode, the background enable bit only takes the
+        // priority from the background, it's always drawn
+        let draw_background = self.background_display_enable || self.cgb_mode;
 
         // first we calculate the window position.
         let window_position_y =
-            if !self.window_display_enable || !self.background_display_enable {
+            if !self.window_display_enable || !draw_background {
                 -1
             } else {
                 // if the window is being draw, the position aligned
@@ -699,7 +839,20 @@ impl GPU {
             return;
         };
 
-        let tile_number: u8 = self.read_byte_from_video_ram(tile_map_base_address + tile_y * 32 + tile_x);
+        let tile_map_address = tile_map_base_address + tile_y * 32 + tile_x;
+        let tile_number: u8 = self.read_byte_from_video_ram_bank(0, tile_map_address);
+
+        // in color mode, the same map position in VRAM bank 1 has the tile attributes:
+        // bits 0-2 palette, bit 3 tile bank, bit 5-6 flips and bit 7 priority
+        let attributes = if self.cgb_mode {
+            self.read_byte_from_video_ram_bank(1, tile_map_address)
+        } else {
+            0
+        };
+
+        let tile_bank = if attributes & 0x08 == 0x08 { 1 } else { 0 };
+        let pixel_y = if attributes & 0x40 == 0x40 { 7 - pixel_y } else { pixel_y };
+        let pixel_x = if attributes & 0x20 == 0x20 { 7 - pixel_x } else { pixel_x };
 
         let tile_address =
 
@@ -715,8 +868,8 @@ impl GPU {
         let a0 = tile_address + (pixel_y * 2);
 
         let (b1, b2) = (
-            self.read_byte_from_video_ram(a0),
-            self.read_byte_from_video_ram(a0 + 1)
+            self.read_byte_from_video_ram_bank(tile_bank, a0),
+            self.read_byte_from_video_ram_bank(tile_bank, a0 + 1)
         );
 
         let xbit = 7 - pixel_x;
@@ -739,13 +892,25 @@ impl GPU {
         self.bg_priority[x] =
             if color_number == 0 {
                 PrioType::Color0
+            } else if self.cgb_mode && !self.background_display_enable {
+                // background lost its priority, sprites always on top
+                PrioType::Color0
+            } else if attributes & 0x80 == 0x80 {
+                PrioType::Priority
             } else {
                 PrioType::Normal
             };
 
-        let color = self.bg_palette_colors[color_number];
+        if self.cgb_mode {
+            let palette = (attributes & 0x07) as usize;
+            let color = GPU::color_palette_rgb(&self.bg_color_palettes, palette, color_number);
+
+            self.calculate_color_pixel(x, color);
+        } else {
+            let color = self.bg_palette_colors[color_number];
 
-        self.calculate_pixel(x, color);
+            self.calculate_pixel(x, color);
+        }
     }
 
     fn draw_sprites(&mut self) {
@@ -783,6 +948,10 @@ impl GPU {
             let yflip: bool = flags & (1 << 6) != 0;
             let belowbg: bool = flags & (1 << 7) != 0;
 
+            // color mode sprites have their own tile bank and palette
+            let tile_bank = if self.cgb_mode && flags & (1 << 3) != 0 { 1 } else { 0 };
+            let color_palette = flags & 0x07;
+
             let line = self.line as i32;
             let sprite_size = self.sprite_size as i32;
 
@@ -809,8 +978,8 @@ impl GPU {
             let tile_address = 0x8000u16 + tile_number * 16 + tile_y * 2;
 
             let (b1, b2) = (
-                self.read_byte_from_video_ram(tile_address),
-                self.read_byte_from_video_ram(tile_address + 1)
+                self.read_byte_from_video_ram_bank(tile_bank, tile_address),
+                self.read_byte_from_video_ram_bank(tile_bank, tile_address + 1)
             );
 
             for x in 0 .. 8 {
@@ -855,10 +1024,22 @@ impl GPU {
                 // here we first check the belowbg sprite property (set by game programmers)
                 // and then this pixel position's bg priority (also set by programmers)
                 // if the background takes priority, the pixel is not calculate
-                if belowbg && self.bg_priority[(spritex + x) as usize] != PrioType::Color0 {
+                let hidden = match self.bg_priority[(spritex + x) as usize] {
+                    PrioType::Color0 => false,
+                    PrioType::Priority => true,
+                    PrioType::Normal => belowbg,
+                };
+
+                if hidden {
                     continue
                 }
 
+                if self.cgb_mode {
+                    let color = GPU::color_palette_rgb(&self.obj_color_palettes, color_palette, color_number);
+
+                    self.calculate_color_pixel((spritex + x) as usize, color);
+                    continue
+                }
 
                 let color = if useobj_1_palette_colors {
                     self.obj_1_palette_colors[color_number]
@@ -3,4 +3,6 @@ pub mod memory;
 pub mod display;
 pub mod frontend;
 pub mod gpu;
-pub mod audio;
\ No newline at end of file
+pub mod audio;
+pub mod error;
+pub mod model;
\ No newline at end of file
@@ -0,0 +1,487 @@
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use error::SafeboyError;
+
+/// Nintendo logo
+///
+/// Every cartridge has this bitmap in its header (0x104-0x133), the
+/// boot ROM compares it and locks up if it doesn't match
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83,
+    0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+    0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63,
+    0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+/// The header ends at 0x14F, anything shorter can't be a ROM
+const HEADER_END: usize = 0x150;
+
+/// Memory Bank Controller kinds
+///
+/// Which chip is inside the cartridge, several cartridge
+/// types share the same controller
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Mapper {
+    None,
+    MBC1,
+    MBC2,
+    MBC3,
+    MBC5,
+    MBC6,
+    MBC7,
+    MMM01,
+    PocketCamera,
+    TAMA5,
+    HuC1,
+    HuC3,
+}
+
+/// Cartridge type
+///
+/// Describes the hardware found in a cartridge, as
+/// declared by the cartridge type byte (0x147)
+pub struct CartridgeType {
+    pub code: u8,
+    pub name: &'static str,
+    pub mapper: Mapper,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+}
+
+macro_rules! cartridge_type {
+    ($code:expr, $name:expr, $mapper:ident, $ram:expr, $battery:expr, $timer:expr, $rumble:expr) => {
+        CartridgeType {
+            code: $code,
+            name: $name,
+            mapper: Mapper::$mapper,
+            ram: $ram,
+            battery: $battery,
+            timer: $timer,
+            rumble: $rumble,
+        }
+    };
+}
+
+/// Known cartridge types
+///
+/// Columns are: code, name, mapper, RAM, battery, timer and rumble
+const CARTRIDGE_TYPES: [CartridgeType; 28] = [
+    cartridge_type!(0x00, "ROM ONLY",                       None,         false, false, false, false),
+    cartridge_type!(0x01, "MBC1",                           MBC1,         false, false, false, false),
+    cartridge_type!(0x02, "MBC1+RAM",                       MBC1,         true,  false, false, false),
+    cartridge_type!(0x03, "MBC1+RAM+BATTERY",               MBC1,         true,  true,  false, false),
+    cartridge_type!(0x05, "MBC2",                           MBC2,         false, false, false, false),
+    cartridge_type!(0x06, "MBC2+BATTERY",                   MBC2,         false, true,  false, false),
+    cartridge_type!(0x08, "ROM+RAM",                        None,         true,  false, false, false),
+    cartridge_type!(0x09, "ROM+RAM+BATTERY",                None,         true,  true,  false, false),
+    cartridge_type!(0x0B, "MMM01",                          MMM01,        false, false, false, false),
+    cartridge_type!(0x0C, "MMM01+RAM",                      MMM01,        true,  false, false, false),
+    cartridge_type!(0x0D, "MMM01+RAM+BATTERY",              MMM01,        true,  true,  false, false),
+    cartridge_type!(0x0F, "MBC3+TIMER+BATTERY",             MBC3,         false, true,  true,  false),
+    cartridge_type!(0x10, "MBC3+TIMER+RAM+BATTERY",         MBC3,         true,  true,  true,  false),
+    cartridge_type!(0x11, "MBC3",                           MBC3,         false, false, false, false),
+    cartridge_type!(0x12, "MBC3+RAM",                       MBC3,         true,  false, false, false),
+    cartridge_type!(0x13, "MBC3+RAM+BATTERY",               MBC3,         true,  true,  false, false),
+    cartridge_type!(0x19, "MBC5",                           MBC5,         false, false, false, false),
+    cartridge_type!(0x1A, "MBC5+RAM",                       MBC5,         true,  false, false, false),
+    cartridge_type!(0x1B, "MBC5+RAM+BATTERY",               MBC5,         true,  true,  false, false),
+    cartridge_type!(0x1C, "MBC5+RUMBLE",                    MBC5,         false, false, false, true),
+    cartridge_type!(0x1D, "MBC5+RUMBLE+RAM",                MBC5,         true,  false, false, true),
+    cartridge_type!(0x1E, "MBC5+RUMBLE+RAM+BATTERY",        MBC5,         true,  true,  false, true),
+    cartridge_type!(0x20, "MBC6",                           MBC6,         true,  true,  false, false),
+    cartridge_type!(0x22, "MBC7+SENSOR+RUMBLE+RAM+BATTERY", MBC7,         true,  true,  false, true),
+    cartridge_type!(0xFC, "POCKET CAMERA",                  PocketCamera, true,  true,  false, false),
+    cartridge_type!(0xFD, "BANDAI TAMA5",                   TAMA5,        true,  true,  true,  false),
+    cartridge_type!(0xFE, "HuC3",                           HuC3,         true,  true,  true,  false),
+    cartridge_type!(0xFF, "HuC1+RAM+BATTERY",               HuC1,         true,  true,  false, false),
+];
+
+/// GameBoy Color support, from the CGB flag (0x143)
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum CgbSupport {
+    /// Plain GameBoy game
+    None,
+
+    /// Works on both GameBoy and GameBoy Color (0x80)
+    Enhanced,
+
+    /// Only works on GameBoy Color (0xC0)
+    Only,
+}
+
+/// Cartridge header
+///
+/// Every cartridge has a header at 0x100-0x14F describing
+/// the game and the hardware inside the cartridge:
+///
+/// * 0x100-0x103 -> Entry point
+/// * 0x104-0x133 -> Nintendo logo
+/// * 0x134-0x143 -> Title (newer cartridges use the last bytes for the manufacturer code and CGB flag)
+/// * 0x144-0x145 -> New licensee code
+/// * 0x146 -> SGB flag
+/// * 0x147 -> Cartridge type
+/// * 0x148 -> ROM size
+/// * 0x149 -> RAM size
+/// * 0x14A -> Destination code
+/// * 0x14B -> Old licensee code
+/// * 0x14C -> Version
+/// * 0x14D -> Header checksum
+/// * 0x14E-0x14F -> Global checksum
+pub struct CartridgeHeader {
+    pub title: String,
+    pub manufacturer_code: Option<String>,
+    pub cgb_flag: u8,
+    pub sgb_flag: u8,
+    pub new_licensee_code: String,
+    pub old_licensee_code: u8,
+    pub cartridge_type: u8,
+    pub rom_size_code: u8,
+    pub ram_size_code: u8,
+    pub destination_code: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+
+    /// Whether the Nintendo logo is the right one
+    pub logo_valid: bool,
+
+    /// Checksums calculated from the ROM data, to be
+    /// compared with the ones in the header
+    pub computed_header_checksum: u8,
+    pub computed_global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    /// Parses the header from the ROM data
+    pub fn parse(data: &[u8]) -> Result<CartridgeHeader, SafeboyError> {
+        if data.len() < HEADER_END {
+            return Err(SafeboyError::InvalidRom(format!("too small to contain a header ({} bytes)", data.len())))
+        }
+
+        let cgb_flag = data[0x143];
+
+        // newer cartridges took the last title bytes for the
+        // manufacturer code (4 bytes) and the CGB flag (1 byte)
+        let manufacturer = &data[0x13F .. 0x143];
+        let has_manufacturer = cgb_flag & 0x80 == 0x80 &&
+            manufacturer.iter().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+
+        let title_end = if has_manufacturer {
+            0x13F
+        } else if cgb_flag & 0x80 == 0x80 {
+            0x143
+        } else {
+            0x144
+        };
+
+        let global_checksum = ((data[0x14E] as u16) << 8) | data[0x14F] as u16;
+
+        Ok(CartridgeHeader {
+            title: ascii(&data[0x134 .. title_end]),
+            manufacturer_code: if has_manufacturer { Some(ascii(manufacturer)) } else { None },
+            cgb_flag,
+            sgb_flag: data[0x146],
+            new_licensee_code: ascii(&data[0x144 .. 0x146]),
+            old_licensee_code: data[0x14B],
+            cartridge_type: data[0x147],
+            rom_size_code: data[0x148],
+            ram_size_code: data[0x149],
+            destination_code: data[0x14A],
+            version: data[0x14C],
+            header_checksum: data[0x14D],
+            global_checksum,
+            logo_valid: data[0x104 .. 0x134] == NINTENDO_LOGO[..],
+            computed_header_checksum: header_checksum(data),
+            computed_global_checksum: global_checksum_of(data),
+        })
+    }
+
+    /// Cartridge type description, if it's a known one
+    pub fn cartridge_type(&self) -> Option<&'static CartridgeType> {
+        CARTRIDGE_TYPES.iter().find(|t| t.code == self.cartridge_type)
+    }
+
+    pub fn mapper(&self) -> Option<Mapper> {
+        self.cartridge_type().map(|t| t.mapper)
+    }
+
+    pub fn has_ram(&self) -> bool {
+        self.cartridge_type().is_some_and(|t| t.ram)
+    }
+
+    /// Whether the cartridge has a battery
+    ///
+    /// Cartridges with a battery keep their RAM (and clock)
+    /// running while the GameBoy is off
+    pub fn has_battery(&self) -> bool {
+        self.cartridge_type().is_some_and(|t| t.battery)
+    }
+
+    pub fn has_timer(&self) -> bool {
+        self.cartridge_type().is_some_and(|t| t.timer)
+    }
+
+    pub fn has_rumble(&self) -> bool {
+        self.cartridge_type().is_some_and(|t| t.rumble)
+    }
+
+    pub fn cgb_support(&self) -> CgbSupport {
+        match self.cgb_flag {
+            0xC0 => CgbSupport::Only,
+            flag if flag & 0x80 == 0x80 => CgbSupport::Enhanced,
+            _ => CgbSupport::None,
+        }
+    }
+
+    /// Whether the game uses Super GameBoy functions
+    pub fn sgb_support(&self) -> bool {
+        self.sgb_flag == 0x03
+    }
+
+    /// Licensee code
+    ///
+    /// 0x33 in the old licensee code means the new (two
+    /// characters) licensee code is used instead
+    pub fn licensee(&self) -> String {
+        if self.old_licensee_code == 0x33 {
+            self.new_licensee_code.clone()
+        } else {
+            format!("{:02X}", self.old_licensee_code)
+        }
+    }
+
+    /// ROM size in bytes, 0 if the size code is unknown
+    pub fn rom_size(&self) -> usize {
+        match self.rom_size_code {
+            0x00 ..= 0x08 => 0x8000 << self.rom_size_code,
+            0x52 => 72 * 0x4000,
+            0x53 => 80 * 0x4000,
+            0x54 => 96 * 0x4000,
+            _ => 0,
+        }
+    }
+
+    /// External RAM size in bytes
+    pub fn ram_size(&self) -> usize {
+        match self.ram_size_code {
+            1 => 0x800,
+            2 => 0x2000,
+            3 => 0x8000,
+            4 => 0x20000,
+            5 => 0x10000,
+            _ => 0,
+        }
+    }
+
+    /// Whether the game is meant to be sold in Japan
+    pub fn japanese(&self) -> bool {
+        self.destination_code == 0x00
+    }
+}
+
+impl fmt::Display for CartridgeHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cartridge_type = self.cartridge_type().map_or("Unknown", |t| t.name);
+
+        writeln!(f, "Title:            {}", self.title)?;
+        writeln!(f, "Manufacturer:     {}", self.manufacturer_code.as_ref().map_or("-", |code| code.as_str()))?;
+        writeln!(f, "CGB:              {:?} ({:02X})", self.cgb_support(), self.cgb_flag)?;
+        writeln!(f, "SGB:              {} ({:02X})", self.sgb_support(), self.sgb_flag)?;
+        writeln!(f, "Licensee:         {} (old {:02X}, new {})", self.licensee(), self.old_licensee_code, self.new_licensee_code)?;
+        writeln!(f, "Cartridge type:   {} ({:02X})", cartridge_type, self.cartridge_type)?;
+        writeln!(f, "ROM size:         {}kb ({:02X})", self.rom_size() / 1024, self.rom_size_code)?;
+        writeln!(f, "RAM size:         {}kb ({:02X})", self.ram_size() / 1024, self.ram_size_code)?;
+        writeln!(f, "Destination:      {}", if self.japanese() { "Japan" } else { "Overseas" })?;
+        writeln!(f, "Version:          {}", self.version)?;
+        writeln!(f, "Header checksum:  {:02X} (computed {:02X})", self.header_checksum, self.computed_header_checksum)?;
+        writeln!(f, "Global checksum:  {:04X} (computed {:04X})", self.global_checksum, self.computed_global_checksum)?;
+        write!(f, "Nintendo logo:    {}", if self.logo_valid { "valid" } else { "invalid" })
+    }
+}
+
+/// Cartridge
+///
+/// The ROM data along with its parsed header. This is
+/// what the MBC implementations are built from
+pub struct Cartridge {
+    pub header: CartridgeHeader,
+    pub rom: Vec<u8>,
+}
+
+impl Cartridge {
+    pub fn new(rom: Vec<u8>) -> Result<Cartridge, SafeboyError> {
+        let header = CartridgeHeader::parse(&rom)?;
+
+        Ok(Cartridge {
+            header,
+            rom,
+        })
+    }
+
+    /// Loads a cartridge from a ROM file
+    pub fn load(rom_file: &str) -> Result<Cartridge, SafeboyError> {
+        let mut data = vec![];
+
+        File::open(rom_file)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .map_err(|e| SafeboyError::RomFile(rom_file.to_string(), e))?;
+
+        Cartridge::new(data)
+    }
+
+    /// Header problems
+    ///
+    /// None of these stop the game from running in the emulator,
+    /// but some of them would on real hardware (the boot ROM
+    /// checks the logo and the header checksum)
+    pub fn warnings(&self) -> Vec<String> {
+        let header = &self.header;
+        let mut warnings = vec![];
+
+        if !header.logo_valid {
+            warnings.push("Nintendo logo doesn't match, the boot ROM would lock up".to_string());
+        }
+
+        if header.header_checksum != header.computed_header_checksum {
+            warnings.push(format!(
+                "Header checksum mismatch: header says {:02X}, computed {:02X}",
+                header.header_checksum,
+                header.computed_header_checksum
+            ));
+        }
+
+        if header.global_checksum != header.computed_global_checksum {
+            warnings.push(format!(
+                "Global checksum mismatch: header says {:04X}, computed {:04X}",
+                header.global_checksum,
+                header.computed_global_checksum
+            ));
+        }
+
+        if header.cartridge_type().is_none() {
+            warnings.push(format!("Unknown cartridge type {:02X}", header.cartridge_type));
+        }
+
+        if header.rom_size() == 0 {
+            warnings.push(format!("Unknown ROM size code {:02X}", header.rom_size_code));
+        } else if header.rom_size() != self.rom.len() {
+            warnings.push(format!(
+                "ROM size mismatch: header says {} bytes, file has {} bytes",
+                header.rom_size(),
+                self.rom.len()
+            ));
+        }
+
+        warnings
+    }
+}
+
+/// Header checksum
+///
+/// Calculated over 0x134-0x14C, the boot ROM
+/// locks up if it doesn't match the one in 0x14D
+fn header_checksum(data: &[u8]) -> u8 {
+    data[0x134 ..= 0x14C].iter().fold(0u8, |checksum, &byte| {
+        checksum.wrapping_sub(byte).wrapping_sub(1)
+    })
+}
+
+/// Global checksum
+///
+/// Sum of every byte in the ROM except the checksum itself,
+/// nothing checks it on real hardware
+fn global_checksum_of(data: &[u8]) -> u16 {
+    data.iter()
+        .enumerate()
+        .filter(|&(address, _)| address != 0x14E && address != 0x14F)
+        .fold(0u16, |checksum, (_, &byte)| checksum.wrapping_add(byte as u16))
+}
+
+/// Converts header bytes to a string, stopping at the first 0
+fn ascii(data: &[u8]) -> String {
+    data.iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| if c.is_ascii_graphic() || c == b' ' { c as char } else { '?' })
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom() -> Vec<u8> {
+        let mut data = vec![0u8; 0x8000];
+
+        data[0x104 .. 0x134].copy_from_slice(&NINTENDO_LOGO);
+        data[0x134 .. 0x13B].copy_from_slice(b"SAFEBOY");
+        data[0x147] = 0x03;
+        data[0x149] = 0x02;
+        data[0x14B] = 0x01;
+
+        data[0x14D] = header_checksum(&data);
+
+        let checksum = global_checksum_of(&data);
+        data[0x14E] = (checksum >> 8) as u8;
+        data[0x14F] = checksum as u8;
+
+        data
+    }
+
+    #[test]
+    fn it_parses_the_header() {
+        let cartridge = Cartridge::new(rom()).unwrap();
+        let header = &cartridge.header;
+
+        assert_eq!(header.title, "SAFEBOY");
+        assert_eq!(header.manufacturer_code, None);
+        assert_eq!(header.mapper(), Some(Mapper::MBC1));
+        assert!(header.has_battery());
+        assert_eq!(header.rom_size(), 0x8000);
+        assert_eq!(header.ram_size(), 0x2000);
+        assert_eq!(header.licensee(), "01");
+        assert!(cartridge.warnings().is_empty());
+    }
+
+    #[test]
+    fn it_parses_the_manufacturer_code() {
+        let mut data = rom();
+        data[0x13F .. 0x143].copy_from_slice(b"AAUE");
+        data[0x143] = 0xC0;
+
+        let header = CartridgeHeader::parse(&data).unwrap();
+
+        assert_eq!(header.title, "SAFEBOY");
+        assert_eq!(header.manufacturer_code, Some("AAUE".to_string()));
+        assert_eq!(header.cgb_support(), CgbSupport::Only);
+    }
+
+    #[test]
+    fn it_warns_about_bad_headers() {
+        let mut data = rom();
+        data[0x104] = 0;
+        data[0x148] = 0x01;
+
+        let warnings = Cartridge::new(data).unwrap().warnings();
+
+        assert_eq!(warnings.len(), 4);
+    }
+
+    #[test]
+    fn it_rejects_truncated_roms() {
+        assert!(Cartridge::new(vec![0; 0x100]).is_err());
+    }
+
+    #[test]
+    fn it_validates_the_bundled_roms() {
+        let cartridge = Cartridge::load("./data/tetris.gb").unwrap();
+
+        assert_eq!(cartridge.header.title, "TETRIS");
+        assert!(cartridge.header.logo_valid);
+        assert_eq!(cartridge.header.header_checksum, cartridge.header.computed_header_checksum);
+    }
+}
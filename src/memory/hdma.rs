@@ -0,0 +1,148 @@
+/// Size of each HDMA block, in bytes
+const BLOCK_SIZE: u16 = 0x10;
+
+/// GameBoy Color VRAM DMA (HDMA)
+///
+/// Copies data from ROM or RAM into VRAM, in blocks of 16 bytes.
+/// It has two modes:
+///
+/// * General purpose DMA: everything is copied at once
+/// * HBlank DMA: one block is copied at each horizontal blank
+///
+/// The registers live at 0xFF51-0xFF55: source (high, low),
+/// destination (high, low) and length/mode/start
+pub struct Hdma {
+    source: u16,
+    destination: u16,
+
+    /// Blocks left to copy, minus one
+    length: u8,
+
+    /// Whether an HBlank DMA is running
+    active: bool,
+}
+
+impl Hdma {
+    pub fn new() -> Hdma {
+        Hdma {
+            source: 0,
+            destination: 0,
+            length: 0x7F,
+            active: false,
+        }
+    }
+
+    pub fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            // bit 7 is clear while an HBlank DMA is running,
+            // it reads 0xFF when there is nothing left to copy
+            0xFF55 => (if self.active { 0x00 } else { 0x80 }) | self.length,
+
+            // source and destination are write only
+            _ => 0xFF,
+        }
+    }
+
+    /// Writes an HDMA register
+    ///
+    /// Returns how many blocks have to be copied right
+    /// away, which is only the case for general purpose DMAs
+    pub fn write_byte(&mut self, address: u16, value: u8) -> u8 {
+        match address {
+            0xFF51 => self.source = (self.source & 0x00FF) | (value as u16) << 8,
+
+            // the lower 4 bits are ignored, blocks are aligned
+            0xFF52 => self.source = (self.source & 0xFF00) | (value & 0xF0) as u16,
+
+            // the destination is always inside VRAM
+            0xFF53 => self.destination = (self.destination & 0x00FF) | ((value & 0x1F) as u16) << 8,
+
+            0xFF54 => self.destination = (self.destination & 0xFF00) | (value & 0xF0) as u16,
+
+            0xFF55 => {
+                match (self.active, value & 0x80 == 0x80) {
+                    // writing with bit 7 clear stops a running HBlank DMA
+                    (true, false) => self.active = false,
+                    (_, true) => {
+                        self.length = value & 0x7F;
+                        self.active = true;
+                    },
+                    (false, false) => {
+                        self.length = value & 0x7F;
+                        return self.length + 1
+                    },
+                }
+            },
+
+            _ => panic!("Invalid HDMA write: {:04X}", address),
+        }
+
+        0
+    }
+
+    /// Whether an HBlank DMA is waiting for the next horizontal blank
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    /// Source and destination of the next block
+    ///
+    /// The addresses move to the following block, and the
+    /// transfer ends after the last one
+    pub fn next_block(&mut self) -> (u16, u16) {
+        let block = (self.source, 0x8000 | self.destination);
+
+        self.source = self.source.wrapping_add(BLOCK_SIZE);
+        self.destination = (self.destination + BLOCK_SIZE) & 0x1FF0;
+
+        if self.length == 0 {
+            self.length = 0x7F;
+            self.active = false;
+        } else {
+            self.length -= 1;
+        }
+
+        block
+    }
+}
+
+impl Default for Hdma {
+    fn default() -> Hdma {
+        Hdma::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_copies_all_blocks_in_general_purpose_mode() {
+        let mut hdma = Hdma::new();
+        hdma.write_byte(0xFF51, 0xC1);
+        hdma.write_byte(0xFF52, 0x2F);
+        hdma.write_byte(0xFF53, 0x88);
+        hdma.write_byte(0xFF54, 0x10);
+
+        assert_eq!(hdma.write_byte(0xFF55, 0x02), 3);
+        assert_eq!(hdma.next_block(), (0xC120, 0x8810));
+        assert_eq!(hdma.next_block(), (0xC130, 0x8820));
+        assert_eq!(hdma.next_block(), (0xC140, 0x8830));
+        assert_eq!(hdma.read_byte(0xFF55), 0xFF);
+    }
+
+    #[test]
+    fn it_stops_hblank_dma() {
+        let mut hdma = Hdma::new();
+
+        assert_eq!(hdma.write_byte(0xFF55, 0x83), 0);
+        assert!(hdma.active());
+
+        hdma.next_block();
+        assert_eq!(hdma.read_byte(0xFF55), 0x02);
+
+        hdma.write_byte(0xFF55, 0x00);
+        assert!(!hdma.active());
+        assert_eq!(hdma.read_byte(0xFF55), 0x82);
+    }
+}
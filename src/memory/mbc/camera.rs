@@ -0,0 +1,639 @@
+extern crate png;
+
+use std::fs::File;
+use std::io::prelude::*;
+use memory::mbc::MBC;
+use memory::cartridge::Cartridge;
+
+/// Sensor output width, in pixels
+pub const CAMERA_WIDTH: usize = 128;
+
+/// Sensor output height, in pixels
+pub const CAMERA_HEIGHT: usize = 112;
+
+/// Camera RAM, 128kb (16 banks)
+const RAM_SIZE: usize = 0x20000;
+
+/// Where the captured image is written in RAM (bank 0)
+const IMAGE_ADDRESS: usize = 0x0100;
+
+/// Number of camera registers (0xA000-0xA035)
+const REGISTER_COUNT: usize = 0x36;
+
+/// Edge enhancement ratios, in percent, selected by register 4
+const EDGE_RATIOS: [i32; 8] = [50, 75, 100, 125, 200, 300, 400, 500];
+
+/// Image source
+///
+/// The camera sensor sees whatever the host provides, as a
+/// 128x112 grayscale image (one byte per pixel, 0 is black)
+pub trait ImageSource {
+    fn capture(&mut self) -> Vec<u8>;
+}
+
+/// Image from a file
+///
+/// Loads a PNG or binary/ASCII PGM once, converting it to grayscale
+/// and scaling it to the sensor size. Every capture sees the same image
+pub struct FileImage {
+    image: Vec<u8>,
+}
+
+impl FileImage {
+    pub fn new(image_file: &str) -> Result<FileImage, String> {
+        let mut data = vec![];
+
+        File::open(image_file)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .map_err(|e| format!("Could not read camera image {}: {}", image_file, e))?;
+
+        let (width, height, pixels) = if data.starts_with(b"\x89PNG") {
+            decode_png(&data)
+        } else if data.starts_with(b"P5") || data.starts_with(b"P2") {
+            decode_pgm(&data)
+        } else {
+            Err("unknown format, expected PNG or PGM".to_string())
+        }.map_err(|e| format!("Could not decode camera image {}: {}", image_file, e))?;
+
+        Ok(FileImage {
+            image: scale_to_sensor(width, height, &pixels),
+        })
+    }
+}
+
+impl ImageSource for FileImage {
+    fn capture(&mut self) -> Vec<u8> {
+        self.image.clone()
+    }
+}
+
+/// Image from a callback
+///
+/// The callback is called on every capture, and must
+/// return a 128x112 grayscale image
+pub struct CallbackImage<F: FnMut() -> Vec<u8>> {
+    callback: F,
+}
+
+impl<F: FnMut() -> Vec<u8>> CallbackImage<F> {
+    pub fn new(callback: F) -> CallbackImage<F> {
+        CallbackImage {
+            callback,
+        }
+    }
+}
+
+impl<F: FnMut() -> Vec<u8>> ImageSource for CallbackImage<F> {
+    fn capture(&mut self) -> Vec<u8> {
+        (self.callback)()
+    }
+}
+
+/// Blank image, used until the host provides one
+struct BlankImage;
+
+impl ImageSource for BlankImage {
+    fn capture(&mut self) -> Vec<u8> {
+        vec![0x80; CAMERA_WIDTH * CAMERA_HEIGHT]
+    }
+}
+
+/// Pocket Camera
+///
+/// The GameBoy Camera cartridge contains 1MB of ROM, 128kb of RAM
+/// and a Mitsubishi M64282FP image sensor.
+///
+/// Selecting RAM bank 0x10 maps the sensor registers into the
+/// RAM area (mirrored every 0x80 bytes):
+///
+/// * 0xA000 -> Bit 0: start capture, reads 1 while capturing
+/// * 0xA001 -> Bit 7: N (exclusive edge mode), bits 5-6: VH (edge direction), bits 0-4: gain
+/// * 0xA002-0xA003 -> Exposure time (big endian)
+/// * 0xA004 -> Bits 4-6: edge enhancement ratio, bit 3: invert
+/// * 0xA005 -> Zero point and reference voltage
+/// * 0xA006-0xA035 -> 4x4 dithering matrix, 3 thresholds per position
+///
+/// Only 0xA000 can be read, the rest of the registers read as 0.
+///
+/// Once captured, the image is written to RAM bank 0 (0xA100-0xAEFF)
+/// as 14x16 tiles (2 bits per pixel)
+pub struct Camera {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_on: bool,
+    rom_bank: usize,
+    ram_bank: usize,
+    battery: bool,
+    registers: [u8; REGISTER_COUNT],
+
+    /// Ticks left until the capture finishes
+    capture_ticks: u32,
+
+    source: Box<dyn ImageSource>,
+}
+
+impl Camera {
+    pub fn new(cartridge: Cartridge) -> Camera {
+        let battery = cartridge.header.has_battery();
+
+        Camera {
+            rom: cartridge.rom,
+            ram: vec![0; RAM_SIZE],
+            ram_on: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            battery,
+            registers: [0; REGISTER_COUNT],
+            capture_ticks: 0,
+            source: Box::new(BlankImage),
+        }
+    }
+
+    fn exposure(&self) -> u32 {
+        ((self.registers[2] as u32) << 8) | self.registers[3] as u32
+    }
+
+    /// Capture duration, in ticks
+    ///
+    /// The sensor is clocked at a quarter of the CPU speed, and
+    /// takes a fixed time to read the image plus the exposure time
+    fn capture_duration(&self) -> u32 {
+        let n = if self.registers[1] & 0x80 == 0x80 { 0 } else { 512 };
+
+        (32446 + n + 16 * self.exposure()) * 4
+    }
+
+    /// Runs the sensor pipeline
+    ///
+    /// The host image goes through the same steps as the analog
+    /// output of the real sensor: exposure, inversion and edge
+    /// enhancement. Then the cartridge dithers it to 4 shades
+    /// using the threshold matrix, and writes the tiles to RAM
+    fn capture(&mut self) {
+        let mut image = self.source.capture();
+        image.resize(CAMERA_WIDTH * CAMERA_HEIGHT, 0);
+
+        let exposure = self.exposure() as i32;
+        let invert = self.registers[4] & 0x08 == 0x08;
+
+        // exposure: 0x1000 leaves the image as it is
+        let exposed: Vec<i32> = image.iter().map(|&pixel| {
+            let value = (pixel as i32 * exposure / 0x1000).min(255);
+
+            if invert { 255 - value } else { value }
+        }).collect();
+
+        let processed = self.enhance_edges(&exposed);
+
+        for y in 0 .. CAMERA_HEIGHT {
+            for x in 0 .. CAMERA_WIDTH {
+                let color = self.dither(x, y, processed[y * CAMERA_WIDTH + x]);
+
+                let tile = (y / 8) * (CAMERA_WIDTH / 8) + x / 8;
+                let address = IMAGE_ADDRESS + tile * 16 + (y % 8) * 2;
+                let bit = 0x80 >> (x % 8);
+
+                if color & 0x01 == 0x01 {
+                    self.ram[address] |= bit;
+                } else {
+                    self.ram[address] &= !bit;
+                }
+
+                if color & 0x02 == 0x02 {
+                    self.ram[address + 1] |= bit;
+                } else {
+                    self.ram[address + 1] &= !bit;
+                }
+            }
+        }
+    }
+
+    /// Edge enhancement
+    ///
+    /// Each pixel is boosted by its difference with its neighbours,
+    /// scaled by the ratio in register 4. VH selects which neighbours
+    /// are used (0: none, 1: vertical, 2: horizontal, 3: both)
+    fn enhance_edges(&self, image: &[i32]) -> Vec<i32> {
+        let direction = (self.registers[1] >> 5) & 0x03;
+        let ratio = EDGE_RATIOS[((self.registers[4] >> 4) & 0x07) as usize];
+
+        if direction == 0 {
+            return image.to_vec()
+        }
+
+        let pixel = |x: i32, y: i32| {
+            let x = x.max(0).min(CAMERA_WIDTH as i32 - 1) as usize;
+            let y = y.max(0).min(CAMERA_HEIGHT as i32 - 1) as usize;
+
+            image[y * CAMERA_WIDTH + x]
+        };
+
+        let mut enhanced = Vec::with_capacity(image.len());
+
+        for y in 0 .. CAMERA_HEIGHT as i32 {
+            for x in 0 .. CAMERA_WIDTH as i32 {
+                let center = pixel(x, y);
+                let mut edge = 0;
+
+                if direction & 0x01 == 0x01 {
+                    edge += 2 * center - pixel(x, y - 1) - pixel(x, y + 1);
+                }
+
+                if direction & 0x02 == 0x02 {
+                    edge += 2 * center - pixel(x - 1, y) - pixel(x + 1, y);
+                }
+
+                enhanced.push(center + edge * ratio / 100);
+            }
+        }
+
+        enhanced
+    }
+
+    /// Dithers a pixel to one of the 4 shades (0 is white)
+    fn dither(&self, x: usize, y: usize, value: i32) -> u8 {
+        let matrix = 6 + ((y & 3) * 4 + (x & 3)) * 3;
+        let thresholds = &self.registers[matrix .. matrix + 3];
+
+        if value < thresholds[0] as i32 {
+            3
+        } else if value < thresholds[1] as i32 {
+            2
+        } else if value < thresholds[2] as i32 {
+            1
+        } else {
+            0
+        }
+    }
+}
+
+impl MBC for Camera {
+    fn read_rom(&self, address: u16) -> u8 {
+        let index =
+            if address < 0x4000 {
+                address as usize
+            } else {
+                (self.rom_bank * 0x4000) | ((address as usize) & 0x3FFF)
+            };
+
+        self.rom[index % self.rom.len()]
+    }
+
+    fn rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 {
+            0
+        } else {
+            self.rom_bank % (self.rom.len() / 0x4000)
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000 ..= 0x1FFF => {
+                self.ram_on = value & 0x0F == 0x0A;
+            },
+
+            // 6 bits of ROM bank, bank 0 can be mapped
+            0x2000 ..= 0x3FFF => {
+                self.rom_bank = (value as usize) & 0x3F;
+            },
+
+            // bit 4 selects the camera registers
+            0x4000 ..= 0x5FFF => {
+                self.ram_bank = if value & 0x10 == 0x10 {
+                    0x10
+                } else {
+                    (value as usize) & 0x0F
+                };
+            },
+
+            0x6000 ..= 0x7FFF => (),
+
+            _ => panic!("Could not write to {:04X} (Camera)", address),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if self.ram_bank == 0x10 {
+            return match address & 0x7F {
+                0x00 => (self.registers[0] & 0x06) | if self.capture_ticks > 0 { 1 } else { 0 },
+                _ => 0x00,
+            }
+        }
+
+        // the RAM can be read even when it's not enabled
+        self.ram[(self.ram_bank * 0x2000) | ((address & 0x1FFF) as usize)]
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        if self.ram_bank == 0x10 {
+            let register = (address & 0x7F) as usize;
+
+            if register >= REGISTER_COUNT {
+                return false
+            }
+
+            if register == 0 && value & 0x01 == 0x01 && self.capture_ticks == 0 {
+                self.capture_ticks = self.capture_duration();
+            }
+
+            self.registers[register] = value;
+            return false
+        }
+
+        if !self.ram_on {
+            return false
+        }
+
+        self.ram[(self.ram_bank * 0x2000) | ((address & 0x1FFF) as usize)] = value;
+        true
+    }
+
+    fn step(&mut self, ticks: u32) {
+        if self.capture_ticks == 0 {
+            return
+        }
+
+        if ticks >= self.capture_ticks {
+            self.capture_ticks = 0;
+            self.registers[0] &= !0x01;
+            self.capture();
+        } else {
+            self.capture_ticks -= ticks;
+        }
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        let size = self.ram.len().min(data.len());
+
+        self.ram[..size].copy_from_slice(&data[..size]);
+    }
+
+    fn set_image_source(&mut self, source: Box<dyn ImageSource>) {
+        self.source = source;
+    }
+}
+
+/// Decodes a PNG file into grayscale pixels
+fn decode_png(data: &[u8]) -> Result<(usize, usize, Vec<u8>), String> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+
+    let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(|e| e.to_string())?;
+
+    let channels = info.color_type.samples();
+    let pixels = buffer[.. info.buffer_size()]
+        .chunks(channels)
+        .map(|pixel| match channels {
+            // RGB(A) to luma, alpha is ignored
+            3 | 4 => ((pixel[0] as u32 * 299 + pixel[1] as u32 * 587 + pixel[2] as u32 * 114) / 1000) as u8,
+            _ => pixel[0],
+        })
+        .collect();
+
+    Ok((info.width as usize, info.height as usize, pixels))
+}
+
+/// Decodes a PGM file (P5 binary or P2 ASCII) into grayscale pixels
+fn decode_pgm(data: &[u8]) -> Result<(usize, usize, Vec<u8>), String> {
+    let mut position = 2;
+    let mut header = [0usize; 3];
+
+    // width, height and maximum value, separated by
+    // whitespace and possibly with comments in between
+    for value in header.iter_mut() {
+        *value = pgm_number(data, &mut position)?;
+    }
+
+    let [width, height, max] = header;
+
+    if max == 0 || max > 0xFFFF {
+        return Err(format!("invalid maximum value {}", max))
+    }
+
+    // every sample takes at least a byte, bigger images
+    // can't be in the file, and might not even fit in memory
+    let pixel_count = match width.checked_mul(height) {
+        Some(count) if count <= data.len() => count,
+        _ => return Err(format!("invalid image size {}x{}", width, height)),
+    };
+
+    let mut samples = Vec::with_capacity(pixel_count);
+
+    if data[1] == b'5' {
+        // a single whitespace separates the header from the data
+        position += 1;
+
+        let size = if max > 0xFF { 2 } else { 1 };
+
+        for i in 0 .. pixel_count {
+            let start = position + i * size;
+            let sample = match data.get(start .. start + size) {
+                Some(bytes) if size == 2 => ((bytes[0] as usize) << 8) | bytes[1] as usize,
+                Some(bytes) => bytes[0] as usize,
+                None => return Err("truncated image data".to_string()),
+            };
+
+            samples.push(sample);
+        }
+    } else {
+        for _ in 0 .. pixel_count {
+            samples.push(pgm_number(data, &mut position)?);
+        }
+    }
+
+    let pixels = samples.iter().map(|&sample| (sample.min(max) * 255 / max) as u8).collect();
+
+    Ok((width, height, pixels))
+}
+
+fn pgm_number(data: &[u8], position: &mut usize) -> Result<usize, String> {
+    loop {
+        match data.get(*position) {
+            Some(b'#') => {
+                while data.get(*position).is_some_and(|&c| c != b'\n') {
+                    *position += 1;
+                }
+            },
+            Some(c) if c.is_ascii_whitespace() => *position += 1,
+            _ => break,
+        }
+    }
+
+    let start = *position;
+
+    while data.get(*position).is_some_and(|c| c.is_ascii_digit()) {
+        *position += 1;
+    }
+
+    std::str::from_utf8(&data[start .. *position])
+        .ok()
+        .and_then(|number| number.parse().ok())
+        .ok_or_else(|| "invalid header".to_string())
+}
+
+/// Scales an image to fill the sensor, cropping the
+/// sides that don't fit the sensor aspect ratio
+fn scale_to_sensor(width: usize, height: usize, pixels: &[u8]) -> Vec<u8> {
+    let mut image = Vec::with_capacity(CAMERA_WIDTH * CAMERA_HEIGHT);
+
+    if width == 0 || height == 0 {
+        return vec![0; CAMERA_WIDTH * CAMERA_HEIGHT]
+    }
+
+    // same scale on both axes, the one that covers the whole sensor
+    let (scale_numerator, scale_denominator) = if width * CAMERA_HEIGHT > height * CAMERA_WIDTH {
+        (height, CAMERA_HEIGHT)
+    } else {
+        (width, CAMERA_WIDTH)
+    };
+
+    let offset_x = (width - CAMERA_WIDTH * scale_numerator / scale_denominator) / 2;
+    let offset_y = (height - CAMERA_HEIGHT * scale_numerator / scale_denominator) / 2;
+
+    for y in 0 .. CAMERA_HEIGHT {
+        for x in 0 .. CAMERA_WIDTH {
+            let source_x = (offset_x + x * scale_numerator / scale_denominator).min(width - 1);
+            let source_y = (offset_y + y * scale_numerator / scale_denominator).min(height - 1);
+
+            image.push(pixels[source_y * width + source_x]);
+        }
+    }
+
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera() -> Camera {
+        let mut data = vec![0u8; 0x8000];
+        data[0x147] = 0xFC;
+
+        let mut camera = Camera::new(Cartridge::new(data).unwrap());
+        camera.write_rom(0x4000, 0x10);
+        camera
+    }
+
+    /// Sets the same thresholds for every matrix position
+    fn set_thresholds(camera: &mut Camera, thresholds: [u8; 3]) {
+        for i in 0 .. 16 {
+            for (j, threshold) in thresholds.iter().enumerate() {
+                camera.write_ram(0xA006 + i * 3 + j as u16, *threshold);
+            }
+        }
+    }
+
+    fn capture(camera: &mut Camera) {
+        camera.write_ram(0xA000, 0x01);
+        assert_eq!(camera.read_ram(0xA000) & 0x01, 1);
+
+        camera.step(camera.capture_duration());
+        assert_eq!(camera.read_ram(0xA000) & 0x01, 0);
+
+        camera.write_rom(0x4000, 0x00);
+    }
+
+    #[test]
+    fn it_captures_the_host_image() {
+        let mut camera = camera();
+
+        // left half black, right half white
+        camera.set_image_source(Box::new(CallbackImage::new(|| {
+            (0 .. CAMERA_WIDTH * CAMERA_HEIGHT)
+                .map(|i| if i % CAMERA_WIDTH < 64 { 0 } else { 255 })
+                .collect()
+        })));
+
+        camera.write_ram(0xA002, 0x10);
+        camera.write_ram(0xA003, 0x00);
+        set_thresholds(&mut camera, [0x40, 0x80, 0xC0]);
+        capture(&mut camera);
+
+        // first tile row: tile 0 is black, tile 15 is white
+        assert_eq!(camera.read_ram(0xA100), 0xFF);
+        assert_eq!(camera.read_ram(0xA101), 0xFF);
+        assert_eq!(camera.read_ram(0xA100 + 15 * 16), 0x00);
+        assert_eq!(camera.read_ram(0xA101 + 15 * 16), 0x00);
+    }
+
+    #[test]
+    fn it_applies_the_exposure() {
+        let mut camera = camera();
+        camera.set_image_source(Box::new(CallbackImage::new(|| vec![0xA0; CAMERA_WIDTH * CAMERA_HEIGHT])));
+        set_thresholds(&mut camera, [0x40, 0x60, 0x90]);
+
+        // half the exposure, 0xA0 becomes 0x50
+        camera.write_ram(0xA002, 0x08);
+        camera.write_ram(0xA003, 0x00);
+        capture(&mut camera);
+
+        assert_eq!(camera.read_ram(0xA100), 0x00);
+        assert_eq!(camera.read_ram(0xA101), 0xFF);
+    }
+
+    #[test]
+    fn it_only_reads_the_control_register() {
+        let mut camera = camera();
+        camera.write_ram(0xA001, 0xE0);
+
+        assert_eq!(camera.read_ram(0xA001), 0x00);
+        assert_eq!(camera.read_ram(0xA080), 0x00);
+    }
+
+    #[test]
+    fn it_decodes_pgm_images() {
+        let (width, height, pixels) = decode_pgm(b"P2\n# comment\n2 1\n15\n0 15\n").unwrap();
+
+        assert_eq!((width, height), (2, 1));
+        assert_eq!(pixels, vec![0, 255]);
+
+        let (_, _, pixels) = decode_pgm(b"P5 2 1 255\n\x10\x20").unwrap();
+        assert_eq!(pixels, vec![0x10, 0x20]);
+    }
+
+    #[test]
+    fn it_rejects_pgm_images_bigger_than_the_file() {
+        assert!(decode_pgm(b"P5 100000 100000 255\n\x10\x20").is_err());
+        assert!(decode_pgm(b"P2 4294967296 4294967296 255\n0 0").is_err());
+        assert!(decode_pgm(b"P5 2 2 255\n\x10\x20").is_err());
+    }
+
+    #[test]
+    fn it_decodes_png_images() {
+        let mut data = vec![];
+
+        {
+            let mut encoder = png::Encoder::new(&mut data, 2, 1);
+            encoder.set_color(png::ColorType::Rgb);
+            encoder.set_depth(png::BitDepth::Eight);
+
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&[0, 0, 0, 255, 255, 255]).unwrap();
+        }
+
+        let (width, height, pixels) = decode_png(&data).unwrap();
+
+        assert_eq!((width, height), (2, 1));
+        assert_eq!(pixels, vec![0, 255]);
+    }
+
+    #[test]
+    fn it_scales_images_to_the_sensor() {
+        let image = scale_to_sensor(256, 224, &vec![0x42; 256 * 224]);
+
+        assert_eq!(image.len(), CAMERA_WIDTH * CAMERA_HEIGHT);
+        assert!(image.iter().all(|&pixel| pixel == 0x42));
+    }
+}
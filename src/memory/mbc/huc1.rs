@@ -0,0 +1,190 @@
+use memory::mbc::MBC;
+use memory::cartridge::Cartridge;
+use memory::mbc::infrared::Infrared;
+
+/// HuC1
+///
+/// Hudson Soft's MBC1 lookalike. It holds up to 1MB of ROM and
+/// 32kb of RAM, plus an infrared LED and sensor.
+///
+/// The RAM area is shared with the infrared register, the value
+/// written to 0x0000-0x1FFF selects which one is mapped:
+///
+/// * 0x0E -> Infrared mode
+/// * Anything else -> RAM mode
+pub struct HuC1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rom_bank: usize,
+    ram_bank: usize,
+    battery: bool,
+
+    /// Whether the infrared register is mapped instead of the RAM
+    ir_mode: bool,
+
+    infrared: Box<dyn Infrared>,
+}
+
+impl HuC1 {
+    pub fn new(cartridge: Cartridge, infrared: Box<dyn Infrared>) -> HuC1 {
+        let ramsize = cartridge.header.ram_size();
+        let battery = cartridge.header.has_battery();
+
+        HuC1 {
+            rom: cartridge.rom,
+            ram: vec![0; ramsize],
+            rom_bank: 1,
+            ram_bank: 0,
+            battery,
+            ir_mode: false,
+            infrared,
+        }
+    }
+
+    fn ram_index(&self, address: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None
+        }
+
+        let index = (self.ram_bank * 0x2000) | ((address & 0x1FFF) as usize);
+
+        Some(index % self.ram.len())
+    }
+}
+
+impl MBC for HuC1 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let index =
+            if address < 0x4000 {
+                address as usize
+            } else {
+                (self.rom_bank * 0x4000) | ((address as usize) & 0x3FFF)
+            };
+
+        self.rom[index % self.rom.len()]
+    }
+
+    fn rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 {
+            0
+        } else {
+            self.rom_bank % (self.rom.len() / 0x4000)
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000 ..= 0x1FFF => {
+                self.ir_mode = value == 0x0E;
+            },
+
+            0x2000 ..= 0x3FFF => {
+                self.rom_bank = match (value as usize) & 0x3F {
+                    0 => 1,
+                    n => n,
+                };
+            },
+
+            0x4000 ..= 0x5FFF => {
+                self.ram_bank = (value as usize) & 0x03;
+            },
+
+            0x6000 ..= 0x7FFF => (),
+
+            _ => panic!("Could not write to {:04X} (HuC1)", address),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        // in infrared mode bit 0 tells whether light
+        // is being received, the rest read as 0xC0
+        if self.ir_mode {
+            return 0xC0 | if self.infrared.light() { 1 } else { 0 }
+        }
+
+        match self.ram_index(address) {
+            Some(index) => self.ram[index],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        if self.ir_mode {
+            self.infrared.set_led(value & 0x01 == 0x01);
+            return false
+        }
+
+        match self.ram_index(address) {
+            Some(index) => {
+                self.ram[index] = value;
+                true
+            },
+
+            None => false,
+        }
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        let size = self.ram.len().min(data.len());
+
+        self.ram[..size].copy_from_slice(&data[..size]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::mbc::infrared::Loopback;
+
+    fn rom() -> Cartridge {
+        let mut data = vec![0u8; 0x40 * 0x4000];
+
+        for bank in 0 .. 0x40 {
+            data[bank * 0x4000] = bank as u8;
+        }
+
+        data[0x147] = 0xFF;
+        data[0x149] = 0x03;
+        Cartridge::new(data).unwrap()
+    }
+
+    #[test]
+    fn it_switches_rom_and_ram_banks() {
+        let mut mbc = HuC1::new(rom(), Box::new(Loopback::new()));
+
+        mbc.write_rom(0x2000, 0x3F);
+        assert_eq!(mbc.read_rom(0x4000), 0x3F);
+
+        mbc.write_rom(0x4000, 0x02);
+        mbc.write_ram(0xA000, 0x55);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0x00);
+
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.read_ram(0xA000), 0x55);
+    }
+
+    #[test]
+    fn it_loops_back_the_infrared_led() {
+        let mut mbc = HuC1::new(rom(), Box::new(Loopback::new()));
+        mbc.write_ram(0xA000, 0x12);
+
+        mbc.write_rom(0x0000, 0x0E);
+        assert_eq!(mbc.read_ram(0xA000), 0xC0);
+
+        mbc.write_ram(0xA000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 0xC1);
+
+        // the RAM was not touched by the infrared writes
+        mbc.write_rom(0x0000, 0x0A);
+        assert_eq!(mbc.read_ram(0xA000), 0x12);
+    }
+}
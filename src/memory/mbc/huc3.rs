@@ -0,0 +1,447 @@
+use memory::mbc::{MBC, unix_time, TICKS_PER_SECOND, RtcClock};
+use memory::cartridge::Cartridge;
+use memory::mbc::infrared::Infrared;
+
+/// Minutes in a day, the HuC3 clock counts minutes and days
+const MINUTES_PER_DAY: u32 = 1440;
+
+/// Size of the clock footer appended to the save file
+///
+/// Minutes and days as 32-bit little endian values, followed
+/// by the 64-bit UNIX timestamp of the save
+const RTC_FOOTER_SIZE: usize = 16;
+
+/// HuC3
+///
+/// Hudson Soft's later MBC. It holds up to 2MB of ROM and 32kb of
+/// RAM, plus an infrared port and a clock. The clock is not mapped
+/// directly, instead it's driven by a small command interface.
+///
+/// The value written to 0x0000-0x1FFF selects what's mapped
+/// into the RAM area:
+///
+/// * 0x00 -> RAM, read only
+/// * 0x0A -> RAM, read and write
+/// * 0x0B -> Clock command/argument (write)
+/// * 0x0C -> Clock command response (read)
+/// * 0x0D -> Clock semaphore, writing bit 0 low runs the command
+/// * 0x0E -> Infrared
+pub struct HuC3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rom_bank: usize,
+    ram_bank: usize,
+    battery: bool,
+    mode: u8,
+    infrared: Box<dyn Infrared>,
+
+    rtc_clock: RtcClock,
+
+    /// Minutes since the start of the day (0-1439)
+    minutes: u32,
+
+    /// Day counter (12 bits)
+    days: u32,
+
+    /// Cycles accumulated towards the next clock second
+    rtc_ticks: u32,
+
+    /// Seconds accumulated towards the next clock minute
+    rtc_seconds: u32,
+
+    /// Host time (UNIX seconds) the clock was last updated
+    rtc_timestamp: u64,
+
+    /// Clock memory
+    ///
+    /// 256 half-bytes, accessed through the commands. The time
+    /// is copied in and out of the first 6 positions
+    rtc_memory: [u8; 0x100],
+
+    /// Clock memory address used by the read/write commands
+    rtc_address: u8,
+
+    rtc_command: u8,
+    rtc_argument: u8,
+    rtc_response: u8,
+}
+
+impl HuC3 {
+    pub fn new(cartridge: Cartridge, rtc_clock: RtcClock, infrared: Box<dyn Infrared>) -> HuC3 {
+        let ramsize = cartridge.header.ram_size();
+        let battery = cartridge.header.has_battery();
+
+        HuC3 {
+            rom: cartridge.rom,
+            ram: vec![0; ramsize],
+            rom_bank: 1,
+            ram_bank: 0,
+            battery,
+            mode: 0,
+            infrared,
+            rtc_clock,
+            minutes: 0,
+            days: 0,
+            rtc_ticks: 0,
+            rtc_seconds: 0,
+            rtc_timestamp: unix_time(),
+            rtc_memory: [0; 0x100],
+            rtc_address: 0,
+            rtc_command: 0,
+            rtc_argument: 0,
+            rtc_response: 0,
+        }
+    }
+
+    fn ram_index(&self, address: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None
+        }
+
+        let index = (self.ram_bank * 0x2000) | ((address & 0x1FFF) as usize);
+
+        Some(index % self.ram.len())
+    }
+
+    fn advance_seconds(&mut self, seconds: u64) {
+        let seconds = self.rtc_seconds as u64 + seconds;
+
+        self.rtc_seconds = (seconds % 60) as u32;
+        self.advance_minutes(seconds / 60);
+    }
+
+    fn advance_minutes(&mut self, minutes: u64) {
+        let minutes = self.minutes as u64 + minutes;
+
+        self.minutes = (minutes % MINUTES_PER_DAY as u64) as u32;
+        self.days = ((self.days as u64 + minutes / MINUTES_PER_DAY as u64) & 0xFFF) as u32;
+    }
+
+    /// Brings the clock up to date with the host clock
+    fn update_rtc(&mut self) {
+        if self.rtc_clock != RtcClock::WallClock {
+            return
+        }
+
+        let now = unix_time();
+
+        if now > self.rtc_timestamp {
+            self.advance_seconds(now - self.rtc_timestamp);
+        }
+
+        self.rtc_timestamp = now;
+    }
+
+    /// Runs the clock command
+    ///
+    /// The upper nibble written in mode 0x0B is the command,
+    /// the lower one is its argument:
+    ///
+    /// * 0x1 -> Read the clock memory and increase the address
+    /// * 0x3 -> Write the argument to the clock memory and increase the address
+    /// * 0x4 -> Set the lower nibble of the address
+    /// * 0x5 -> Set the upper nibble of the address
+    /// * 0x6 -> Extended commands, selected by the argument:
+    ///   0 copies the time into the memory, 1 sets the time
+    ///   from the memory and 2 returns a ready status
+    fn execute_rtc_command(&mut self) {
+        match self.rtc_command {
+            0x1 => {
+                self.rtc_response = self.rtc_memory[self.rtc_address as usize] & 0x0F;
+                self.rtc_address = self.rtc_address.wrapping_add(1);
+            },
+
+            0x3 => {
+                self.rtc_memory[self.rtc_address as usize] = self.rtc_argument;
+                self.rtc_address = self.rtc_address.wrapping_add(1);
+            },
+
+            0x4 => {
+                self.rtc_address = (self.rtc_address & 0xF0) | self.rtc_argument;
+            },
+
+            0x5 => {
+                self.rtc_address = (self.rtc_address & 0x0F) | (self.rtc_argument << 4);
+            },
+
+            0x6 => match self.rtc_argument {
+                0x0 => {
+                    self.update_rtc();
+
+                    // minutes and days, 3 nibbles each, lower nibble first
+                    for i in 0 .. 3 {
+                        self.rtc_memory[i] = ((self.minutes >> (i * 4)) & 0x0F) as u8;
+                        self.rtc_memory[3 + i] = ((self.days >> (i * 4)) & 0x0F) as u8;
+                    }
+                },
+
+                0x1 => {
+                    self.update_rtc();
+
+                    let mut minutes = 0;
+                    let mut days = 0;
+
+                    for i in 0 .. 3 {
+                        minutes |= (self.rtc_memory[i] as u32 & 0x0F) << (i * 4);
+                        days |= (self.rtc_memory[3 + i] as u32 & 0x0F) << (i * 4);
+                    }
+
+                    self.minutes = minutes % MINUTES_PER_DAY;
+                    self.days = days;
+                    self.rtc_seconds = 0;
+                },
+
+                0x2 => {
+                    self.rtc_response = 0x1;
+                },
+
+                _ => (),
+            },
+
+            _ => (),
+        }
+    }
+}
+
+impl MBC for HuC3 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let index =
+            if address < 0x4000 {
+                address as usize
+            } else {
+                (self.rom_bank * 0x4000) | ((address as usize) & 0x3FFF)
+            };
+
+        self.rom[index % self.rom.len()]
+    }
+
+    fn rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 {
+            0
+        } else {
+            self.rom_bank % (self.rom.len() / 0x4000)
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000 ..= 0x1FFF => {
+                self.mode = value & 0x0F;
+            },
+
+            0x2000 ..= 0x3FFF => {
+                self.rom_bank = match (value as usize) & 0x7F {
+                    0 => 1,
+                    n => n,
+                };
+            },
+
+            0x4000 ..= 0x5FFF => {
+                self.ram_bank = (value as usize) & 0x03;
+            },
+
+            0x6000 ..= 0x7FFF => (),
+
+            _ => panic!("Could not write to {:04X} (HuC3)", address),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        match self.mode {
+            0x00 | 0x0A => match self.ram_index(address) {
+                Some(index) => self.ram[index],
+                None => 0xFF,
+            },
+
+            0x0C => 0x80 | (self.rtc_command << 4) | self.rtc_response,
+
+            // commands run instantly, so we are always ready
+            0x0D => 0xFF,
+
+            0x0E => 0xC0 | if self.infrared.light() { 1 } else { 0 },
+
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        match self.mode {
+            0x0A => match self.ram_index(address) {
+                Some(index) => {
+                    self.ram[index] = value;
+                    true
+                },
+
+                None => false,
+            },
+
+            0x0B => {
+                self.rtc_command = (value >> 4) & 0x07;
+                self.rtc_argument = value & 0x0F;
+                false
+            },
+
+            // the command may have set the clock
+            0x0D if value & 0x01 == 0 => {
+                self.execute_rtc_command();
+                true
+            },
+
+            0x0E => {
+                self.infrared.set_led(value & 0x01 == 0x01);
+                false
+            },
+
+            _ => false,
+        }
+    }
+
+    fn step(&mut self, ticks: u32) {
+        if self.rtc_clock != RtcClock::Cycles {
+            return
+        }
+
+        self.rtc_ticks += ticks;
+
+        while self.rtc_ticks >= TICKS_PER_SECOND {
+            self.rtc_ticks -= TICKS_PER_SECOND;
+            self.advance_seconds(1);
+        }
+    }
+
+    fn set_rtc_clock(&mut self, rtc_clock: RtcClock) {
+        // catch up with the host clock before leaving it
+        self.update_rtc();
+
+        self.rtc_clock = rtc_clock;
+        self.rtc_ticks = 0;
+        self.rtc_timestamp = unix_time();
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+
+        data.extend_from_slice(&self.minutes.to_le_bytes());
+        data.extend_from_slice(&self.days.to_le_bytes());
+        data.extend_from_slice(&self.rtc_timestamp.to_le_bytes());
+
+        data
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        let size = self.ram.len().min(data.len());
+
+        self.ram[..size].copy_from_slice(&data[..size]);
+
+        let footer = &data[size..];
+
+        if footer.len() != RTC_FOOTER_SIZE {
+            return
+        }
+
+        let mut minutes = [0u8; 4];
+        let mut days = [0u8; 4];
+        let mut timestamp = [0u8; 8];
+
+        minutes.copy_from_slice(&footer[0 .. 4]);
+        days.copy_from_slice(&footer[4 .. 8]);
+        timestamp.copy_from_slice(&footer[8 .. 16]);
+
+        self.minutes = u32::from_le_bytes(minutes) % MINUTES_PER_DAY;
+        self.days = u32::from_le_bytes(days) & 0xFFF;
+        self.rtc_timestamp = u64::from_le_bytes(timestamp);
+
+        // the clock kept running while the game was off
+        self.update_rtc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::mbc::infrared::Loopback;
+
+    fn rom() -> Cartridge {
+        let mut data = vec![0u8; 0x80 * 0x4000];
+        data[0x147] = 0xFE;
+        data[0x149] = 0x03;
+        Cartridge::new(data).unwrap()
+    }
+
+    fn command(mbc: &mut HuC3, value: u8) -> u8 {
+        mbc.write_rom(0x0000, 0x0B);
+        mbc.write_ram(0xA000, value);
+        mbc.write_rom(0x0000, 0x0D);
+        mbc.write_ram(0xA000, 0xFE);
+        mbc.write_rom(0x0000, 0x0C);
+        mbc.read_ram(0xA000) & 0x0F
+    }
+
+    #[test]
+    fn it_reads_the_time_through_commands() {
+        let mut mbc = HuC3::new(rom(), RtcClock::Cycles, Box::new(Loopback::new()));
+
+        // one day, two hours and three minutes
+        for _ in 0 .. MINUTES_PER_DAY + 123 {
+            mbc.step(TICKS_PER_SECOND * 60);
+        }
+
+        command(&mut mbc, 0x60);
+        command(&mut mbc, 0x40);
+        command(&mut mbc, 0x50);
+
+        let minutes = (0 .. 3).fold(0, |minutes, i| minutes | ((command(&mut mbc, 0x10) as u32) << (i * 4)));
+        let days = (0 .. 3).fold(0, |days, i| days | ((command(&mut mbc, 0x10) as u32) << (i * 4)));
+
+        assert_eq!(minutes, 123);
+        assert_eq!(days, 1);
+    }
+
+    #[test]
+    fn it_sets_the_time_through_commands() {
+        let mut mbc = HuC3::new(rom(), RtcClock::Cycles, Box::new(Loopback::new()));
+
+        command(&mut mbc, 0x40);
+        command(&mut mbc, 0x50);
+
+        // 0x05A minutes, 0x003 days
+        for nibble in [0xA, 0x5, 0x0, 0x3, 0x0, 0x0].iter() {
+            command(&mut mbc, 0x30 | nibble);
+        }
+
+        command(&mut mbc, 0x61);
+
+        assert_eq!(mbc.minutes, 0x5A);
+        assert_eq!(mbc.days, 3);
+        assert_eq!(command(&mut mbc, 0x62), 1);
+    }
+
+    #[test]
+    fn it_loops_back_the_infrared_led() {
+        let mut mbc = HuC3::new(rom(), RtcClock::Cycles, Box::new(Loopback::new()));
+
+        mbc.write_rom(0x0000, 0x0E);
+        assert_eq!(mbc.read_ram(0xA000), 0xC0);
+
+        mbc.write_ram(0xA000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 0xC1);
+    }
+
+    #[test]
+    fn it_saves_the_clock_in_the_footer() {
+        let mut mbc = HuC3::new(rom(), RtcClock::Cycles, Box::new(Loopback::new()));
+
+        for _ in 0 .. 42 {
+            mbc.step(TICKS_PER_SECOND * 60);
+        }
+
+        let mut restored = HuC3::new(rom(), RtcClock::Cycles, Box::new(Loopback::new()));
+        restored.load_ram(&mbc.dump_ram());
+
+        assert_eq!(restored.minutes, 42);
+    }
+}
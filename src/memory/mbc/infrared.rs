@@ -0,0 +1,40 @@
+/// Infrared port
+///
+/// Some Hudson cartridges (HuC1, HuC3) include an infrared LED and
+/// a light sensor, used to exchange data between two GameBoys or
+/// with other devices. This is the other end of that link.
+pub trait Infrared {
+    /// Turns the cartridge LED on or off
+    fn set_led(&mut self, on: bool);
+
+    /// Whether the sensor is receiving light
+    fn light(&self) -> bool;
+}
+
+/// Loopback infrared link
+///
+/// The sensor sees the cartridge's own LED, as if it was
+/// pointed at a mirror. Good enough for games to pass their
+/// infrared self-checks, and for testing
+#[derive(Default)]
+pub struct Loopback {
+    led: bool,
+}
+
+impl Loopback {
+    pub fn new() -> Loopback {
+        Loopback {
+            led: false,
+        }
+    }
+}
+
+impl Infrared for Loopback {
+    fn set_led(&mut self, on: bool) {
+        self.led = on;
+    }
+
+    fn light(&self) -> bool {
+        self.led
+    }
+}
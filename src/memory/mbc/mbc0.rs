@@ -1,7 +1,16 @@
 use memory::mbc::MBC;
+use memory::cartridge::Cartridge;
 
 pub struct MBC0 {
     rom: Vec<u8>,
+
+    /// External RAM, only in ROM+RAM cartridges
+    ///
+    /// There is no register to enable it, it's always mapped
+    /// at 0xA000-0xBFFF (8k bytes at most)
+    ram: Vec<u8>,
+
+    battery: bool,
 }
 
 /// This is the implementation for no MBC
@@ -10,9 +19,19 @@ pub struct MBC0 {
 /// MBC implementation actually maps the requested
 /// ROM addresses 1:1 with the ROM data
 impl MBC0 {
-    pub fn new(data: Vec<u8>) -> MBC0 {
+    pub fn new(cartridge: Cartridge) -> MBC0 {
+        let ramsize = if cartridge.header.has_ram() {
+            cartridge.header.ram_size().min(0x2000)
+        } else {
+            0
+        };
+
+        let battery = cartridge.header.has_battery();
+
         MBC0 {
-            rom: data
+            rom: cartridge.rom,
+            ram: vec![0; ramsize],
+            battery,
         }
     }
 }
@@ -28,11 +47,64 @@ impl MBC for MBC0 {
         ()
     }
 
-    fn read_ram(&self, _address: u16) -> u8 {
-        0
+    fn read_ram(&self, address: u16) -> u8 {
+        if self.ram.is_empty() {
+            return 0
+        }
+
+        self.ram[(address & 0x1FFF) as usize % self.ram.len()]
     }
 
-    fn write_ram(&mut self, _address: u16, _value: u8) {
-        ()
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        if self.ram.is_empty() {
+            return false
+        }
+
+        let index = (address & 0x1FFF) as usize % self.ram.len();
+        self.ram[index] = value;
+        true
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        let size = self.ram.len().min(data.len());
+
+        self.ram[..size].copy_from_slice(&data[..size]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(cartridge_type: u8) -> Cartridge {
+        let mut data = vec![0u8; 0x8000];
+        data[0x147] = cartridge_type;
+        data[0x149] = 0x02;
+        Cartridge::new(data).unwrap()
+    }
+
+    #[test]
+    fn it_maps_the_ram_of_rom_ram_cartridges() {
+        let mut mbc = MBC0::new(rom(0x09));
+        mbc.write_ram(0xA123, 0x42);
+
+        assert!(mbc.has_battery());
+        assert_eq!(mbc.read_ram(0xA123), 0x42);
+        assert_eq!(mbc.dump_ram().len(), 0x2000);
+
+        let mut mbc = MBC0::new(rom(0x00));
+        mbc.write_ram(0xA123, 0x42);
+
+        assert!(!mbc.has_battery());
+        assert_eq!(mbc.read_ram(0xA123), 0);
+        assert!(mbc.dump_ram().is_empty());
     }
 }
@@ -1,25 +1,40 @@
-use memory::mbc::{MBC, ram_size};
+use memory::mbc::MBC;
+use memory::cartridge::{Cartridge, NINTENDO_LOGO};
 
 /// MBC 1
 ///
 /// This is the first and most primitive MBC1 used in games
 /// It holds a maximum of 2MB of ROM and 32kb of RAM
+///
+/// The bank number is split in two registers: BANK1 (5 bits)
+/// and BANK2 (2 bits). BANK2 is used as the upper bits of the ROM
+/// bank, or as the RAM bank, depending on the banking mode:
+///
+/// * Mode 0 -> BANK2 only applies to the 0x4000-0x7FFF ROM area
+/// * Mode 1 -> BANK2 also applies to 0x0000-0x3FFF and to the RAM
+///
+/// Multicarts (MBC1M) wire BANK2 one bit lower, so it selects
+/// one of four 256kb games in a 1MB ROM
 pub struct MBC1 {
     rom: Vec<u8>,
     ram: Vec<u8>,
     ram_on: bool,
     ram_mode: bool,
-    rom_bank: usize,
-    ram_bank: usize,
+    bank1: usize,
+    bank2: usize,
+    battery: bool,
+
+    /// Whether this is a multicart (MBC1M)
+    multicart: bool,
 }
 
 
 impl MBC1 {
-    pub fn new(data: Vec<u8>,) -> MBC1 {
-        let ramsize = match data[0x147] {
-            0x02 => ram_size(data[0x149]),
-            0x03 => ram_size(data[0x149]),
-            _ => 0,
+    pub fn new(cartridge: Cartridge) -> MBC1 {
+        let ramsize = if cartridge.header.has_ram() {
+            cartridge.header.ram_size()
+        } else {
+            0
         };
 
         let mut initial_ram = Vec::with_capacity(ramsize);
@@ -28,56 +43,124 @@ impl MBC1 {
             initial_ram.push(0u8);
         }
 
+        let battery = cartridge.header.has_battery();
+        let multicart = MBC1::is_multicart(&cartridge.rom);
+
         MBC1 {
-            rom: data,
+            rom: cartridge.rom,
             ram: initial_ram,
             ram_on: false,
             ram_mode: false,
-            rom_bank: 1,
-            ram_bank: 0,
+            bank1: 1,
+            bank2: 0,
+            battery,
+            multicart,
+        }
+    }
+
+    /// Detects multicarts
+    ///
+    /// There is no header flag for MBC1M, but each of the games
+    /// inside a 1MB multicart has its own header, so we look for
+    /// the Nintendo logo repeated at the 256kb boundaries
+    fn is_multicart(data: &[u8]) -> bool {
+        if data.len() != 0x100000 {
+            return false
+        }
+
+        let logos = (0 .. 4)
+            .map(|game| game * 0x40000 + 0x104)
+            .filter(|&address| data[address .. address + NINTENDO_LOGO.len()] == NINTENDO_LOGO[..])
+            .count();
+
+        logos > 1
+    }
+
+    /// Bits BANK2 is shifted by when building the bank number
+    fn bank2_shift(&self) -> usize {
+        if self.multicart { 4 } else { 5 }
+    }
+
+    /// ROM bank mapped at 0x0000-0x3FFF
+    fn rom_bank_low(&self) -> usize {
+        if self.ram_mode {
+            self.bank2 << self.bank2_shift()
+        } else {
+            0
+        }
+    }
+
+    /// ROM bank mapped at 0x4000-0x7FFF
+    fn rom_bank_high(&self) -> usize {
+        let bank1 = if self.multicart {
+            self.bank1 & 0x0F
+        } else {
+            self.bank1
+        };
+
+        (self.bank2 << self.bank2_shift()) | bank1
+    }
+
+    fn ram_index(&self, address: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None
         }
+
+        let ram_bank = if self.ram_mode {
+            self.bank2
+        } else {
+            0
+        };
+
+        // smaller RAMs ignore the upper bank bits
+        Some(((ram_bank * 0x2000) | ((address & 0x1FFF) as usize)) % self.ram.len())
     }
 }
 
 impl MBC for MBC1 {
     fn read_rom(&self, address: u16) -> u8 {
-
-        let index =
+        let bank =
             if address < 0x4000 {
-                address as usize
+                self.rom_bank_low()
             } else {
-                self.rom_bank * 0x4000 | ((address as usize) & 0x3FFF)
+                self.rom_bank_high()
             };
 
-        let not_found_value = 0u8;
+        // bank numbers bigger than the ROM wrap around,
+        // as the upper bank lines are not connected
+        let index = (bank * 0x4000) | ((address as usize) & 0x3FFF);
 
-        // get this position value, or 0 if it's not found
-        let rom_byte = self.rom.get(index).unwrap_or(&not_found_value);
+        self.rom[index % self.rom.len()]
+    }
 
-        *rom_byte
+    fn rom_bank(&self, address: u16) -> usize {
+        let bank =
+            if address < 0x4000 {
+                self.rom_bank_low()
+            } else {
+                self.rom_bank_high()
+            };
+
+        bank % (self.rom.len() / 0x4000)
     }
 
     fn write_rom(&mut self, a: u16, v: u8) {
         match a {
             0x0000 ..= 0x1FFF => {
-                self.ram_on = v == 0x0A;
+                self.ram_on = v & 0x0F == 0x0A;
             },
 
+            // the zero check is done with the 5 bits, even if
+            // multicarts only use 4 of them
             0x2000 ..= 0x3FFF => {
-                self.rom_bank =
-                    (self.rom_bank & 0x60) |
-                    match (v as usize) & 0x1F {
-                        0 => 1,
-                        n => n
-                    }
+                self.bank1 = match (v as usize) & 0x1F {
+                    0 => 1,
+                    n => n
+                }
             },
 
             0x4000 ..= 0x5FFF => {
-                if !self.ram_mode {
-                    self.rom_bank = self.rom_bank & 0x1F | (((v as usize) & 0x03) << 5)
-                } else {
-                    self.ram_bank = (v as usize) & 0x03;
-                }
+                self.bank2 = (v as usize) & 0x03;
             },
 
             0x6000 ..= 0x7FFF => {
@@ -90,29 +173,157 @@ impl MBC for MBC1 {
 
     fn read_ram(&self, address: u16) -> u8 {
         if !self.ram_on {
-            return 0
+            return 0xFF
         }
 
-        let ram_bank = if self.ram_mode {
-            self.ram_bank
-        } else {
-            0
-        };
-
-        self.ram[(ram_bank * 0x2000) | ((address & 0x1FFF) as usize)]
+        match self.ram_index(address) {
+            Some(index) => self.ram[index],
+            None => 0xFF,
+        }
     }
 
-    fn write_ram(&mut self, address: u16, v: u8) {
+    fn write_ram(&mut self, address: u16, v: u8) -> bool {
         if !self.ram_on {
-            return
+            return false
         }
 
-        let ram_bank = if self.ram_mode {
-            self.ram_bank
-        } else {
-            0
-        };
+        match self.ram_index(address) {
+            Some(index) => {
+                self.ram[index] = v;
+                true
+            },
+
+            None => false,
+        }
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        // ignore anything past our RAM size, save files
+        // from other emulators may carry extra data
+        let size = self.ram.len().min(data.len());
+
+        self.ram[..size].copy_from_slice(&data[..size]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(cartridge_type: u8) -> Cartridge {
+        let mut data = vec![0u8; 0x8000];
+        data[0x147] = cartridge_type;
+        data[0x149] = 0x02;
+        Cartridge::new(data).unwrap()
+    }
+
+    #[test]
+    fn it_keeps_ram_through_dump_and_load() {
+        let mut mbc = MBC1::new(rom(0x03));
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0xA123, 0x42);
+
+        let mut restored = MBC1::new(rom(0x03));
+        restored.load_ram(&mbc.dump_ram());
+        restored.write_rom(0x0000, 0x0A);
+
+        assert!(restored.has_battery());
+        assert_eq!(restored.read_ram(0xA123), 0x42);
+    }
+
+    #[test]
+    fn it_only_accepts_ram_writes_while_enabled() {
+        let mut mbc = MBC1::new(rom(0x03));
+        assert!(!mbc.write_ram(0xA000, 0x42));
+
+        mbc.write_rom(0x0000, 0x0A);
+        assert!(mbc.write_ram(0xA000, 0x42));
+
+        mbc.write_rom(0x0000, 0x00);
+        assert!(!mbc.write_ram(0xA000, 0x42));
+    }
+
+    #[test]
+    fn it_has_no_battery_without_battery_type() {
+        assert!(!MBC1::new(rom(0x02)).has_battery());
+    }
+
+    fn banked_data(banks: usize) -> Vec<u8> {
+        let mut data = vec![0u8; banks * 0x4000];
+
+        for bank in 0 .. banks {
+            data[bank * 0x4000 + 0x1000] = bank as u8;
+        }
+
+        data[0x147] = 0x01;
+        data
+    }
+
+    #[test]
+    fn it_maps_bank2_to_low_rom_in_mode_1() {
+        let mut mbc = MBC1::new(Cartridge::new(banked_data(128)).unwrap());
+
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.read_rom(0x1000), 0x00);
+        assert_eq!(mbc.read_rom(0x5000), 0x41);
+
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(0x1000), 0x40);
+        assert_eq!(mbc.read_rom(0x5000), 0x41);
+    }
+
+    #[test]
+    fn it_wraps_banks_bigger_than_the_rom() {
+        let mut mbc = MBC1::new(Cartridge::new(banked_data(4)).unwrap());
+
+        mbc.write_rom(0x2000, 0x06);
+        assert_eq!(mbc.read_rom(0x5000), 0x02);
+
+        // bank 0x20 is remapped to 0x21, and wraps to 1
+        mbc.write_rom(0x2000, 0x00);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_rom(0x5000), 0x01);
+    }
+
+    #[test]
+    fn it_returns_ff_with_ram_disabled() {
+        let mut mbc = MBC1::new(rom(0x03));
+
+        mbc.write_rom(0x0000, 0x1A);
+        mbc.write_ram(0xA000, 0x12);
+        assert_eq!(mbc.read_ram(0xA000), 0x12);
+
+        mbc.write_rom(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn it_detects_multicarts() {
+        let mut data = banked_data(64);
+
+        for game in 0 .. 4 {
+            let address = game * 0x40000 + 0x104;
+            data[address .. address + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+        }
+
+        let mut mbc = MBC1::new(Cartridge::new(data).unwrap());
+        assert!(mbc.multicart);
+
+        mbc.write_rom(0x2000, 0x12);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_rom(0x5000), 0x12);
+
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(0x1000), 0x10);
 
-        self.ram[(ram_bank * 0x2000) | ((address & 0x1FFF) as usize)] = v;
+        assert!(!MBC1::new(Cartridge::new(banked_data(64)).unwrap()).multicart);
     }
 }
@@ -0,0 +1,171 @@
+use memory::mbc::MBC;
+use memory::cartridge::Cartridge;
+
+/// MBC2 built-in RAM size, 512 half-bytes
+const RAM_SIZE: usize = 0x200;
+
+/// MBC 2
+///
+/// It holds a maximum of 256kb of ROM (16 banks). Instead of
+/// external RAM, the chip itself contains 512x4 bits of RAM.
+///
+/// There is a single register range (0x0000-0x3FFF) and bit 8
+/// of the address selects what is written: RAM enable when clear,
+/// ROM bank when set
+pub struct MBC2 {
+    rom: Vec<u8>,
+
+    /// Built-in RAM
+    ///
+    /// Only the lower 4 bits of each byte are stored, the
+    /// upper ones are always read back as 1
+    ram: [u8; RAM_SIZE],
+
+    ram_on: bool,
+    rom_bank: usize,
+    battery: bool,
+}
+
+impl MBC2 {
+    pub fn new(cartridge: Cartridge) -> MBC2 {
+        let battery = cartridge.header.has_battery();
+
+        MBC2 {
+            rom: cartridge.rom,
+            ram: [0; RAM_SIZE],
+            ram_on: false,
+            rom_bank: 1,
+            battery,
+        }
+    }
+}
+
+impl MBC for MBC2 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let index =
+            if address < 0x4000 {
+                address as usize
+            } else {
+                (self.rom_bank * 0x4000) | ((address as usize) & 0x3FFF)
+            };
+
+        self.rom[index % self.rom.len()]
+    }
+
+    fn rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 {
+            0
+        } else {
+            self.rom_bank % (self.rom.len() / 0x4000)
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000 ..= 0x3FFF => {
+                if address & 0x0100 == 0 {
+                    self.ram_on = value & 0x0F == 0x0A;
+                } else {
+                    self.rom_bank = match (value as usize) & 0x0F {
+                        0 => 1,
+                        n => n,
+                    };
+                }
+            },
+
+            // there are no more registers
+            0x4000 ..= 0x7FFF => (),
+
+            _ => panic!("Could not write to {:04X} (MBC2)", address),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_on {
+            return 0xFF
+        }
+
+        // the RAM is echoed over the whole 0xA000-0xBFFF range
+        self.ram[(address as usize) & 0x01FF] | 0xF0
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        if !self.ram_on {
+            return false
+        }
+
+        self.ram[(address as usize) & 0x01FF] = value & 0x0F;
+        true
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        self.ram.to_vec()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        for (ram, value) in self.ram.iter_mut().zip(data.iter()) {
+            *ram = value & 0x0F;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom() -> Cartridge {
+        let mut data = vec![0u8; 0x10 * 0x4000];
+
+        for bank in 0 .. 0x10 {
+            data[bank * 0x4000] = bank as u8;
+        }
+
+        data[0x147] = 0x06;
+        Cartridge::new(data).unwrap()
+    }
+
+    #[test]
+    fn it_selects_register_with_address_bit_8() {
+        let mut mbc = MBC2::new(rom());
+
+        // bit 8 clear, this enables the RAM instead of switching banks
+        mbc.write_rom(0x2000, 0x0A);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+
+        mbc.write_rom(0x2100, 0x05);
+        assert_eq!(mbc.read_rom(0x4000), 5);
+
+        mbc.write_ram(0xA000, 0x0B);
+        assert_eq!(mbc.read_ram(0xA000), 0xFB);
+    }
+
+    #[test]
+    fn it_stores_half_bytes_and_echoes_ram() {
+        let mut mbc = MBC2::new(rom());
+        mbc.write_rom(0x0000, 0x0A);
+
+        mbc.write_ram(0xA005, 0xAB);
+
+        assert_eq!(mbc.read_ram(0xA005), 0xFB);
+        assert_eq!(mbc.read_ram(0xA205), 0xFB);
+        assert_eq!(mbc.read_ram(0xBE05), 0xFB);
+    }
+
+    #[test]
+    fn it_keeps_ram_through_dump_and_load() {
+        let mut mbc = MBC2::new(rom());
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0xA1FF, 0x07);
+
+        let mut restored = MBC2::new(rom());
+        restored.load_ram(&mbc.dump_ram());
+        restored.write_rom(0x0000, 0x0A);
+
+        assert!(restored.has_battery());
+        assert_eq!(restored.read_ram(0xA1FF), 0xF7);
+    }
+}
@@ -0,0 +1,536 @@
+use std::str::FromStr;
+use memory::mbc::{MBC, unix_time, TICKS_PER_SECOND};
+use memory::cartridge::Cartridge;
+
+/// Size of the RTC footer appended to the save file (BGB layout)
+const RTC_FOOTER_SIZE: usize = 48;
+
+/// Size of the older RTC footer, with a 32-bit timestamp (VBA layout)
+const RTC_FOOTER_SIZE_32: usize = 44;
+
+/// RTC time source
+///
+/// The clock inside the cartridge keeps running even when the GameBoy
+/// is off. We can follow the host wall-clock (like a real cartridge) or
+/// count emulated cycles, which makes the clock deterministic for tests
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum RtcClock {
+    WallClock,
+    Cycles,
+}
+
+impl FromStr for RtcClock {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RtcClock, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "wall" => Ok(RtcClock::WallClock),
+            "cycles" => Ok(RtcClock::Cycles),
+            _ => Err(format!("Unknown RTC clock {}, expected wall or cycles", s)),
+        }
+    }
+}
+
+/// RTC registers
+///
+/// These are the five clock registers, mapped into the RAM area
+/// when selecting banks 0x08-0x0C:
+///
+/// * 0x08 -> Seconds (0-59)
+/// * 0x09 -> Minutes (0-59)
+/// * 0x0A -> Hours (0-23)
+/// * 0x0B -> Lower 8 bits of the day counter
+/// * 0x0C -> Bit 0: day counter bit 8, Bit 6: halt, Bit 7: day counter carry
+#[derive(PartialEq, Copy, Clone, Debug)]
+struct RtcRegisters {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16,
+    halt: bool,
+    day_carry: bool,
+}
+
+impl RtcRegisters {
+    fn new() -> RtcRegisters {
+        RtcRegisters {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halt: false,
+            day_carry: false,
+        }
+    }
+
+    fn read(&self, register: u8) -> u8 {
+        match register {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => self.days as u8,
+            _ => {
+                ((self.days >> 8) as u8 & 0x01) |
+                    (if self.halt      { 0x40 } else { 0 }) |
+                    (if self.day_carry { 0x80 } else { 0 })
+            },
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        // the registers only have as many bits as they need, so
+        // out of range values (60-63 seconds, etc.) can be written
+        match register {
+            0x08 => self.seconds = value & 0x3F,
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | value as u16,
+            _ => {
+                self.days = (self.days & 0xFF) | ((value as u16 & 0x01) << 8);
+                self.halt = value & 0x40 == 0x40;
+                self.day_carry = value & 0x80 == 0x80;
+            },
+        }
+    }
+
+    /// Advances the clock by one second
+    ///
+    /// Each counter only carries over into the next one when it
+    /// reaches its real limit, out of range values just wrap around
+    /// their bits without carrying (as the hardware does)
+    fn tick(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+
+        if self.seconds != 60 {
+            return
+        }
+
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+
+        if self.minutes != 60 {
+            return
+        }
+
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+
+        if self.hours != 24 {
+            return
+        }
+
+        self.hours = 0;
+        self.add_days(1);
+    }
+
+    /// Advances the clock by the given amount of seconds
+    fn advance(&mut self, mut seconds: u64) {
+        // tick one by one until every counter is back in range,
+        // then we can do the rest with plain arithmetic
+        while seconds > 0 && (self.seconds >= 60 || self.minutes >= 60 || self.hours >= 24) {
+            self.tick();
+            seconds -= 1;
+        }
+
+        let total = self.seconds as u64 +
+            self.minutes as u64 * 60 +
+            self.hours as u64 * 3600 +
+            seconds;
+
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        self.add_days(total / 86400);
+    }
+
+    fn add_days(&mut self, days: u64) {
+        let days = self.days as u64 + days;
+
+        // the day counter is 9 bits, overflowing it sets the
+        // carry bit, which stays set until the game clears it
+        if days > 0x1FF {
+            self.day_carry = true;
+        }
+
+        self.days = (days & 0x1FF) as u16;
+    }
+
+    fn write_footer(&self, footer: &mut Vec<u8>) {
+        for register in 0x08 .. 0x0D {
+            footer.extend_from_slice(&(self.read(register) as u32).to_le_bytes());
+        }
+    }
+
+    fn read_footer(footer: &[u8]) -> RtcRegisters {
+        let mut registers = RtcRegisters::new();
+
+        for (i, register) in (0x08 .. 0x0D).enumerate() {
+            registers.write(register, footer[i * 4]);
+        }
+
+        registers
+    }
+}
+
+/// MBC 3
+///
+/// Used by many later games (Pokémon Gold/Silver among them), it
+/// holds up to 2MB of ROM and 32kb of RAM (4 banks). Some variants
+/// include a battery-backed Real Time Clock (RTC)
+pub struct MBC3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_on: bool,
+    rom_bank: usize,
+
+    /// RAM bank or RTC register
+    ///
+    /// 0x00-0x03 select a RAM bank, 0x08-0x0C map
+    /// one of the RTC registers into the RAM area
+    ram_bank: u8,
+
+    battery: bool,
+    has_rtc: bool,
+    rtc_clock: RtcClock,
+
+    /// Live RTC registers, these keep counting
+    rtc: RtcRegisters,
+
+    /// Latched RTC registers, these are what the game reads
+    rtc_latched: RtcRegisters,
+
+    /// Last value written to the latch register, latching
+    /// happens when writing 0x00 and then 0x01
+    rtc_latch: u8,
+
+    /// Cycles accumulated towards the next RTC second
+    rtc_ticks: u32,
+
+    /// Host time (UNIX seconds) the live registers were last updated
+    rtc_timestamp: u64,
+}
+
+impl MBC3 {
+    pub fn new(cartridge: Cartridge, rtc_clock: RtcClock) -> MBC3 {
+        let ramsize = if cartridge.header.has_ram() {
+            cartridge.header.ram_size()
+        } else {
+            0
+        };
+
+        let battery = cartridge.header.has_battery();
+        let has_rtc = cartridge.header.has_timer();
+
+        MBC3 {
+            rom: cartridge.rom,
+            ram: vec![0; ramsize],
+            ram_on: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            battery,
+            has_rtc,
+            rtc_clock,
+            rtc: RtcRegisters::new(),
+            rtc_latched: RtcRegisters::new(),
+            rtc_latch: 0xFF,
+            rtc_ticks: 0,
+            rtc_timestamp: unix_time(),
+        }
+    }
+
+    /// Brings the live RTC registers up to date with the host clock
+    fn update_rtc(&mut self) {
+        if self.rtc_clock != RtcClock::WallClock {
+            return
+        }
+
+        let now = unix_time();
+
+        if !self.rtc.halt && now > self.rtc_timestamp {
+            self.rtc.advance(now - self.rtc_timestamp);
+        }
+
+        self.rtc_timestamp = now;
+    }
+
+    fn ram_index(&self, address: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None
+        }
+
+        let index = ((self.ram_bank as usize & 0x03) * 0x2000) | ((address & 0x1FFF) as usize);
+
+        Some(index % self.ram.len())
+    }
+}
+
+impl MBC for MBC3 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let index =
+            if address < 0x4000 {
+                address as usize
+            } else {
+                (self.rom_bank * 0x4000) | ((address as usize) & 0x3FFF)
+            };
+
+        self.rom[index % self.rom.len()]
+    }
+
+    fn rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 {
+            0
+        } else {
+            self.rom_bank % (self.rom.len() / 0x4000)
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            // enables both the RAM and the RTC registers
+            0x0000 ..= 0x1FFF => {
+                self.ram_on = value & 0x0F == 0x0A;
+            },
+
+            // 7 bits of ROM bank, bank 0 is mapped to 1
+            0x2000 ..= 0x3FFF => {
+                self.rom_bank = match (value as usize) & 0x7F {
+                    0 => 1,
+                    n => n,
+                };
+            },
+
+            0x4000 ..= 0x5FFF => {
+                self.ram_bank = value;
+            },
+
+            // latch clock data: writing 0x00 and then 0x01 copies
+            // the live registers into the ones the game can read
+            0x6000 ..= 0x7FFF => {
+                if self.rtc_latch == 0x00 && value == 0x01 {
+                    self.update_rtc();
+                    self.rtc_latched = self.rtc;
+                }
+
+                self.rtc_latch = value;
+            },
+
+            _ => panic!("Could not write to {:04X} (MBC3)", address),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_on {
+            return 0xFF
+        }
+
+        match self.ram_bank {
+            0x00 ..= 0x03 => match self.ram_index(address) {
+                Some(index) => self.ram[index],
+                None => 0xFF,
+            },
+
+            0x08 ..= 0x0C if self.has_rtc => self.rtc_latched.read(self.ram_bank),
+
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        if !self.ram_on {
+            return false
+        }
+
+        match self.ram_bank {
+            0x00 ..= 0x03 => match self.ram_index(address) {
+                Some(index) => {
+                    self.ram[index] = value;
+                    true
+                },
+
+                None => false,
+            },
+
+            0x08 ..= 0x0C if self.has_rtc => {
+                self.update_rtc();
+
+                // writing the seconds resets the sub-second divider
+                if self.ram_bank == 0x08 {
+                    self.rtc_ticks = 0;
+                }
+
+                self.rtc.write(self.ram_bank, value);
+                true
+            },
+
+            _ => false,
+        }
+    }
+
+    fn step(&mut self, ticks: u32) {
+        if !self.has_rtc || self.rtc_clock != RtcClock::Cycles || self.rtc.halt {
+            return
+        }
+
+        self.rtc_ticks += ticks;
+
+        while self.rtc_ticks >= TICKS_PER_SECOND {
+            self.rtc_ticks -= TICKS_PER_SECOND;
+            self.rtc.tick();
+        }
+    }
+
+    fn set_rtc_clock(&mut self, rtc_clock: RtcClock) {
+        // catch up with the host clock before leaving it
+        self.update_rtc();
+
+        self.rtc_clock = rtc_clock;
+        self.rtc_ticks = 0;
+        self.rtc_timestamp = unix_time();
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    /// Dumps the RAM followed by the RTC footer
+    ///
+    /// The footer follows the layout used by BGB and VBA: the
+    /// live and latched registers as 32-bit little endian values,
+    /// followed by the 64-bit UNIX timestamp of the save
+    fn dump_ram(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+
+        if !self.has_rtc {
+            return data
+        }
+
+        let mut rtc = self.rtc;
+        let now = unix_time();
+
+        if self.rtc_clock == RtcClock::WallClock && !rtc.halt && now > self.rtc_timestamp {
+            rtc.advance(now - self.rtc_timestamp);
+        }
+
+        rtc.write_footer(&mut data);
+        self.rtc_latched.write_footer(&mut data);
+        data.extend_from_slice(&now.to_le_bytes());
+
+        data
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        let size = self.ram.len().min(data.len());
+
+        self.ram[..size].copy_from_slice(&data[..size]);
+
+        if !self.has_rtc {
+            return
+        }
+
+        let footer = &data[size..];
+
+        if footer.len() != RTC_FOOTER_SIZE && footer.len() != RTC_FOOTER_SIZE_32 {
+            return
+        }
+
+        self.rtc = RtcRegisters::read_footer(&footer[0 .. 20]);
+        self.rtc_latched = RtcRegisters::read_footer(&footer[20 .. 40]);
+
+        let timestamp = if footer.len() == RTC_FOOTER_SIZE {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&footer[40 .. 48]);
+            u64::from_le_bytes(bytes)
+        } else {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&footer[40 .. 44]);
+            u32::from_le_bytes(bytes) as u64
+        };
+
+        // the clock kept running while the game was off, so we
+        // move it forward by the time elapsed since the save
+        self.rtc_timestamp = timestamp;
+        self.update_rtc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(cartridge_type: u8) -> Cartridge {
+        let mut data = vec![0u8; 0x80 * 0x4000];
+
+        for bank in 0 .. 0x80 {
+            data[bank * 0x4000] = bank as u8;
+        }
+
+        data[0x147] = cartridge_type;
+        data[0x149] = 0x03;
+        Cartridge::new(data).unwrap()
+    }
+
+    fn latch(mbc: &mut MBC3) {
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x6000, 0x01);
+    }
+
+    #[test]
+    fn it_switches_rom_banks() {
+        let mut mbc = MBC3::new(rom(0x13), RtcClock::Cycles);
+
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+
+        mbc.write_rom(0x2000, 0x7F);
+        assert_eq!(mbc.read_rom(0x4000), 0x7F);
+    }
+
+    #[test]
+    fn it_latches_the_clock() {
+        let mut mbc = MBC3::new(rom(0x10), RtcClock::Cycles);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x08);
+
+        mbc.step(TICKS_PER_SECOND * 61);
+        assert_eq!(mbc.read_ram(0xA000), 0);
+
+        latch(&mut mbc);
+        assert_eq!(mbc.read_ram(0xA000), 1);
+
+        mbc.write_rom(0x4000, 0x09);
+        assert_eq!(mbc.read_ram(0xA000), 1);
+    }
+
+    #[test]
+    fn it_sets_the_day_carry() {
+        let mut registers = RtcRegisters::new();
+        registers.days = 0x1FF;
+        registers.hours = 23;
+        registers.minutes = 59;
+        registers.seconds = 59;
+
+        registers.tick();
+
+        assert_eq!(registers.days, 0);
+        assert!(registers.day_carry);
+    }
+
+    #[test]
+    fn it_saves_the_clock_in_the_footer() {
+        let mut mbc = MBC3::new(rom(0x10), RtcClock::Cycles);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x0A);
+        mbc.write_ram(0xA000, 12);
+
+        let data = mbc.dump_ram();
+        assert_eq!(data.len(), 0x8000 + RTC_FOOTER_SIZE);
+
+        let mut restored = MBC3::new(rom(0x10), RtcClock::Cycles);
+        restored.load_ram(&data);
+        restored.write_rom(0x0000, 0x0A);
+        restored.write_rom(0x4000, 0x0A);
+        latch(&mut restored);
+
+        assert_eq!(restored.read_ram(0xA000), 12);
+    }
+}
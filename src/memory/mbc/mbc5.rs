@@ -0,0 +1,273 @@
+use memory::mbc::{MBC, TICKS_PER_SECOND};
+use memory::cartridge::Cartridge;
+
+/// MBC 5
+///
+/// The first MBC guaranteed to work in GameBoy Color double speed
+/// mode. It holds up to 8MB of ROM (9 bits of ROM bank) and 128kb
+/// of RAM (16 banks). Unlike MBC1, bank 0 can be mapped into the
+/// switchable ROM area.
+///
+/// Some variants include a rumble motor, which is driven by bit 3
+/// of the RAM bank register (so these only have 8 RAM banks)
+pub struct MBC5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_on: bool,
+    rom_bank: usize,
+    ram_bank: usize,
+    battery: bool,
+    has_rumble: bool,
+
+    /// Whether the rumble motor is currently on
+    rumble: bool,
+
+    /// Ticks the motor was on since the last sample
+    ///
+    /// Games drive the motor with PWM, toggling it many times
+    /// per frame, so the strength is the share of time it was on
+    rumble_ticks: u32,
+
+    /// Ticks since the last sample
+    rumble_period: u32,
+}
+
+impl MBC5 {
+    pub fn new(cartridge: Cartridge) -> MBC5 {
+        let ramsize = if cartridge.header.has_ram() {
+            cartridge.header.ram_size()
+        } else {
+            0
+        };
+
+        let battery = cartridge.header.has_battery();
+        let has_rumble = cartridge.header.has_rumble();
+
+        MBC5 {
+            rom: cartridge.rom,
+            ram: vec![0; ramsize],
+            ram_on: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            battery,
+            has_rumble,
+            rumble: false,
+            rumble_ticks: 0,
+            rumble_period: 0,
+        }
+    }
+
+    fn ram_index(&self, address: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None
+        }
+
+        let index = (self.ram_bank * 0x2000) | ((address & 0x1FFF) as usize);
+
+        Some(index % self.ram.len())
+    }
+}
+
+impl MBC for MBC5 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let index =
+            if address < 0x4000 {
+                address as usize
+            } else {
+                (self.rom_bank * 0x4000) | ((address as usize) & 0x3FFF)
+            };
+
+        self.rom[index % self.rom.len()]
+    }
+
+    fn rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 {
+            0
+        } else {
+            self.rom_bank % (self.rom.len() / 0x4000)
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000 ..= 0x1FFF => {
+                self.ram_on = value == 0x0A;
+            },
+
+            // lower 8 bits of the ROM bank, 0 is a valid bank here
+            0x2000 ..= 0x2FFF => {
+                self.rom_bank = (self.rom_bank & 0x100) | value as usize;
+            },
+
+            // 9th bit of the ROM bank
+            0x3000 ..= 0x3FFF => {
+                self.rom_bank = (self.rom_bank & 0xFF) | (((value as usize) & 0x01) << 8);
+            },
+
+            0x4000 ..= 0x5FFF => {
+                if self.has_rumble {
+                    self.rumble = value & 0x08 == 0x08;
+                    self.ram_bank = (value as usize) & 0x07;
+                } else {
+                    self.ram_bank = (value as usize) & 0x0F;
+                }
+            },
+
+            0x6000 ..= 0x7FFF => (),
+
+            _ => panic!("Could not write to {:04X} (MBC5)", address),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_on {
+            return 0xFF
+        }
+
+        match self.ram_index(address) {
+            Some(index) => self.ram[index],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        if !self.ram_on {
+            return false
+        }
+
+        match self.ram_index(address) {
+            Some(index) => {
+                self.ram[index] = value;
+                true
+            },
+
+            None => false,
+        }
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        let size = self.ram.len().min(data.len());
+
+        self.ram[..size].copy_from_slice(&data[..size]);
+    }
+
+    fn step(&mut self, ticks: u32) {
+        if !self.has_rumble {
+            return
+        }
+
+        if self.rumble {
+            self.rumble_ticks = self.rumble_ticks.saturating_add(ticks);
+        }
+
+        self.rumble_period = self.rumble_period.saturating_add(ticks);
+
+        // nobody may be sampling the motor (the debugger doesn't),
+        // so keep roughly the last second to avoid overflowing
+        while self.rumble_period > TICKS_PER_SECOND {
+            self.rumble_ticks /= 2;
+            self.rumble_period /= 2;
+        }
+    }
+
+    fn rumble(&mut self) -> f32 {
+        let strength = if self.rumble_period == 0 {
+            if self.rumble { 1.0 } else { 0.0 }
+        } else {
+            self.rumble_ticks as f32 / self.rumble_period as f32
+        };
+
+        self.rumble_ticks = 0;
+        self.rumble_period = 0;
+
+        strength
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(cartridge_type: u8) -> Cartridge {
+        let mut data = vec![0u8; 0x200 * 0x4000];
+
+        for bank in 0 .. 0x200 {
+            data[bank * 0x4000] = bank as u8;
+            data[bank * 0x4000 + 1] = (bank >> 8) as u8;
+        }
+
+        data[0x147] = cartridge_type;
+        data[0x149] = 0x04;
+        Cartridge::new(data).unwrap()
+    }
+
+    #[test]
+    fn it_maps_bank_zero() {
+        let mut mbc = MBC5::new(rom(0x19));
+
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0);
+    }
+
+    #[test]
+    fn it_uses_nine_bits_of_rom_bank() {
+        let mut mbc = MBC5::new(rom(0x19));
+
+        mbc.write_rom(0x2000, 0x23);
+        mbc.write_rom(0x3000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0x23);
+        assert_eq!(mbc.read_rom(0x4001), 0x01);
+    }
+
+    #[test]
+    fn it_drives_the_rumble_motor() {
+        let mut mbc = MBC5::new(rom(0x1E));
+        mbc.write_rom(0x0000, 0x0A);
+
+        mbc.write_rom(0x4000, 0x09);
+        assert_eq!(mbc.rumble(), 1.0);
+
+        mbc.write_ram(0xA000, 0x42);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.rumble(), 0.0);
+        assert_eq!(mbc.read_ram(0xA000), 0x42);
+    }
+
+    #[test]
+    fn it_measures_the_rumble_duty_cycle() {
+        let mut mbc = MBC5::new(rom(0x1E));
+
+        for _ in 0 .. 4 {
+            mbc.write_rom(0x4000, 0x08);
+            mbc.step(100);
+            mbc.write_rom(0x4000, 0x00);
+            mbc.step(300);
+        }
+
+        assert_eq!(mbc.rumble(), 0.25);
+
+        mbc.step(400);
+        assert_eq!(mbc.rumble(), 0.0);
+    }
+
+    #[test]
+    fn it_keeps_the_rumble_window_bounded() {
+        let mut mbc = MBC5::new(rom(0x1E));
+        mbc.write_rom(0x4000, 0x08);
+
+        // over 2^32 ticks without sampling
+        for _ in 0 .. 1100 {
+            mbc.step(TICKS_PER_SECOND);
+        }
+
+        assert_eq!(mbc.rumble(), 1.0);
+    }
+}
@@ -0,0 +1,483 @@
+use memory::mbc::MBC;
+use memory::cartridge::Cartridge;
+
+/// Accelerometer value for a flat cartridge (0g)
+const ACCELEROMETER_CENTER: f32 = 0x81D0 as f32;
+
+/// Accelerometer units per g of tilt
+const ACCELEROMETER_GRAVITY: f32 = 0x70 as f32;
+
+/// EEPROM size, in 16-bit words (93LC56, 2kbit)
+const EEPROM_WORDS: usize = 0x80;
+
+/// EEPROM state
+///
+/// The EEPROM receives commands one bit at a time: a start bit,
+/// a 2-bit opcode and an 8-bit address. Then, depending on the
+/// command, data bits are shifted in or out
+#[derive(PartialEq, Copy, Clone, Debug)]
+enum EepromState {
+    /// Waiting for the start bit
+    Idle,
+
+    /// Receiving the opcode and address
+    Command,
+
+    /// Shifting data out
+    Read,
+
+    /// Shifting data in, for one word or for all of them
+    Write,
+    WriteAll,
+
+    /// Command finished, waiting for chip select to go low
+    Done,
+}
+
+/// 93LC56 serial EEPROM
+///
+/// Used instead of SRAM to store the save data. The game talks
+/// to it by bit-banging the chip select (CS), clock (CLK) and
+/// data in (DI) lines, and reads the data out (DO) line back.
+///
+/// Commands (after the start bit):
+///
+/// * 10 AAAAAAAA -> READ word
+/// * 01 AAAAAAAA -> WRITE word (followed by 16 data bits)
+/// * 11 AAAAAAAA -> ERASE word (set to 0xFFFF)
+/// * 00 11XXXXXX -> EWEN, enable erase/write
+/// * 00 00XXXXXX -> EWDS, disable erase/write
+/// * 00 10XXXXXX -> ERAL, erase all
+/// * 00 01XXXXXX -> WRAL, write all (followed by 16 data bits)
+struct Eeprom {
+    data: [u16; EEPROM_WORDS],
+    state: EepromState,
+    chip_select: bool,
+    clock: bool,
+    data_in: bool,
+    data_out: bool,
+    write_enabled: bool,
+    shift: u16,
+    bits: u8,
+    address: usize,
+}
+
+impl Eeprom {
+    fn new() -> Eeprom {
+        Eeprom {
+            data: [0xFFFF; EEPROM_WORDS],
+            state: EepromState::Idle,
+            chip_select: false,
+            clock: false,
+            data_in: false,
+            data_out: true,
+            write_enabled: false,
+            shift: 0,
+            bits: 0,
+            address: 0,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        (if self.chip_select { 0x80 } else { 0 }) |
+            (if self.clock    { 0x40 } else { 0 }) |
+            (if self.data_in  { 0x02 } else { 0 }) |
+            (if self.data_out { 0x01 } else { 0 })
+    }
+
+    fn write(&mut self, value: u8) {
+        let chip_select = value & 0x80 == 0x80;
+        let clock = value & 0x40 == 0x40;
+
+        self.data_in = value & 0x02 == 0x02;
+
+        // dropping the chip select aborts any command
+        if !chip_select {
+            if self.chip_select && self.state != EepromState::Read {
+                self.data_out = true;
+            }
+
+            self.state = EepromState::Idle;
+        } else if clock && !self.clock {
+            // bits are sampled on the clock rising edge
+            let bit = self.data_in;
+            self.clock_bit(bit);
+        }
+
+        self.chip_select = chip_select;
+        self.clock = clock;
+    }
+
+    fn clock_bit(&mut self, bit: bool) {
+        match self.state {
+            EepromState::Idle => {
+                if bit {
+                    self.state = EepromState::Command;
+                    self.shift = 0;
+                    self.bits = 0;
+                }
+            },
+
+            EepromState::Command => {
+                self.shift = (self.shift << 1) | bit as u16;
+                self.bits += 1;
+
+                if self.bits == 10 {
+                    self.execute((self.shift >> 8) as u8, (self.shift & 0xFF) as u8);
+                }
+            },
+
+            EepromState::Read => {
+                self.data_out = self.shift & 0x8000 == 0x8000;
+                self.shift <<= 1;
+                self.bits += 1;
+
+                // sequential reads keep going with the next word
+                if self.bits == 16 {
+                    self.address = (self.address + 1) % EEPROM_WORDS;
+                    self.shift = self.data[self.address];
+                    self.bits = 0;
+                }
+            },
+
+            EepromState::Write | EepromState::WriteAll => {
+                self.shift = (self.shift << 1) | bit as u16;
+                self.bits += 1;
+
+                if self.bits == 16 {
+                    if self.write_enabled {
+                        if self.state == EepromState::WriteAll {
+                            self.data = [self.shift; EEPROM_WORDS];
+                        } else {
+                            self.data[self.address] = self.shift;
+                        }
+                    }
+
+                    self.data_out = true;
+                    self.state = EepromState::Done;
+                }
+            },
+
+            EepromState::Done => (),
+        }
+    }
+
+    fn execute(&mut self, opcode: u8, address: u8) {
+        self.address = (address as usize) % EEPROM_WORDS;
+        self.shift = 0;
+        self.bits = 0;
+
+        self.state = match opcode {
+            // READ, the first bit out is a dummy 0
+            0x2 => {
+                self.shift = self.data[self.address];
+                self.data_out = false;
+                EepromState::Read
+            },
+
+            0x1 => {
+                self.data_out = false;
+                EepromState::Write
+            },
+
+            0x3 => {
+                if self.write_enabled {
+                    self.data[self.address] = 0xFFFF;
+                }
+
+                self.data_out = true;
+                EepromState::Done
+            },
+
+            _ => match address >> 6 {
+                0x0 => {
+                    self.write_enabled = false;
+                    EepromState::Done
+                },
+
+                0x1 => {
+                    self.data_out = false;
+                    EepromState::WriteAll
+                },
+
+                0x2 => {
+                    if self.write_enabled {
+                        self.data = [0xFFFF; EEPROM_WORDS];
+                    }
+
+                    self.data_out = true;
+                    EepromState::Done
+                },
+
+                _ => {
+                    self.write_enabled = true;
+                    EepromState::Done
+                },
+            },
+        };
+    }
+}
+
+/// MBC 7
+///
+/// Used by Kirby Tilt 'n' Tumble. Instead of RAM, it has an
+/// accelerometer and a serial EEPROM, mapped as registers in
+/// 0xA000-0xAFFF (address bits 4-7 select the register):
+///
+/// * 0xA00x -> Write 0x55 to erase the accelerometer latch
+/// * 0xA01x -> Write 0xAA to latch the accelerometer
+/// * 0xA02x-0xA03x -> Latched X (low, high)
+/// * 0xA04x-0xA05x -> Latched Y (low, high)
+/// * 0xA06x -> Always 0x00
+/// * 0xA07x -> Always 0xFF
+/// * 0xA08x -> EEPROM lines
+///
+/// The registers are only accessible after writing 0x0A to
+/// 0x0000-0x1FFF and 0x40 to 0x4000-0x5FFF
+pub struct MBC7 {
+    rom: Vec<u8>,
+    rom_bank: usize,
+    ram_on_1: bool,
+    ram_on_2: bool,
+    battery: bool,
+
+    /// Host tilt, in g (x: right, y: down)
+    tilt_x: f32,
+    tilt_y: f32,
+
+    /// Latched accelerometer values
+    accelerometer_x: u16,
+    accelerometer_y: u16,
+
+    /// Whether the latch was erased, it needs to be
+    /// erased before latching new values
+    latch_ready: bool,
+
+    eeprom: Eeprom,
+}
+
+impl MBC7 {
+    pub fn new(cartridge: Cartridge) -> MBC7 {
+        let battery = cartridge.header.has_battery();
+
+        MBC7 {
+            rom: cartridge.rom,
+            rom_bank: 1,
+            ram_on_1: false,
+            ram_on_2: false,
+            battery,
+            tilt_x: 0.0,
+            tilt_y: 0.0,
+            accelerometer_x: 0x8000,
+            accelerometer_y: 0x8000,
+            latch_ready: false,
+            eeprom: Eeprom::new(),
+        }
+    }
+
+    fn accelerometer_value(tilt: f32) -> u16 {
+        (ACCELEROMETER_CENTER + tilt * ACCELEROMETER_GRAVITY)
+            .max(0.0)
+            .min(0xFFFF as f32) as u16
+    }
+}
+
+impl MBC for MBC7 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let index =
+            if address < 0x4000 {
+                address as usize
+            } else {
+                (self.rom_bank * 0x4000) | ((address as usize) & 0x3FFF)
+            };
+
+        self.rom[index % self.rom.len()]
+    }
+
+    fn rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 {
+            0
+        } else {
+            self.rom_bank % (self.rom.len() / 0x4000)
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000 ..= 0x1FFF => {
+                self.ram_on_1 = value == 0x0A;
+            },
+
+            0x2000 ..= 0x3FFF => {
+                self.rom_bank = (value as usize) & 0x7F;
+            },
+
+            0x4000 ..= 0x5FFF => {
+                self.ram_on_2 = value == 0x40;
+            },
+
+            0x6000 ..= 0x7FFF => (),
+
+            _ => panic!("Could not write to {:04X} (MBC7)", address),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_on_1 || !self.ram_on_2 || address >= 0xB000 {
+            return 0xFF
+        }
+
+        match (address >> 4) & 0x0F {
+            0x2 => self.accelerometer_x as u8,
+            0x3 => (self.accelerometer_x >> 8) as u8,
+            0x4 => self.accelerometer_y as u8,
+            0x5 => (self.accelerometer_y >> 8) as u8,
+            0x6 => 0x00,
+            0x8 => self.eeprom.read(),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        if !self.ram_on_1 || !self.ram_on_2 || address >= 0xB000 {
+            return false
+        }
+
+        match (address >> 4) & 0x0F {
+            0x0 if value == 0x55 => {
+                self.accelerometer_x = 0x8000;
+                self.accelerometer_y = 0x8000;
+                self.latch_ready = true;
+                false
+            },
+
+            0x1 if value == 0xAA && self.latch_ready => {
+                self.accelerometer_x = MBC7::accelerometer_value(self.tilt_x);
+                self.accelerometer_y = MBC7::accelerometer_value(self.tilt_y);
+                self.latch_ready = false;
+                false
+            },
+
+            0x8 => {
+                self.eeprom.write(value);
+                true
+            },
+
+            _ => false,
+        }
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    /// Dumps the EEPROM, as little endian words
+    fn dump_ram(&self) -> Vec<u8> {
+        self.eeprom.data.iter()
+            .flat_map(|word| word.to_le_bytes().to_vec())
+            .collect()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        for (word, bytes) in self.eeprom.data.iter_mut().zip(data.chunks(2)) {
+            if bytes.len() == 2 {
+                *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+            }
+        }
+    }
+
+    fn set_tilt(&mut self, x: f32, y: f32) {
+        self.tilt_x = x;
+        self.tilt_y = y;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled_mbc() -> MBC7 {
+        let mut data = vec![0u8; 0x8000];
+        data[0x147] = 0x22;
+
+        let mut mbc = MBC7::new(Cartridge::new(data).unwrap());
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x40);
+        mbc
+    }
+
+    /// Clocks a list of bits into the EEPROM, returning DO after each one
+    fn clock_bits(mbc: &mut MBC7, bits: &[u8]) -> Vec<u8> {
+        bits.iter().map(|&bit| {
+            mbc.write_ram(0xA080, 0x80 | (bit << 1));
+            mbc.write_ram(0xA080, 0xC0 | (bit << 1));
+            mbc.read_ram(0xA080) & 0x01
+        }).collect()
+    }
+
+    fn command(mbc: &mut MBC7, opcode: u8, address: u8) -> Vec<u8> {
+        let mut bits = vec![1, (opcode >> 1) & 1, opcode & 1];
+
+        for i in (0 .. 8).rev() {
+            bits.push((address >> i) & 1);
+        }
+
+        clock_bits(mbc, &bits)
+    }
+
+    fn deselect(mbc: &mut MBC7) {
+        mbc.write_ram(0xA080, 0x00);
+    }
+
+    #[test]
+    fn it_latches_the_accelerometer() {
+        let mut mbc = enabled_mbc();
+        mbc.set_tilt(1.0, -0.5);
+
+        // latching without erasing first does nothing
+        mbc.write_ram(0xA010, 0xAA);
+        assert_eq!(mbc.read_ram(0xA030), 0x80);
+
+        mbc.write_ram(0xA000, 0x55);
+        mbc.write_ram(0xA010, 0xAA);
+
+        let x = mbc.read_ram(0xA020) as u16 | (mbc.read_ram(0xA030) as u16) << 8;
+        let y = mbc.read_ram(0xA040) as u16 | (mbc.read_ram(0xA050) as u16) << 8;
+
+        assert_eq!(x, 0x81D0 + 0x70);
+        assert_eq!(y, 0x81D0 - 0x38);
+    }
+
+    #[test]
+    fn it_writes_and_reads_the_eeprom() {
+        let mut mbc = enabled_mbc();
+
+        command(&mut mbc, 0x0, 0xC0);
+        deselect(&mut mbc);
+
+        command(&mut mbc, 0x1, 0x05);
+        let data: Vec<u8> = (0 .. 16).rev().map(|i| ((0xBEEFu16 >> i) & 1) as u8).collect();
+        clock_bits(&mut mbc, &data);
+        assert_eq!(mbc.read_ram(0xA080) & 0x01, 1);
+        deselect(&mut mbc);
+
+        command(&mut mbc, 0x2, 0x05);
+        let out = clock_bits(&mut mbc, &[0; 16]);
+        let word = out.iter().fold(0u16, |word, &bit| (word << 1) | bit as u16);
+        deselect(&mut mbc);
+
+        assert_eq!(word, 0xBEEF);
+        assert_eq!(&mbc.dump_ram()[10 .. 12], &[0xEF, 0xBE]);
+    }
+
+    #[test]
+    fn it_ignores_writes_until_enabled() {
+        let mut mbc = enabled_mbc();
+
+        command(&mut mbc, 0x1, 0x00);
+        clock_bits(&mut mbc, &[0; 16]);
+        deselect(&mut mbc);
+
+        assert_eq!(mbc.eeprom.data[0], 0xFFFF);
+    }
+}
@@ -1,8 +1,24 @@
 use std::io::prelude::*;
 use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use memory::cartridge::{Cartridge, Mapper};
+use error::SafeboyError;
 
 mod mbc0;
 mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
+mod mbc7;
+mod camera;
+mod huc1;
+mod huc3;
+mod infrared;
+
+pub use self::mbc3::RtcClock;
+pub use self::infrared::{Infrared, Loopback};
+pub use self::camera::{ImageSource, FileImage, CallbackImage, CAMERA_WIDTH, CAMERA_HEIGHT};
 
 /// Memory Banking Controller
 ///
@@ -18,7 +34,8 @@ mod mbc1;
 /// memory of the GameBoy without needed to upgrade the hardware.
 ///
 /// There is about 30 MBC types out there, but we only implemented
-/// the first two ones: MBC0 (no-MBC) and MBC1
+/// a few of them: MBC0 (no-MBC), MBC1, MBC2, MBC3, MBC5, MBC7, HuC1,
+/// HuC3 and the Pocket Camera
 pub trait MBC {
     /// Reads ROM from the give address
     fn read_rom(&self, address: u16) -> u8;
@@ -27,9 +44,81 @@ pub trait MBC {
     fn write_rom(&mut self, address: u16, value: u8);
 
     fn read_ram(&self, address: u16) -> u8;
-    fn write_ram(&mut self, address: u16, value: u8);
+
+    /// Writes data to the external RAM
+    ///
+    /// Returns whether the MBC accepted the write, writes
+    /// to disabled RAM or to registers that aren't saved
+    /// don't make the save file stale
+    fn write_ram(&mut self, address: u16, value: u8) -> bool;
+
+    /// ROM bank mapped at the given address
+    ///
+    /// Used to tell apart code living at the same address
+    /// in different banks, when debugging
+    fn rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 { 0 } else { 1 }
+    }
+
+    /// Steps the MBC
+    ///
+    /// Most MBCs are just address decoders, but some of them
+    /// contain hardware that runs along the CPU (like clocks)
+    fn step(&mut self, _ticks: u32) {}
+
+    /// Whether the cartridge has a battery
+    ///
+    /// Cartridges with a battery keep their external RAM
+    /// powered when the GameBoy is switched off, this is how
+    /// games store save data between sessions
+    fn has_battery(&self) -> bool {
+        false
+    }
+
+    /// Dumps the external RAM contents
+    ///
+    /// The returned bytes are what ends up in the `.sav` file
+    fn dump_ram(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Loads the external RAM contents
+    ///
+    /// This receives the bytes previously returned by `dump_ram`
+    fn load_ram(&mut self, _data: &[u8]) {}
+
+    /// Rumble motor strength since the last call
+    ///
+    /// Only some cartridges have a rumble motor (MBC5 variants),
+    /// this is the share of time it was on, from 0.0 to 1.0
+    fn rumble(&mut self) -> f32 {
+        0.0
+    }
+
+    /// Sets the cartridge tilt, in g
+    ///
+    /// Only cartridges with an accelerometer (MBC7) use this,
+    /// x is positive when tilting right and y when tilting down
+    fn set_tilt(&mut self, _x: f32, _y: f32) {}
+
+    /// Sets where the camera sensor takes images from
+    ///
+    /// Only used by the Pocket Camera
+    fn set_image_source(&mut self, _source: Box<dyn ImageSource>) {}
+
+    /// Sets what drives the cartridge clock
+    ///
+    /// Only cartridges with a clock (MBC3 and HuC3) use this,
+    /// they follow the host wall-clock by default
+    fn set_rtc_clock(&mut self, _rtc_clock: RtcClock) {}
 }
 
+/// Ticks per clock second
+///
+/// Cartridge clocks run from their own 32768Hz crystal, but when they
+/// are driven by emulated cycles we count one second every CPU_SPEED ticks
+const TICKS_PER_SECOND: u32 = 4_194_304;
+
 /// Loads a new MBC
 ///
 /// This method will detect which kind of MBC the game has
@@ -37,40 +126,90 @@ pub trait MBC {
 ///
 /// The result is returned as a Box, which contains the raw
 /// game data allocaated in the stack
-pub fn load_mbc(rom_file: &str) -> Result<Box<dyn MBC+'static>, String> {
-    let mut data = vec![];
-    let mut file = File::open(rom_file).unwrap();
+pub fn load_mbc(rom_file: &str) -> Result<Box<dyn MBC+'static>, SafeboyError> {
+    let cartridge = Cartridge::load(rom_file)?;
+    let mut mbc = new_mbc(cartridge)?;
 
-    // read all bytes through the end, data now contains all
-    // raw rom bytes
-    let _size = file.read_to_end(&mut data).unwrap();
+    // battery backed cartridges may have a save file from
+    // a previous session sitting next to the ROM file
+    if mbc.has_battery() {
+        load_battery_ram(mbc.as_mut(), &save_file_path(rom_file));
+    }
 
-    // in order to know what kind of MBC we are working with,
-    // we need to read this address space in the ROM, which
-    // contains the cartdridge type
-    let mbc_type = data[0x147];
+    Ok(mbc)
+}
 
-    match mbc_type {
-        0x00 => {
-            let mbc = mbc0::MBC0::new(data);
-            Ok(Box::new(mbc) as Box<dyn MBC>)
-        },
+/// Creates the MBC for a cartridge
+///
+/// The cartridge type in the header tells us which
+/// controller is inside the cartridge
+pub fn new_mbc(cartridge: Cartridge) -> Result<Box<dyn MBC+'static>, SafeboyError> {
+    // every cartridge has at least two ROM banks, anything
+    // smaller has been cut short
+    if cartridge.rom.len() < 0x8000 {
+        return Err(SafeboyError::InvalidRom(format!("truncated, only {} bytes", cartridge.rom.len())))
+    }
 
-        0x01 ..= 0x03 =>  {
-            let mbc = mbc1::MBC1::new(data);
-            Ok(Box::new(mbc) as Box<dyn MBC>)
-        },
+    let mapper = match cartridge.header.mapper() {
+        Some(mapper) => mapper,
+        None => return Err(SafeboyError::UnsupportedMapper(cartridge.header.cartridge_type)),
+    };
 
-        _ => Err(format!("Unsupported MBC: {0:x}", mbc_type)),
-    }
+    let mbc = match mapper {
+        Mapper::None => Box::new(mbc0::MBC0::new(cartridge)) as Box<dyn MBC>,
+        Mapper::MBC1 => Box::new(mbc1::MBC1::new(cartridge)) as Box<dyn MBC>,
+        Mapper::MBC2 => Box::new(mbc2::MBC2::new(cartridge)) as Box<dyn MBC>,
+        Mapper::MBC3 => Box::new(mbc3::MBC3::new(cartridge, RtcClock::WallClock)) as Box<dyn MBC>,
+        Mapper::MBC5 => Box::new(mbc5::MBC5::new(cartridge)) as Box<dyn MBC>,
+        Mapper::MBC7 => Box::new(mbc7::MBC7::new(cartridge)) as Box<dyn MBC>,
+        Mapper::PocketCamera => Box::new(camera::Camera::new(cartridge)) as Box<dyn MBC>,
+        Mapper::HuC1 => Box::new(huc1::HuC1::new(cartridge, Box::new(Loopback::new()))) as Box<dyn MBC>,
+        Mapper::HuC3 => Box::new(huc3::HuC3::new(cartridge, RtcClock::WallClock, Box::new(Loopback::new()))) as Box<dyn MBC>,
+        _ => return Err(SafeboyError::UnsupportedMapper(cartridge.header.cartridge_type)),
+    };
+
+    Ok(mbc)
+}
+
+/// Save file path
+///
+/// The save file lives next to the ROM, with the same
+/// name and a `.sav` extension (`zelda.gb` -> `zelda.sav`)
+pub fn save_file_path(rom_file: &str) -> PathBuf {
+    Path::new(rom_file).with_extension("sav")
+}
+
+/// Writes the external RAM of a battery backed cartridge to disk
+pub fn save_battery_ram(mbc: &dyn MBC, save_file: &Path) -> Result<(), String> {
+    let mut file = File::create(save_file)
+        .map_err(|e| format!("Could not create save file {}: {}", save_file.display(), e))?;
+
+    file.write_all(&mbc.dump_ram())
+        .map_err(|e| format!("Could not write save file {}: {}", save_file.display(), e))
 }
 
-fn ram_size(v: u8) -> usize {
-    match v {
-        1 => 0x800,
-        2 => 0x2000,
-        3 => 0x8000,
-        4 => 0x20000,
-        _ => 0,
+/// Reads the save file into the external RAM, if it exists
+fn load_battery_ram(mbc: &mut dyn MBC, save_file: &Path) {
+    let mut data = vec![];
+
+    // no save file just means the game was never saved
+    let mut file = match File::open(save_file) {
+        Ok(file) => file,
+        Err(_) => return,
+    };
+
+    match file.read_to_end(&mut data) {
+        Ok(_) => mbc.load_ram(&data),
+        Err(e) => eprintln!("Could not read save file {}: {}", save_file.display(), e),
     }
-}
\ No newline at end of file
+}
+
+/// Host time, in seconds since the UNIX epoch
+///
+/// Used by cartridge clocks following the host wall-clock
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
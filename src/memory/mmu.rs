@@ -1,16 +1,32 @@
 use memory::mbc;
+use memory::cartridge::Cartridge;
+use memory::hdma::Hdma;
+use error::SafeboyError;
+use model::Model;
+use std::path::PathBuf;
+use std::fs::File;
+use std::io::prelude::*;
 use cpu::timer::Timer;
 use frontend::keypad::Keypad;
 use gpu::gpu::GPU;
 use audio::audio;
 use audio::audio::Audio;
 
-/// Working RAM, 8k bytes
+/// Working RAM, 8k bytes (32k bytes in the GameBoy Color)
 const WORKING_RAM_SIZE: usize = 0x8000;
 
+/// Working RAM bank size, 4k bytes
+const WORKING_RAM_BANK_SIZE: usize = 0x1000;
+
 /// High RAM (Zero Page), 127 bytes
 const HIGH_RAM_SIZE: usize = 0x7F;
 
+/// Boot ROM, 256 bytes
+const BOOT_ROM_SIZE: usize = 0x100;
+
+/// I/O registers up to the sound ones (0xFF00-0xFF3F)
+const IO_REGISTERS_SIZE: usize = 0x40;
+
 
 /// Memory Management Unit (MMU)
 ///
@@ -25,6 +41,12 @@ pub struct MMU {
     /// * These addresses are echoed in the range 0xE000-0xFDFF, but it's tipically not used
     working_ram: [u8; WORKING_RAM_SIZE],
 
+    /// Working RAM bank (SVBK)
+    ///
+    /// The GameBoy Color has 8 banks, bank 0 is always at
+    /// 0xC000-0xCFFF and banks 1-7 can be mapped at 0xD000-0xDFFF
+    working_ram_bank: usize,
+
     /// High RAM, also called Zero-page RAM, with 127 bytes of size
     ///
     /// * This is a super-speed RAM space use for the program stack
@@ -81,16 +103,89 @@ pub struct MMU {
     /// More details in the module.
     pub mbc: Box<dyn mbc::MBC+'static>,
 
-    pub audio: audio::Audio
+    /// Save file
+    ///
+    /// Where the external RAM is persisted, only set
+    /// for cartridges with a battery
+    save_file: Option<PathBuf>,
+
+    /// Whether the external RAM changed since the last save
+    external_ram_dirty: bool,
+
+    /// Boot ROM
+    ///
+    /// While mapped, it hides the first 256 bytes of the cartridge
+    /// ROM. The boot ROM unmaps itself writing to 0xFF50 just
+    /// before jumping to the game at 0x0100
+    boot_rom: Option<Vec<u8>>,
+
+    pub audio: audio::Audio,
+
+    /// Serial and sound registers
+    ///
+    /// These are not emulated, they just keep the last value
+    /// written so games can read back what the boot ROM left
+    io_registers: [u8; IO_REGISTERS_SIZE],
+
+    /// Hardware model being emulated
+    pub model: Model,
+
+    /// GameBoy Color mode
+    ///
+    /// Only when running a color cartridge (0x143 flagged with 0x80
+    /// or 0xC0) in a color model, otherwise the GameBoy Color
+    /// hardware behaves like the monochrome one
+    pub cgb_mode: bool,
+
+    /// VRAM DMA, only in GameBoy Color mode
+    hdma: Hdma,
+
+    /// Double speed mode (GameBoy Color)
+    ///
+    /// The CPU and the timer run twice as fast, while the GPU,
+    /// the sound and the HDMA keep the normal speed
+    pub double_speed: bool,
+
+    /// Speed switch armed (KEY1 bit 0)
+    ///
+    /// The next STOP switches between normal and double speed
+    speed_switch_armed: bool,
+
+    /// Half of a normal speed tick left from the last
+    /// double speed step, so odd tick counts are not lost
+    half_tick: bool,
 }
 
 impl MMU {
-    pub fn new(rom_file: &str) -> MMU {
+    pub fn new(rom_file: &str, model: Model) -> Result<MMU, SafeboyError> {
         // load the file raw data into the MBC, where the ERAM is located
-        let mbc = mbc::load_mbc(rom_file).unwrap();
+        let mbc = mbc::load_mbc(rom_file)?;
+
+        let save_file = if mbc.has_battery() {
+            Some(mbc::save_file_path(rom_file))
+        } else {
+            None
+        };
+
+        Ok(MMU::with_mbc(mbc, save_file, model))
+    }
+
+    /// Creates the MMU from ROM data already in memory
+    ///
+    /// There is no file to persist the external RAM to, so
+    /// battery backed games will not be saved
+    pub fn from_rom(data: Vec<u8>, model: Model) -> Result<MMU, SafeboyError> {
+        let mbc = mbc::new_mbc(Cartridge::new(data)?)?;
+
+        Ok(MMU::with_mbc(mbc, None, model))
+    }
+
+    fn with_mbc(mbc: Box<dyn mbc::MBC+'static>, save_file: Option<PathBuf>, model: Model) -> MMU {
+        let cgb_mode = model.is_cgb() && mbc.read_rom(0x0143) & 0x80 == 0x80;
 
         let mut mmu = MMU {
             working_ram: [0; WORKING_RAM_SIZE],
+            working_ram_bank: 1,
             high_ram: [0; HIGH_RAM_SIZE],
 
             interrupt_enable: 0,
@@ -100,64 +195,133 @@ impl MMU {
             keypad: Keypad::new(),
             gpu: GPU::new(),
             mbc,
-            audio: Audio::new()
+            save_file,
+            external_ram_dirty: false,
+            boot_rom: None,
+            audio: Audio::new(),
+            io_registers: [0; IO_REGISTERS_SIZE],
+            model,
+            cgb_mode,
+            hdma: Hdma::new(),
+            double_speed: false,
+            speed_switch_armed: false,
+            half_tick: false,
         };
 
+        mmu.gpu.set_cgb_mode(cgb_mode);
+
         mmu.reset();
 
         mmu
     }
 
-    fn reset(&mut self) {
-        // Timer counter (TIMA)
-        self.write_byte(0xFF05, 0);
+    /// Maps the boot ROM
+    ///
+    /// The I/O registers go back to their power on values,
+    /// as the boot ROM is the one setting them up
+    pub fn load_boot_rom(&mut self, boot_rom_file: &str) -> Result<(), SafeboyError> {
+        let mut data = vec![];
+
+        File::open(boot_rom_file)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .map_err(|e| SafeboyError::BootRomFile(boot_rom_file.to_string(), e))?;
+
+        if data.len() != BOOT_ROM_SIZE {
+            return Err(SafeboyError::InvalidBootRom(data.len()))
+        }
+
+        self.boot_rom = Some(data);
 
-        // Timer modulo (TMA)
-        self.write_byte(0xFF06, 0);
+        // everything the boot ROM sets up starts cleared,
+        // including the LCD (off) and the sound
+        for (address, _) in self.model.io_registers() {
+            self.write_byte(address, 0);
+        }
 
-        // Timer control (TAC)
-        self.write_byte(0xFF07, 0);
+        // the divider starts counting from power on
+        self.timer.set_divider_counter(0);
 
-        // LCD control (LCDC)
-        self.write_byte(0xFF40, 0x91);
+        Ok(())
+    }
 
-        // LCD position (Scroll Y) (SCY)
-        self.write_byte(0xFF42, 0);
+    /// Whether the boot ROM is still mapped
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom.is_some()
+    }
 
-        // LCD position (Scroll X) (SCX)
-        self.write_byte(0xFF43, 0);
+    fn reset(&mut self) {
+        // the boot ROM leaves the I/O registers set up, and
+        // games detect the model from some of them
+        for (address, value) in self.model.io_registers() {
+            self.write_byte(address, value);
+        }
 
-        // LCD Y-coordinate (LY)
-        self.write_byte(0xFF44, 0);
+        // the boot ROM takes some time to run, so the divider
+        // and the LCD have been running for a while
+        self.timer.set_divider_counter(self.model.divider_counter());
 
-        // LCD LY Compare (LYC)
-        self.write_byte(0xFF45, 0);
+        let (line, clock) = self.model.lcd_phase();
+        self.gpu.set_phase(line, clock);
+    }
 
-        // LCD Window Y Position
-        self.write_byte(0xFF4A, 0);
+    /// Saves the external RAM
+    ///
+    /// Writes the battery backed RAM to the save file. Nothing
+    /// is written if the cartridge has no battery or if the
+    /// game didn't touch the RAM since the last save
+    pub fn save_ram(&mut self) {
+        if !self.external_ram_dirty {
+            return
+        }
 
-        // LCD Window X Position
-        self.write_byte(0xFF4B, 0);
+        if let Some(ref save_file) = self.save_file {
+            match mbc::save_battery_ram(self.mbc.as_ref(), save_file) {
+                Ok(()) => self.external_ram_dirty = false,
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+    }
 
-        // BG Palette (BGP)
-        self.write_byte(0xFF47, 0xFC);
+    /// Switches between normal and double speed
+    ///
+    /// Called on STOP, it only does something when the
+    /// game armed the switch writing to KEY1 first. Returns
+    /// whether the speed changed
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.cgb_mode || !self.speed_switch_armed {
+            return false
+        }
 
-        // Spritte Palette 0
-        self.write_byte(0xFF48, 0xFF);
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+        self.half_tick = false;
 
-        // Spritte Palette 1
-        self.write_byte(0xFF49, 0xFF);
+        true
     }
 
     /// Steps the MMU
     ///
     /// This will handle interrupts from implemented sources
     /// (timer, GPU and keypad) and also cycle the GPU and the Timer
-    pub fn step(&mut self, ticks: u32) {
-        // cycle the timer and check for interrupts
+    ///
+    /// Ticks are CPU ticks, which in double speed last half of a
+    /// normal one. Returns how many normal speed ticks went by,
+    /// which is what the GPU (and the frame timing) sees
+    pub fn step(&mut self, ticks: u32) -> u32 {
+        // cycle the timer and check for interrupts,
+        // it runs from the CPU clock
         self.timer.step(ticks);
         self.interrupt_flag |= self.timer.interrupt;
 
+        let ticks = if self.double_speed {
+            let half_ticks = ticks + self.half_tick as u32;
+            self.half_tick = half_ticks % 2 == 1;
+
+            half_ticks / 2
+        } else {
+            ticks
+        };
+
         // check for keypad interrupts
         // keypad is not cycled because interrupt data
         // is directly handled by user input
@@ -167,10 +331,25 @@ impl MMU {
         self.gpu.step(ticks);
         self.interrupt_flag |= self.gpu.interrupt;
 
+        // HBlank DMA copies one block every horizontal blank
+        if self.gpu.horizontal_blank_started {
+            self.gpu.horizontal_blank_started = false;
+
+            if self.hdma.active() {
+                self.hdma_transfer_block();
+            }
+        }
+
+        // some cartridges have their own hardware running
+        // along the CPU, like the MBC3 clock
+        self.mbc.step(ticks);
+
         // reset interrupts
         self.keypad.interrupt = 0;
         self.timer.interrupt = 0;
         self.gpu.interrupt = 0;
+
+        ticks
     }
 
     /// Read a byte from the MMU
@@ -180,6 +359,13 @@ impl MMU {
     /// to GPU, timer, keypad, etc. addresses, but this is handled
     /// internally
     pub fn read_byte(&mut self, address: u16) -> u8 {
+        // the boot ROM sits on top of the cartridge header area
+        if let Some(ref boot_rom) = self.boot_rom {
+            if (address as usize) < BOOT_ROM_SIZE {
+                return boot_rom[address as usize]
+            }
+        }
+
         match address {
 
             0x0000 ..= 0x7FFF => {
@@ -199,7 +385,7 @@ impl MMU {
             },
 
             0xD000 ..= 0xDFFF | 0xF000 ..= 0xFDFF => {
-                self.working_ram[0x1000 | address as usize & 0x0FFF]
+                self.working_ram[(self.working_ram_bank * WORKING_RAM_BANK_SIZE) | (address as usize & 0x0FFF)]
             },
 
             0xFE00 ..= 0xFE9F => {
@@ -212,7 +398,7 @@ impl MMU {
 
             0xFF01 ..= 0xFF02 => {
                 // Serial unimplemented
-                0x0
+                self.io_registers[address as usize & 0x3F]
             },
 
             0xFF04 ..= 0xFF07 => {
@@ -225,21 +411,34 @@ impl MMU {
 
             0xFF10 ..= 0xFF3F => {
                 // Sound unimplemented
-                0x0
+                self.io_registers[address as usize & 0x3F]
             },
 
+            // KEY1, current speed in bit 7 and switch armed in bit 0
             0xFF4D => {
-                0
+                if self.cgb_mode {
+                    (if self.double_speed { 0x80 } else { 0 }) | 0x7E | self.speed_switch_armed as u8
+                } else {
+                    0xFF
+                }
             },
 
             0xFF40 ..= 0xFF4F => {
                 self.gpu.read_byte(address)
             },
 
+            0xFF51 ..= 0xFF55 if self.cgb_mode => {
+                self.hdma.read_byte(address)
+            },
+
             0xFF68 ..= 0xFF6B => {
                 self.gpu.read_byte(address)
             },
 
+            0xFF70 if self.cgb_mode => {
+                0xF8 | self.working_ram_bank as u8
+            },
+
             0xFF80 ..= 0xFFFE => {
                 self.high_ram[address as usize & 0x007F]
             },
@@ -270,7 +469,9 @@ impl MMU {
 
             // more MBC memory!
             0xA000 ..= 0xBFFF => {
-                self.mbc.write_ram(address, value)
+                if self.mbc.write_ram(address, value) {
+                    self.external_ram_dirty = true;
+                }
             },
 
             // internal working ram (bank 0)
@@ -280,7 +481,7 @@ impl MMU {
 
             // internal working ram (bank 1)
             0xD000 ..= 0xDFFF | 0xF000 ..= 0xFDFF => {
-                self.working_ram[0x1000 | (address as usize & 0x0FFF)] = value
+                self.working_ram[(self.working_ram_bank * WORKING_RAM_BANK_SIZE) | (address as usize & 0x0FFF)] = value
             },
 
             // gpu, mapped to OAM (object attribute memory)
@@ -295,6 +496,7 @@ impl MMU {
 
             // serial port, not implemented
             0xFF01 ..= 0xFF03 => {
+                self.io_registers[address as usize & 0x3F] = value
             }
 
             // timer
@@ -304,6 +506,7 @@ impl MMU {
 
             // sound
             0xFF10 ..= 0xFF3F => {
+                self.io_registers[address as usize & 0x3F] = value;
                 self.audio.write_byte(address, value)
             },
 
@@ -313,6 +516,13 @@ impl MMU {
                 self.dma_ram_to_oam_transfer(value)
             },
 
+            // KEY1, prepares the speed switch (GameBoy Color)
+            0xFF4D => {
+                if self.cgb_mode {
+                    self.speed_switch_armed = value & 0x01 == 0x01;
+                }
+            },
+
             0xFF40 ..= 0xFF4F => {
                 self.gpu.write_byte(address, value)
             },
@@ -321,6 +531,37 @@ impl MMU {
                 self.interrupt_flag = value
             },
 
+            // VRAM DMA (GameBoy Color)
+            0xFF51 ..= 0xFF55 => {
+                if self.cgb_mode {
+                    for _ in 0 .. self.hdma.write_byte(address, value) {
+                        self.hdma_transfer_block();
+                    }
+                }
+            },
+
+            // color palettes (GameBoy Color)
+            0xFF68 ..= 0xFF6B => {
+                self.gpu.write_byte(address, value)
+            },
+
+            // working RAM bank (GameBoy Color), bank 0 selects bank 1
+            0xFF70 => {
+                if self.cgb_mode {
+                    self.working_ram_bank = match value & 0x07 {
+                        0 => 1,
+                        n => n as usize,
+                    };
+                }
+            },
+
+            // unmaps the boot ROM, it can't be mapped again
+            0xFF50 => {
+                if value != 0 {
+                    self.boot_rom = None;
+                }
+            },
+
             0xFF80 ..= 0xFFFE => {
                 self.high_ram[address as usize & 0x007F] = value
             },
@@ -338,6 +579,16 @@ impl MMU {
         self.write_byte(address + 1, (value >> 8) as u8);
     }
 
+    /// Copies one 16 bytes block into VRAM
+    fn hdma_transfer_block(&mut self) {
+        let (source, destination) = self.hdma.next_block();
+
+        for i in 0 .. 0x10 {
+            let b = self.read_byte(source.wrapping_add(i));
+            self.gpu.write_byte(destination + i, b);
+        }
+    }
+
     fn dma_ram_to_oam_transfer(&mut self, value: u8) {
         let base = (value as u16) << 8;
 
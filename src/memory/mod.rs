@@ -1,2 +1,4 @@
 pub mod mmu;
-pub mod mbc;
\ No newline at end of file
+pub mod mbc;
+pub mod cartridge;
+pub mod hdma;
\ No newline at end of file
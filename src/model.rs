@@ -0,0 +1,183 @@
+use std::fmt;
+use std::str::FromStr;
+use memory::cartridge::{CartridgeHeader, CgbSupport};
+
+/// GameBoy model
+///
+/// Every model leaves the machine in a slightly different state
+/// when its boot ROM hands over to the game, and games use
+/// these differences (mostly the A register) to detect where
+/// they are running
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Model {
+    /// Original GameBoy, first boot ROM revision
+    DMG0,
+
+    /// Original GameBoy
+    DMG,
+
+    /// GameBoy Pocket (and Light)
+    MGB,
+
+    /// Super GameBoy
+    SGB,
+
+    /// Super GameBoy 2
+    SGB2,
+
+    /// GameBoy Color
+    CGB,
+
+    /// GameBoy Advance, running GameBoy games
+    AGB,
+}
+
+impl Model {
+    /// Best model for a cartridge
+    ///
+    /// Color cartridges run on a GameBoy Color, and
+    /// the rest on the original GameBoy
+    pub fn for_cartridge(header: &CartridgeHeader) -> Model {
+        match header.cgb_support() {
+            CgbSupport::None => Model::DMG,
+            CgbSupport::Enhanced | CgbSupport::Only => Model::CGB,
+        }
+    }
+
+    /// Whether this model is a Super GameBoy
+    pub fn is_sgb(&self) -> bool {
+        matches!(*self, Model::SGB | Model::SGB2)
+    }
+
+    /// Whether this model has the GameBoy Color hardware
+    pub fn is_cgb(&self) -> bool {
+        matches!(*self, Model::CGB | Model::AGB)
+    }
+
+    /// Internal divider counter when the game starts
+    ///
+    /// DIV is the upper byte of this counter, the lower byte
+    /// is the phase the timer keeps from the boot ROM. Each boot
+    /// ROM takes a different amount of time to run
+    pub fn divider_counter(&self) -> u16 {
+        match *self {
+            Model::DMG0 => 0x182C,
+            Model::DMG | Model::MGB => 0xABCC,
+            Model::SGB | Model::SGB2 => 0xD85C,
+            Model::CGB | Model::AGB => 0x267C,
+        }
+    }
+
+    /// I/O registers when the game starts
+    ///
+    /// Most of them are left the same by every boot ROM, but the
+    /// serial control, the sound and the GameBoy Color registers
+    /// differ. The divider and the LCD position are not here, see
+    /// `divider_counter` and `lcd_phase`
+    pub fn io_registers(&self) -> Vec<(u16, u8)> {
+        let mut registers = vec![
+            (0xFF00, 0xCF), // P1
+            (0xFF01, 0x00), // SB
+            (0xFF05, 0x00), // TIMA
+            (0xFF06, 0x00), // TMA
+            (0xFF07, 0xF8), // TAC
+            (0xFF0F, 0xE1), // IF
+            (0xFF10, 0x80), // NR10
+            (0xFF11, 0xBF), // NR11
+            (0xFF12, 0xF3), // NR12
+            (0xFF13, 0xFF), // NR13
+            (0xFF14, 0xBF), // NR14
+            (0xFF16, 0x3F), // NR21
+            (0xFF17, 0x00), // NR22
+            (0xFF18, 0xFF), // NR23
+            (0xFF19, 0xBF), // NR24
+            (0xFF1A, 0x7F), // NR30
+            (0xFF1B, 0xFF), // NR31
+            (0xFF1C, 0x9F), // NR32
+            (0xFF1D, 0xFF), // NR33
+            (0xFF1E, 0xBF), // NR34
+            (0xFF20, 0xFF), // NR41
+            (0xFF21, 0x00), // NR42
+            (0xFF22, 0x00), // NR43
+            (0xFF23, 0xBF), // NR44
+            (0xFF24, 0x77), // NR50
+            (0xFF25, 0xF3), // NR51
+            (0xFF40, 0x91), // LCDC
+            (0xFF41, 0x85), // STAT
+            (0xFF42, 0x00), // SCY
+            (0xFF43, 0x00), // SCX
+            (0xFF45, 0x00), // LYC
+            (0xFF47, 0xFC), // BGP
+            (0xFF48, 0xFF), // OBP0
+            (0xFF49, 0xFF), // OBP1
+            (0xFF4A, 0x00), // WY
+            (0xFF4B, 0x00), // WX
+            (0xFFFF, 0x00), // IE
+        ];
+
+        match *self {
+            Model::DMG0 | Model::DMG | Model::MGB => {
+                registers.extend_from_slice(&[
+                    (0xFF02, 0x7E), // SC
+                    (0xFF26, 0xF1), // NR52
+                ]);
+            },
+
+            // the SGB boot ROM leaves the sound off for the SNES
+            Model::SGB | Model::SGB2 => {
+                registers.extend_from_slice(&[
+                    (0xFF02, 0x7E), // SC
+                    (0xFF26, 0xF0), // NR52
+                ]);
+            },
+
+            // the serial control has the clock speed bit
+            Model::CGB | Model::AGB => {
+                registers.extend_from_slice(&[
+                    (0xFF02, 0x7F), // SC
+                    (0xFF26, 0xF1), // NR52
+                    (0xFF4D, 0x7E), // KEY1
+                    (0xFF4F, 0xFE), // VBK
+                    (0xFF70, 0xF8), // SVBK
+                ]);
+            },
+        }
+
+        registers
+    }
+
+    /// LCD line and clock when the game starts
+    ///
+    /// The boot ROM turns on the LCD, so the game starts somewhere
+    /// in the middle of a frame
+    pub fn lcd_phase(&self) -> (u8, u32) {
+        match *self {
+            Model::DMG0 => (0x00, 0x00),
+            Model::DMG | Model::MGB | Model::SGB | Model::SGB2 => (0x00, 0x34),
+            Model::CGB | Model::AGB => (0x90, 0x00),
+        }
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Model, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "dmg0" => Ok(Model::DMG0),
+            "dmg" => Ok(Model::DMG),
+            "mgb" => Ok(Model::MGB),
+            "sgb" => Ok(Model::SGB),
+            "sgb2" => Ok(Model::SGB2),
+            "cgb" => Ok(Model::CGB),
+            "agb" => Ok(Model::AGB),
+            _ => Err(format!("Unknown model {}, expected one of dmg0, dmg, mgb, sgb, sgb2, cgb, agb", s)),
+        }
+    }
+}
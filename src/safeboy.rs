@@ -1,25 +1,299 @@
 extern crate safeboy;
 extern crate clap;
 
-use clap::Parser;
+use std::io::{self, Write};
+use std::process;
+use clap::{Parser, Subcommand};
 use safeboy::frontend::gameboy::Gameboy;
+use safeboy::frontend::console::Console;
+use safeboy::frontend::gdb::GdbServer;
+use safeboy::cpu::z80::Z80;
+use safeboy::memory::mbc::{FileImage, RtcClock};
+use safeboy::memory::cartridge::Cartridge;
+use safeboy::model::Model;
+use safeboy::cpu::disasm;
+use safeboy::cpu::debugger::parse_address;
+use safeboy::cpu::trace::{Tracer, TraceOptions, Trigger};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short, long)]
-    rom: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(short, long, required = true)]
+    rom: Option<String>,
+
+    /// Hardware model: dmg0, dmg, mgb, sgb, sgb2, cgb or agb
+    ///
+    /// By default, color cartridges run on cgb and the rest on dmg
+    #[arg(long)]
+    model: Option<Model>,
+
+    /// Boot ROM (256 bytes) to run before the game, like data/dmg.rom
+    #[arg(long)]
+    boot_rom: Option<String>,
+
+    /// Image (PNG or PGM) seen by the Pocket Camera sensor
+    #[arg(long)]
+    camera_image: Option<String>,
+
+    /// What drives the cartridge clock (MBC3 and HuC3): wall, the
+    /// host clock, or cycles, emulated time for repeatable runs
+    #[arg(long)]
+    rtc_clock: Option<RtcClock>,
+
+    /// Writes a trace of every instruction to this file, in Gameboy Doctor format
+    #[arg(long)]
+    trace: Option<String>,
+
+    /// Starts tracing after a number of instructions, or at pc:ADDR (or pc:BANK:ADDR)
+    #[arg(long, requires = "trace")]
+    trace_start: Option<Trigger>,
+
+    /// Stops tracing after a number of instructions, or at pc:ADDR (or pc:BANK:ADDR)
+    #[arg(long, requires = "trace")]
+    trace_stop: Option<Trigger>,
+
+    /// Only traces code running from these ROM banks (comma separated)
+    #[arg(long, requires = "trace", value_delimiter = ',')]
+    trace_banks: Vec<usize>,
+
+    /// Serves the GDB remote protocol on this port of localhost, the
+    /// game waits for a client to connect
+    #[arg(long)]
+    gdb: Option<u16>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Prints the cartridge header of a ROM
+    Info {
+        #[arg(short, long)]
+        rom: String,
+    },
+
+    /// Prints the disassembly of a ROM bank
+    Disasm {
+        #[arg(short, long)]
+        rom: String,
+
+        /// ROM bank to list, mapped at 0x4000-0x7FFF (0x0000-0x3FFF is always bank 0)
+        #[arg(short, long, default_value_t = 0)]
+        bank: usize,
+
+        /// First address, by default the start of the bank
+        #[arg(long, value_parser = parse_address)]
+        from: Option<u16>,
+
+        /// Last address, by default the end of the bank
+        #[arg(long, value_parser = parse_address)]
+        to: Option<u16>,
+    },
+
+    /// Runs a ROM in the terminal debugger, without a window
+    Debug {
+        #[arg(short, long)]
+        rom: String,
+
+        /// Hardware model, like for running the game
+        #[arg(long)]
+        model: Option<Model>,
+
+        /// Boot ROM to run before the game, so it can be debugged too
+        #[arg(long)]
+        boot_rom: Option<String>,
+    },
 }
 
 fn main() {
     let args = Args::parse();
 
-    let rom_file = args.rom;
+    match args.command {
+        Some(Command::Info { rom }) => {
+            info(rom.as_str());
+            return
+        },
+
+        Some(Command::Disasm { rom, bank, from, to }) => {
+            disassemble(rom.as_str(), bank, from, to);
+            return
+        },
+
+        Some(Command::Debug { rom, model, boot_rom }) => {
+            debug(rom.as_str(), model, boot_rom);
+            return
+        },
+
+        None => {},
+    }
+
+    let rom_file = args.rom.unwrap();
 
     println!("Welcome to Safeboy! We are preparing your rom to emulate...");
     println!("Loading rom file: {}", rom_file);
 
-    let mut gameboy = Gameboy::new(rom_file.as_str());
+    let model = args.model.unwrap_or_else(|| default_model(rom_file.as_str()));
+
+    let mut gameboy = match Gameboy::new(rom_file.as_str(), model) {
+        Ok(gameboy) => gameboy,
+        Err(e) => {
+            eprintln!("Could not start the emulator. {}", e);
+            process::exit(1);
+        }
+    };
+
+    if let Some(boot_rom_file) = args.boot_rom {
+        if let Err(e) = gameboy.load_boot_rom(boot_rom_file.as_str()) {
+            eprintln!("Could not start the emulator. {}", e);
+            process::exit(1);
+        }
+    }
+
+    if let Some(trace_file) = args.trace {
+        let options = TraceOptions {
+            start: args.trace_start,
+            stop: args.trace_stop,
+            banks: args.trace_banks,
+        };
+
+        match Tracer::create(trace_file.as_str(), options) {
+            Ok(tracer) => gameboy.trace(tracer),
+            Err(e) => {
+                eprintln!("Could not start the emulator. {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(port) = args.gdb {
+        match GdbServer::bind(port) {
+            Ok(server) => {
+                println!("Waiting for GDB on localhost:{}", server.port());
+                gameboy.serve_gdb(server);
+            },
+            Err(e) => {
+                eprintln!("Could not start the emulator. {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(image_file) = args.camera_image {
+        match FileImage::new(image_file.as_str()) {
+            Ok(image) => gameboy.set_image_source(Box::new(image)),
+            Err(e) => {
+                eprintln!("Could not start the emulator. {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(rtc_clock) = args.rtc_clock {
+        gameboy.set_rtc_clock(rtc_clock);
+    }
 
     gameboy.run();
-}
\ No newline at end of file
+}
+
+/// Model a ROM runs on when none is given
+///
+/// Color cartridges run on cgb and the rest on dmg
+fn default_model(rom_file: &str) -> Model {
+    Cartridge::load(rom_file)
+        .map(|cartridge| Model::for_cartridge(&cartridge.header))
+        .unwrap_or(Model::DMG)
+}
+
+/// Prints the cartridge header, and anything that looks wrong with it
+fn info(rom_file: &str) {
+    let cartridge = match Cartridge::load(rom_file) {
+        Ok(cartridge) => cartridge,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    println!("{}", cartridge.header);
+
+    for warning in cartridge.warnings() {
+        println!("Warning: {}", warning);
+    }
+}
+
+/// Prints the listing of a ROM bank
+///
+/// Bank 0 is listed at 0x0000-0x3FFF, the rest at 0x4000-0x7FFF
+fn disassemble(rom_file: &str, bank: usize, from: Option<u16>, to: Option<u16>) {
+    let cartridge = match Cartridge::load(rom_file) {
+        Ok(cartridge) => cartridge,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let banks = cartridge.rom.len() / 0x4000;
+
+    if bank >= banks {
+        eprintln!("Bank {} does not exist, the ROM has {} banks", bank, banks);
+        process::exit(1);
+    }
+
+    let from = from.unwrap_or(if bank == 0 { 0x0000 } else { 0x4000 });
+    let to = to.unwrap_or(if from < 0x4000 { 0x3FFF } else { 0x7FFF });
+
+    if from > to || to > 0x7FFF {
+        eprintln!("Invalid range {:04X}-{:04X}, the ROM is at 0x0000-0x7FFF", from, to);
+        process::exit(1);
+    }
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+
+    // bank 0 can't be mapped at 0x4000, the MBCs turn it into bank 1
+    let result = disasm::write_listing(&mut out, &cartridge.rom, bank.max(1), from, to)
+        .and_then(|_| out.flush());
+
+    // a closed pipe (like piping to head) is not an error
+    if let Err(e) = result {
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Runs the terminal debugger on stdin and stdout
+fn debug(rom_file: &str, model: Option<Model>, boot_rom: Option<String>) {
+    let model = model.unwrap_or_else(|| default_model(rom_file));
+
+    let mut cpu = match Z80::new(rom_file, model) {
+        Ok(cpu) => cpu,
+        Err(e) => {
+            eprintln!("Could not start the debugger. {}", e);
+            process::exit(1);
+        }
+    };
+
+    if let Some(boot_rom_file) = boot_rom {
+        if let Err(e) = cpu.load_boot_rom(boot_rom_file.as_str()) {
+            eprintln!("Could not start the debugger. {}", e);
+            process::exit(1);
+        }
+    }
+
+    println!("Debugging {}, type help for the list of commands", rom_file);
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+
+    if let Err(e) = Console::new(cpu).run(stdin.lock(), &mut stdout.lock()) {
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}